@@ -0,0 +1,52 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Embeds the git commit, build time, target and enabled features for
+/// `/__m2proxy/version` and `--version --verbose`
+fn main() {
+    // Builds without a checkout, e.g. in Docker, can pass the commit in
+    let commit = std::env::var("M2PROXY_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    // Honors reproducible builds
+    let epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| {
+            let feature = name.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_ascii_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=M2PROXY_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=M2PROXY_BUILD_EPOCH={}", epoch);
+    println!("cargo:rustc-env=M2PROXY_FEATURES={}", features.join(","));
+    let target = std::env::var("TARGET").unwrap_or_default();
+    println!("cargo:rustc-env=M2PROXY_TARGET={}", target);
+    println!("cargo:rerun-if-env-changed=M2PROXY_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD")
+        && let Some(reference) = head.trim().strip_prefix("ref: ")
+    {
+        println!("cargo:rerun-if-changed=.git/{}", reference);
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::{Result, anyhow};
+use hyper::{Method, StatusCode};
+use serde_json::{Value, json};
+use tokio::sync::mpsc;
+use tracing::warn;
+use url::Url;
+
+use crate::date::Utc;
+use crate::har::redact_url;
+
+/// When the audit log is rotated and how many old files are kept
+pub struct Rotation {
+    /// Size in bytes that starts a new file, 0 to never rotate
+    pub max_size: u64,
+    /// Rotated files kept as `PATH.1` (newest) to `PATH.N`
+    pub keep: usize,
+}
+
+/// Append-only JSON lines record of the targets each client accessed
+/// (`--audit-log`)
+pub struct AuditLog {
+    tx: mpsc::UnboundedSender<Value>,
+}
+
+impl AuditLog {
+    pub fn open(path: PathBuf, rotation: Rotation) -> Result<Self> {
+        let file = append(&path)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        // A thread of its own, the writer would hold a blocking pool thread
+        // for the life of the process
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer(path, file, rotation, rx))
+            .map_err(|e| anyhow!("Failed to start the audit log writer: {}", e))?;
+        Ok(AuditLog { tx })
+    }
+
+    /// Start the record of a request, written when the entry is dropped
+    pub fn entry(&self, request_id: &str, client: IpAddr, method: &Method, url: &Url) -> Entry {
+        Entry {
+            tx: self.tx.clone(),
+            time: SystemTime::now(),
+            record: json!({
+                "request_id": request_id,
+                "client": client.to_string(),
+                "method": method.as_str(),
+                "url": redact_url(url).as_str(),
+            }),
+            status: None,
+            received: Arc::default(),
+            sent: Arc::default(),
+        }
+    }
+}
+
+/// A request being audited
+pub struct Entry {
+    tx: mpsc::UnboundedSender<Value>,
+    time: SystemTime,
+    record: Value,
+    /// Missing if the client went away first
+    status: Option<StatusCode>,
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
+}
+
+impl Entry {
+    pub fn status(&mut self, status: StatusCode) {
+        self.status = Some(status);
+    }
+
+    /// Counter of request body bytes from the client
+    pub fn received(&self) -> Arc<AtomicU64> {
+        self.received.clone()
+    }
+
+    /// Counter of response body bytes to the client
+    pub fn sent(&self) -> Arc<AtomicU64> {
+        self.sent.clone()
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let mut record = self.record.take();
+        record["time"] = Utc::from(self.time).iso8601().into();
+        record["status"] = self.status.map(|status| status.as_u16()).into();
+        record["received_bytes"] = self.received.load(Ordering::Relaxed).into();
+        record["sent_bytes"] = self.sent.load(Ordering::Relaxed).into();
+        let _ = self.tx.send(record);
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))
+}
+
+fn writer(
+    path: PathBuf,
+    mut file: File,
+    rotation: Rotation,
+    mut rx: mpsc::UnboundedReceiver<Value>,
+) {
+    let mut size = file.metadata().map_or(0, |metadata| metadata.len());
+    while let Some(record) = rx.blocking_recv() {
+        let mut lines = Vec::new();
+        for record in std::iter::once(record).chain(std::iter::from_fn(|| rx.try_recv().ok())) {
+            let _ = serde_json::to_writer(&mut lines, &record);
+            lines.push(b'\n');
+        }
+        if let Err(e) = file.write_all(&lines) {
+            warn!("Failed to write {}: {}", path.display(), e);
+            continue;
+        }
+        size += lines.len() as u64;
+        if rotation.max_size > 0 && size >= rotation.max_size {
+            match rotate(&path, rotation.keep).and_then(|()| append(&path)) {
+                Ok(new) => {
+                    file = new;
+                    size = 0;
+                }
+                Err(e) => warn!("Failed to rotate {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+/// Shift `PATH.N` to `PATH.N+1` and `PATH` to `PATH.1`, dropping files
+/// beyond `keep`
+fn rotate(path: &Path, keep: usize) -> Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(());
+    }
+    let _ = std::fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        let from = numbered(n);
+        if from.exists() {
+            std::fs::rename(&from, numbered(n + 1))?;
+        }
+    }
+    std::fs::rename(path, numbered(1))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("m2proxy-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        for content in ["first", "second", "third"] {
+            std::fs::write(&path, content).unwrap();
+            rotate(&path, 2).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        let files = (read("audit.log"), read("audit.log.1"), read("audit.log.2"));
+        let dropped = read("audit.log.3");
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            (None, Some("third".to_string()), Some("second".to_string()))
+        );
+        assert_eq!(dropped, None);
+    }
+}
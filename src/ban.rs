@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+/// Longest ban, for durations too large to add to the clock
+const MAX_BAN: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+/// End of a ban of `duration` starting at `now`
+fn until(now: Instant, duration: Duration) -> Instant {
+    now.checked_add(duration)
+        .or_else(|| now.checked_add(MAX_BAN))
+        .unwrap_or(now)
+}
+
+/// Clients refused at accept time, banned by hand or after too many
+/// requests the proxy denied (`--ban-after`)
+pub struct Bans {
+    /// Denials within `window` that ban a client, 0 for manual bans only
+    threshold: u32,
+    window: Duration,
+    /// How long automatic bans last
+    duration: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Start of the current window and the denials in it
+    strikes: HashMap<IpAddr, (Instant, u32)>,
+    /// When each ban ends
+    banned: HashMap<IpAddr, Instant>,
+}
+
+impl Bans {
+    pub fn new(threshold: u32, window: Duration, duration: Duration) -> Self {
+        Bans {
+            threshold,
+            window,
+            duration,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match state.banned.get(&ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                state.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a denied request of `ip`, true if that got it banned
+    pub fn strike(&self, ip: IpAddr) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget clients that stopped failing so the map stays small
+        let window = self.window;
+        state
+            .strikes
+            .retain(|_, (start, _)| now.duration_since(*start) < window);
+        let (_, count) = state.strikes.entry(ip).or_insert((now, 0));
+        *count += 1;
+        if *count < self.threshold {
+            return false;
+        }
+        state.strikes.remove(&ip);
+        state.banned.insert(ip, until(now, self.duration));
+        true
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .banned
+            .insert(ip.to_canonical(), until(Instant::now(), duration));
+    }
+
+    /// Lift the ban on `ip`, false if it was not banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.banned.remove(&ip.to_canonical()).is_some()
+    }
+
+    /// Banned clients and the seconds left on their bans
+    pub fn list(&self) -> Value {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.banned.retain(|_, until| *until > now);
+        let mut banned: Vec<_> = state.banned.iter().collect();
+        banned.sort();
+        let banned: Vec<Value> = banned
+            .into_iter()
+            .map(|(ip, until)| {
+                json!({
+                    "ip": ip.to_string(),
+                    "remaining_seconds": until.duration_since(now).as_secs(),
+                })
+            })
+            .collect();
+        json!({ "banned": banned })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banning() {
+        let bans = Bans::new(2, Duration::from_secs(60), Duration::from_secs(600));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(!bans.strike(ip));
+        assert!(!bans.is_banned(ip));
+        assert!(bans.strike("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned("192.0.2.2".parse().unwrap()));
+        assert_eq!(bans.list()["banned"][0]["ip"], "192.0.2.1");
+
+        assert!(bans.unban(ip));
+        assert!(!bans.unban(ip));
+        assert!(!bans.is_banned(ip));
+        bans.ban(ip, Duration::ZERO);
+        assert!(!bans.is_banned(ip));
+        bans.ban(ip, Duration::from_secs(u64::MAX));
+        assert!(bans.is_banned(ip));
+
+        let forever = Bans::new(1, Duration::from_secs(60), Duration::MAX);
+        assert!(forever.strike(ip));
+        assert!(forever.is_banned(ip));
+
+        let manual = Bans::new(0, Duration::from_secs(60), Duration::from_secs(600));
+        assert!(!manual.strike(ip));
+        assert!(!manual.strike(ip));
+        assert!(!manual.is_banned(ip));
+    }
+}
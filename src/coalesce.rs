@@ -0,0 +1,495 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use http_body_util::BodyExt;
+use hyper::body::{Body as HttpBody, Bytes, Frame};
+use hyper::header::{self, HeaderMap};
+use hyper::{Method, Request, Response, StatusCode, Version};
+use tokio::sync::{Notify, watch};
+use url::Url;
+
+use crate::body::{self, Body, BoxError};
+
+/// Start of a response kept so later requests can still join its flight,
+/// and the most a flight reads ahead of its slowest reader
+const REPLAY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Request headers whose values must match for requests to share a response
+const VARY: [header::HeaderName; 4] = [
+    header::ACCEPT,
+    header::ACCEPT_ENCODING,
+    header::ACCEPT_LANGUAGE,
+    header::HOST,
+];
+
+/// Request headers that make a response specific to one client
+const PRIVATE: [&str; 10] = [
+    "authorization",
+    "cookie",
+    "range",
+    "if-match",
+    "if-none-match",
+    "if-modified-since",
+    "if-unmodified-since",
+    "if-range",
+    crate::DRY_RUN_HEADER,
+    crate::TIMEOUT_HEADER,
+];
+
+/// Identical GET requests in flight at the same time, answered with one
+/// upstream response (`--coalesce`)
+#[derive(Default)]
+pub struct Coalescer {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+/// Part a request takes in a flight
+pub enum Role {
+    /// First request, its response is shared
+    Leader(Leader),
+    /// Waits for the response of the leader
+    Follower(Follower),
+}
+
+/// Response streamed to every request of a flight
+struct Flight {
+    /// Decided once the leader has a response, `None` if it is not shared
+    head: watch::Sender<Option<Option<Head>>>,
+    stream: Mutex<Stream>,
+    /// Signalled when readers advanced and the upstream may be read again
+    drained: Notify,
+}
+
+#[derive(Clone)]
+struct Head {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct Stream {
+    chunks: VecDeque<Bytes>,
+    /// Bytes in `chunks`
+    held: usize,
+    /// Index of the first chunk still held, later requests cannot join once
+    /// chunks were released
+    base: usize,
+    /// Next chunk of each reader, `None` once it finished or went away
+    cursors: Vec<Option<usize>>,
+    end: Option<Result<(), String>>,
+    trailers: Option<HeaderMap>,
+    wakers: Vec<Waker>,
+}
+
+impl Stream {
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn readers(&self) -> usize {
+        self.cursors.iter().flatten().count()
+    }
+
+    /// Drop the chunks every reader is past, once the response outgrew
+    /// what is replayed to requests joining late
+    fn release(&mut self) {
+        if self.base == 0 && self.held <= REPLAY_SIZE {
+            return;
+        }
+        let done = self.cursors.iter().flatten().min().copied();
+        let done = done.unwrap_or(self.base + self.chunks.len());
+        while self.base < done
+            && let Some(chunk) = self.chunks.pop_front()
+        {
+            self.held -= chunk.len();
+            self.base += 1;
+        }
+    }
+}
+
+impl Coalescer {
+    /// Role of a request in the flight for the same target, `None` for
+    /// requests that are not shared
+    pub fn join<B: HttpBody>(&self, req: &Request<B>, target: &Url) -> Option<Role> {
+        if req.method() != Method::GET
+            || !req.body().is_end_stream()
+            || PRIVATE.iter().any(|name| req.headers().contains_key(*name))
+        {
+            return None;
+        }
+        let mut key = target.to_string();
+        for name in &VARY {
+            key.push('\n');
+            for value in req.headers().get_all(name) {
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(flight) = flights.get(&key) {
+            return Some(Role::Follower(Follower {
+                flight: flight.clone(),
+            }));
+        }
+        let flight = Arc::new(Flight {
+            head: watch::channel(None).0,
+            stream: Mutex::default(),
+            drained: Notify::new(),
+        });
+        flights.insert(key.clone(), flight.clone());
+        Some(Role::Leader(Leader {
+            flights: self.flights.clone(),
+            key,
+            flight,
+        }))
+    }
+}
+
+/// Remove `flight` from the map unless a newer one took its place
+fn land(flights: &Mutex<HashMap<String, Arc<Flight>>>, key: &str, flight: &Arc<Flight>) {
+    let mut flights = flights.lock().unwrap_or_else(|e| e.into_inner());
+    if flights
+        .get(key)
+        .is_some_and(|other| Arc::ptr_eq(other, flight))
+    {
+        flights.remove(key);
+    }
+}
+
+pub struct Leader {
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    key: String,
+    flight: Arc<Flight>,
+}
+
+impl Leader {
+    /// Stream `response` to the requests that joined and return this
+    /// request's copy; responses specific to one client are not shared
+    pub fn share(self, response: Response<Body>) -> Response<Body> {
+        if !shareable(&response) {
+            return response;
+        }
+        let (parts, upstream) = response.into_parts();
+        let head = Head {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+        };
+        let own = SharedBody::attach(&self.flight).unwrap_or_else(body::empty);
+        self.flight.head.send_replace(Some(Some(head)));
+        tokio::spawn(pump(
+            self.flights.clone(),
+            self.key.clone(),
+            self.flight.clone(),
+            upstream,
+        ));
+        Response::from_parts(parts, own)
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // Followers forward their requests themselves unless it was shared
+        self.flight.head.send_if_modified(|head| {
+            let unset = head.is_none();
+            if unset {
+                *head = Some(None);
+            }
+            unset
+        });
+        if self
+            .flight
+            .head
+            .borrow()
+            .as_ref()
+            .is_some_and(Option::is_none)
+        {
+            land(&self.flights, &self.key, &self.flight);
+        }
+    }
+}
+
+pub struct Follower {
+    flight: Arc<Flight>,
+}
+
+impl Follower {
+    /// The response of the leader, `None` if it is not shared or already
+    /// partly released and this request must be forwarded on its own
+    pub async fn response(self) -> Option<Response<Body>> {
+        let mut head = self.flight.head.subscribe();
+        let head = head.wait_for(Option::is_some).await.ok()?.clone()??;
+        let body = SharedBody::attach(&self.flight)?;
+        let mut response = Response::new(body);
+        *response.status_mut() = head.status;
+        *response.version_mut() = head.version;
+        *response.headers_mut() = head.headers;
+        Some(response)
+    }
+}
+
+/// Only complete, successful responses without cookies that caches may
+/// store and that vary on nothing but the flight key are shared
+fn shareable(response: &Response<Body>) -> bool {
+    let headers = response.headers();
+    let mut cache_control = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase());
+    response.status() == StatusCode::OK
+        && !headers.contains_key(header::SET_COOKIE)
+        && headers
+            .get_all(header::VARY)
+            .iter()
+            .flat_map(|value| value.as_bytes().split(|b| *b == b','))
+            .map(|name| name.trim_ascii())
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                VARY.iter()
+                    .any(|key| name.eq_ignore_ascii_case(key.as_ref()))
+            })
+        && !cache_control.any(|directive| directive == "private" || directive == "no-store")
+}
+
+/// Read the upstream body into the flight until it ends or every reader
+/// went away, waiting for the slowest reader once too much is held
+async fn pump(
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+    key: String,
+    flight: Arc<Flight>,
+    mut upstream: Body,
+) {
+    let end = loop {
+        let frame = upstream.frame().await;
+        {
+            let mut stream = flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+            if stream.readers() == 0 {
+                break Err("abandoned".to_string());
+            }
+            match frame {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => {
+                        stream.held += data.len();
+                        stream.chunks.push_back(data);
+                        stream.release();
+                        stream.wake();
+                    }
+                    // Sent to every reader after the last chunk
+                    Err(frame) => stream.trailers = frame.into_trailers().ok(),
+                },
+                Some(Err(e)) => break Err(e.to_string()),
+                None => break Ok(()),
+            }
+        }
+        loop {
+            let drained = flight.drained.notified();
+            {
+                let stream = flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+                if stream.held <= REPLAY_SIZE || stream.readers() == 0 {
+                    break;
+                }
+            }
+            drained.await;
+        }
+    };
+    land(&flights, &key, &flight);
+    let mut stream = flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+    stream.end = Some(end);
+    stream.wake();
+}
+
+/// Body of one request of a flight
+struct SharedBody {
+    flight: Arc<Flight>,
+    reader: usize,
+}
+
+impl SharedBody {
+    /// Reader from the first chunk, `None` if that was already released
+    fn attach(flight: &Arc<Flight>) -> Option<Body> {
+        let mut stream = flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+        if stream.base > 0 {
+            return None;
+        }
+        stream.cursors.push(Some(0));
+        Some(
+            SharedBody {
+                flight: flight.clone(),
+                reader: stream.cursors.len() - 1,
+            }
+            .boxed(),
+        )
+    }
+}
+
+impl HttpBody for SharedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut stream = self.flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(cursor) = stream.cursors[self.reader] else {
+            return Poll::Ready(None);
+        };
+        if let Some(chunk) = stream.chunks.get(cursor - stream.base).cloned() {
+            stream.cursors[self.reader] = Some(cursor + 1);
+            stream.release();
+            self.flight.drained.notify_one();
+            return Poll::Ready(Some(Ok(Frame::data(chunk))));
+        }
+        match stream.end.clone() {
+            Some(end) => {
+                stream.cursors[self.reader] = None;
+                Poll::Ready(match end {
+                    Ok(()) => stream
+                        .trailers
+                        .clone()
+                        .map(|trailers| Ok(Frame::trailers(trailers))),
+                    Err(e) => Some(Err(e.into())),
+                })
+            }
+            None => {
+                stream.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for SharedBody {
+    fn drop(&mut self) {
+        let mut stream = self.flight.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.cursors[self.reader] = None;
+        stream.release();
+        self.flight.drained.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures_util::stream;
+    use http_body_util::StreamBody;
+
+    use super::*;
+
+    fn get(accept: &str) -> Request<Body> {
+        Request::get("/")
+            .header(header::ACCEPT, accept)
+            .body(body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn flights() {
+        let coalescer = Coalescer::default();
+        let target = Url::parse("https://example.com/a").unwrap();
+        let Some(Role::Leader(leader)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no leader");
+        };
+        let Some(Role::Follower(follower)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no follower");
+        };
+        assert!(matches!(
+            coalescer.join(&get("text/html"), &target),
+            Some(Role::Leader(_))
+        ));
+        let mut range = get("*/*");
+        range
+            .headers_mut()
+            .insert(header::RANGE, "bytes=0-1".parse().unwrap());
+        assert!(coalescer.join(&range, &target).is_none());
+        let mut deadline = get("*/*");
+        deadline
+            .headers_mut()
+            .insert(crate::TIMEOUT_HEADER, "1s".parse().unwrap());
+        assert!(coalescer.join(&deadline, &target).is_none());
+
+        let response = leader.share(Response::new(body::full("shared")));
+        let followed = follower.response().await.unwrap();
+        let read = |response: Response<Body>| async {
+            response.into_body().collect().await.unwrap().to_bytes()
+        };
+        assert_eq!(read(response).await, "shared");
+        assert_eq!(read(followed).await, "shared");
+
+        // Not shared, the follower forwards on its own
+        let Some(Role::Leader(leader)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no leader");
+        };
+        let Some(Role::Follower(follower)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no follower");
+        };
+        let mut private = Response::new(body::full("private"));
+        private
+            .headers_mut()
+            .insert(header::SET_COOKIE, "a=1".parse().unwrap());
+        assert_eq!(read(leader.share(private)).await, "private");
+        assert!(follower.response().await.is_none());
+
+        let vary = |value: &str| {
+            let mut response = Response::new(body::empty());
+            response
+                .headers_mut()
+                .insert(header::VARY, value.parse().unwrap());
+            shareable(&response)
+        };
+        assert!(vary("Accept-Encoding, accept"));
+        assert!(!vary("*"));
+        assert!(!vary("Accept-Encoding, User-Agent"));
+        assert!(!vary("Cookie"));
+    }
+
+    #[tokio::test]
+    async fn slow_readers_hold_back_the_upstream() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counted = pulled.clone();
+        let chunk = Bytes::from(vec![0; 1 << 20]);
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "1".parse().unwrap());
+        let frames = (0..32)
+            .map(move |_| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                Ok::<_, BoxError>(Frame::data(chunk.clone()))
+            })
+            .chain([Ok(Frame::trailers(trailers))]);
+        let upstream = body::boxed(StreamBody::new(stream::iter(frames)));
+
+        let coalescer = Coalescer::default();
+        let target = Url::parse("https://example.com/large").unwrap();
+        let Some(Role::Leader(leader)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no leader");
+        };
+        let Some(Role::Follower(follower)) = coalescer.join(&get("*/*"), &target) else {
+            panic!("no follower");
+        };
+        let response = leader.share(Response::new(upstream));
+        let followed = follower.response().await.unwrap();
+        let read =
+            |response: Response<Body>| async { response.into_body().collect().await.unwrap() };
+        // The leader reads on while the follower does not
+        let own = tokio::spawn(read(response));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pulled.load(Ordering::Relaxed) <= (REPLAY_SIZE >> 20) + 1);
+
+        let followed = read(followed).await;
+        let own = own.await.unwrap();
+        assert_eq!(pulled.load(Ordering::Relaxed), 32);
+        for collected in [own, followed] {
+            let trailers = collected.trailers().cloned().unwrap();
+            assert_eq!(trailers["x-checksum"], "1");
+            assert_eq!(collected.to_bytes().len(), 32 << 20);
+        }
+    }
+}
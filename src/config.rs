@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::upstream::Balance;
+
+/// Settings read from the `--config` TOML file
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// `HOST:PORT:ADDR` pins added to `--resolve`, which wins for the same
+    /// `HOST:PORT`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub resolve: Vec<String>,
+    /// `[[route]]` tables, matched in order
+    #[serde(default, rename = "route", skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteConfig>,
+    /// `[[rewrite]]` tables, all applied in order
+    #[serde(default, rename = "rewrite", skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<RewriteConfig>,
+    /// `[[redirect]]` tables, the first matching one applies
+    #[serde(default, rename = "redirect", skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<RedirectConfig>,
+    /// `[[credential]]` tables, the first matching one applies
+    #[serde(default, rename = "credential", skip_serializing_if = "Vec::is_empty")]
+    pub credentials: Vec<CredentialConfig>,
+    /// `[[user_agent]]` tables, the first matching one applies
+    #[serde(default, rename = "user_agent", skip_serializing_if = "Vec::is_empty")]
+    pub user_agents: Vec<UserAgentConfig>,
+    /// `[[pin]]` tables, the first matching one applies
+    #[serde(default, rename = "pin", skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<PinConfig>,
+    /// `[[warm]]` tables
+    #[serde(default, rename = "warm", skip_serializing_if = "Vec::is_empty")]
+    pub warms: Vec<WarmConfig>,
+}
+
+/// Short path mapped to an upstream URL template
+///
+/// ```toml
+/// [[route]]
+/// path = "/gh/{owner}/{repo}/{path..}"
+/// upstream = "https://github.com/{owner}/{repo}/{path..}"
+/// ```
+///
+/// `upstreams` lists equivalent mirrors instead, chosen by `balance` among
+/// those passing the optional `health_check`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub path: String,
+    pub upstream: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// Path requested on each upstream origin to check its health
+    pub health_check: Option<String>,
+    /// Seconds between health checks
+    pub health_interval: Option<u64>,
+    /// Milliseconds to wait for response headers before racing a second
+    /// upstream
+    pub hedge_after: Option<u64>,
+    /// `ip` or `cookie:NAME`, keeps each client on one upstream
+    pub sticky: Option<String>,
+    /// Sign requests to the upstreams, e.g. private S3 buckets
+    pub sigv4: Option<SigV4Config>,
+    /// Fetch bearer tokens for the upstreams
+    pub oauth2: Option<OAuth2Config>,
+    /// URL template also sent a share of the GET and HEAD requests, its
+    /// responses are discarded
+    pub shadow: Option<String>,
+    /// Percentage of requests sent to `shadow`, 100 by default
+    pub shadow_percent: Option<u64>,
+    /// Host header sent to the upstreams instead of their own, e.g. for
+    /// virtual-hosted buckets behind a fixed address
+    pub host_header: Option<String>,
+    /// `IP` or `IP:PORT` dialed for the upstreams instead of resolving them
+    pub connect_to: Option<String>,
+    /// Name sent as TLS SNI and verified in the certificate instead of the
+    /// upstream host
+    pub sni: Option<String>,
+}
+
+/// AWS Signature Version 4 credentials of a route
+///
+/// ```toml
+/// sigv4 = { region = "eu-west-1", access_key = "${env:AWS_ACCESS_KEY_ID}", secret_key = "${file:/run/secrets/aws}" }
+/// ```
+///
+/// Without keys, the role credentials of the EC2 instance are used.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigV4Config {
+    pub region: String,
+    /// Defaults to `s3`
+    pub service: Option<String>,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+/// Regex substitution on the path of targets
+///
+/// ```toml
+/// [[rewrite]]
+/// host = "example.com" # optional
+/// pattern = "^/releases/v([0-9.]+)/(.*)$"
+/// replace = "/download/$1/$2"
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub host: Option<String>,
+    pub pattern: String,
+    pub replace: String,
+}
+
+/// Redirect for targets that moved, answered without contacting them
+///
+/// ```toml
+/// [[redirect]]
+/// host = "old.example.com" # optional
+/// pattern = "^/repo/(.*)$"
+/// to = "https://new.example.com/repo/$1"
+/// status = 301 # 308 by default
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub host: Option<String>,
+    pub pattern: String,
+    pub to: String,
+    pub status: Option<u16>,
+}
+
+/// Headers added to outbound requests for a host, never sent to clients
+///
+/// ```toml
+/// [[credential]]
+/// host = "ghcr.io" # or "*.example.com" for subdomains
+/// headers = { Authorization = "Bearer ${env:GHCR_TOKEN}" }
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialConfig {
+    pub host: String,
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Public keys an upstream host must present, as base64 SHA-256 hashes of
+/// the SubjectPublicKeyInfo like curl's `--pinnedpubkey`
+///
+/// ```toml
+/// [[pin]]
+/// host = "mirror.example.com" # or "*.example.com" for subdomains
+/// sha256 = ["sha256//AAAA...=", "sha256//BBBB...="]
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinConfig {
+    pub host: String,
+    pub sha256: Vec<String>,
+}
+
+/// Upstream origin kept connected so first requests skip the handshake
+///
+/// ```toml
+/// [[warm]]
+/// url = "https://pypi.org/"
+/// connections = 2 # 1 by default
+/// interval = 30 # seconds between pings, 30 by default
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmConfig {
+    pub url: String,
+    pub connections: Option<usize>,
+    pub interval: Option<u64>,
+}
+
+/// What happens to requests whose User-Agent matches a pattern
+///
+/// ```toml
+/// [[user_agent]]
+/// pattern = "^(pip|cargo|docker)/"
+/// action = "allow"
+///
+/// [[user_agent]]
+/// pattern = "(?i)bot|crawler|spider"
+/// action = "throttle" # or "block"
+/// rate = 30 # requests per minute and client
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserAgentConfig {
+    pub pattern: String,
+    pub action: UserAgentAction,
+    pub rate: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserAgentAction {
+    Allow,
+    Block,
+    Throttle,
+}
+
+/// OAuth 2.0 client credentials of a route
+///
+/// ```toml
+/// oauth2 = { token_url = "https://auth.example.com/token", client_id = "proxy", client_secret = "${file:/run/secrets/oauth}" }
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// The config as it takes effect, for `--print-config`: references in
+    /// upstreams must resolve but are printed as written, and secrets are
+    /// masked unless they only refer to variables or files
+    pub fn effective(mut self) -> Result<Self> {
+        for route in &mut self.routes {
+            for template in route
+                .upstream
+                .iter()
+                .chain(&route.upstreams)
+                .chain(&route.shadow)
+            {
+                interpolate(template)?;
+            }
+            if let Some(sigv4) = &mut route.sigv4 {
+                for secret in [
+                    &mut sigv4.access_key,
+                    &mut sigv4.secret_key,
+                    &mut sigv4.session_token,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    *secret = mask(secret)?;
+                }
+            }
+            if let Some(oauth2) = &mut route.oauth2 {
+                oauth2.client_id = mask(&oauth2.client_id)?;
+                oauth2.client_secret = mask(&oauth2.client_secret)?;
+            }
+        }
+        for credential in &mut self.credentials {
+            for value in credential.headers.values_mut() {
+                *value = mask(value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// TOML text of the config
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// `value` if it takes its secrets from references, which must resolve,
+/// otherwise a placeholder
+fn mask(value: &str) -> Result<String> {
+    interpolate(value)?;
+    Ok(if value.contains("${") {
+        value.to_string()
+    } else {
+        crate::har::REDACTED.to_string()
+    })
+}
+
+/// Replace `${env:VAR}` (or `${VAR}`) with the value of an environment
+/// variable and `${file:PATH}` with the contents of a file, without its
+/// trailing newline
+pub fn interpolate(value: &str) -> Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated ${{ in {:?}", value))?;
+        let reference = &rest[start + 2..start + end];
+        match reference.strip_prefix("file:") {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read secret {}: {}", path, e))?;
+                out.push_str(text.trim_end_matches(['\r', '\n']));
+            }
+            None => {
+                let name = reference.strip_prefix("env:").unwrap_or(reference);
+                let value = std::env::var(name)
+                    .map_err(|_| anyhow!("Environment variable {} is not set", name))?;
+                out.push_str(&value);
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolation() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(interpolate("a${PATH}b").unwrap(), format!("a{}b", path));
+        assert_eq!(interpolate("plain").unwrap(), "plain");
+        assert!(interpolate("${M2PROXY_UNSET_VARIABLE}").is_err());
+        assert!(interpolate("${PATH").is_err());
+        assert_eq!(interpolate("${env:PATH}").unwrap(), path);
+
+        let file = std::env::temp_dir().join(format!("m2proxy-secret-{}", std::process::id()));
+        std::fs::write(&file, "s3cret\n").unwrap();
+        let value = interpolate(&format!("Bearer ${{file:{}}}", file.display()));
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(value.unwrap(), "Bearer s3cret");
+        assert!(interpolate("${file:/nonexistent/m2proxy-secret}").is_err());
+    }
+
+    #[test]
+    fn effective_config() {
+        let file = std::env::temp_dir().join(format!("m2proxy-token-{}", std::process::id()));
+        std::fs::write(&file, "s3cret\n").unwrap();
+        let config: Config = toml::from_str(&format!(
+            r#"
+            resolve = ["example.com:443:192.0.2.1"]
+
+            [[route]]
+            path = "/{{path..}}"
+            upstream = "https://example.com/{{path..}}?token=${{file:{file}}}"
+            oauth2 = {{ token_url = "https://auth.example.com/token", client_id = "${{file:{file}}}", client_secret = "hunter2" }}
+
+            [[credential]]
+            host = "ghcr.io"
+            headers = {{ Authorization = "Bearer literal" }}
+            "#,
+            file = file.display()
+        ))
+        .unwrap();
+        let text = config.effective().map(|config| config.to_toml());
+        std::fs::remove_file(&file).unwrap();
+        let text = text.unwrap().unwrap();
+        assert!(!text.contains("s3cret"), "{}", text);
+        assert!(
+            text.starts_with("resolve = [\"example.com:443:192.0.2.1\"]\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("upstream = \"https://example.com/{path..}?token=${file:"),
+            "{}",
+            text
+        );
+        assert!(text.contains("client_id = \"${file:"), "{}", text);
+        assert!(text.contains("client_secret = \"[redacted]\""), "{}", text);
+        assert!(text.contains("Authorization = \"[redacted]\""), "{}", text);
+        assert!(!text.contains("upstreams"), "{}", text);
+
+        let unset: Config = toml::from_str(
+            r#"
+            [[route]]
+            path = "/{path..}"
+            upstream = "https://${M2PROXY_UNSET_VARIABLE}@example.com/{path..}"
+            "#,
+        )
+        .unwrap();
+        assert!(unset.effective().is_err());
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::body::Incoming;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::{Request, Response, Uri};
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use hyper_util::client::legacy;
+use hyper_util::client::legacy::connect::{
+    CaptureConnection, Connected, Connection, HttpConnector, capture_connection,
+};
+use hyper_util::rt::TokioIo;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::Service;
+
+use crate::body::Body;
+use crate::dns::{Overrides, Resolver, uri_port};
+use crate::pin::Pinned;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Returned when an upstream host has no free connection slot
+#[derive(Debug)]
+pub struct ConnLimitExceeded(pub String);
+
+impl std::fmt::Display for ConnLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection limit reached for {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnLimitExceeded {}
+
+/// Per-host cap on simultaneous upstream connections, hosts are only kept
+/// while they have connections or requests waiting for one
+#[derive(Clone)]
+pub struct HostLimiter {
+    max: usize,
+    wait: Duration,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimiter {
+    /// `max == 0` disables the limit; `wait` is how long to queue for a slot
+    pub fn new(max: usize, wait: Duration) -> Self {
+        HostLimiter {
+            max,
+            wait,
+            hosts: Arc::default(),
+        }
+    }
+
+    async fn acquire(&self, key: String) -> Result<Option<Slot>, BoxError> {
+        if self.max == 0 {
+            return Ok(None);
+        }
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max)))
+            .clone();
+
+        let permit = if self.wait.is_zero() {
+            semaphore.try_acquire_owned().ok()
+        } else {
+            tokio::time::timeout(self.wait, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok)
+        };
+        let slot = Slot {
+            permit,
+            key,
+            hosts: self.hosts.clone(),
+        };
+        if slot.permit.is_none() {
+            return Err(Box::new(ConnLimitExceeded(slot.key.clone())));
+        }
+        Ok(Some(slot))
+    }
+}
+
+/// Connection slot of a host, which is forgotten once no slot is taken
+/// and no request waits for one
+struct Slot {
+    permit: Option<OwnedSemaphorePermit>,
+    key: String,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        // Permits and waiters each hold the semaphore too
+        if hosts
+            .get(&self.key)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            hosts.remove(&self.key);
+        }
+    }
+}
+
+/// Upstream TCP connector
+///
+/// Applies [`Overrides`] before falling back to DNS, and holds a
+/// [`HostLimiter`] slot for as long as each connection stays open. Only the
+/// dialed address changes; the TLS layer above still sees the original URI,
+/// so SNI and certificate validation use the target host.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<Resolver>,
+    overrides: Overrides,
+    limiter: HostLimiter,
+}
+
+impl Connector {
+    pub fn new(http: HttpConnector<Resolver>, overrides: Overrides, limiter: HostLimiter) -> Self {
+        Connector {
+            http,
+            overrides,
+            limiter,
+        }
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let key = format!("{}:{}", uri.host().unwrap_or(""), uri_port(&uri));
+        let dst = match self.overrides.lookup(&uri) {
+            Some(addr) => {
+                // Dial the pinned address directly, skipping resolution
+                match Uri::builder()
+                    .scheme(uri.scheme_str().unwrap_or("http"))
+                    .authority(addr.to_string())
+                    .path_and_query("/")
+                    .build()
+                {
+                    Ok(dst) => dst,
+                    Err(e) => return Box::pin(async move { Err(e.into()) }),
+                }
+            }
+            None => uri,
+        };
+
+        let mut http = self.http.clone();
+        let limiter = self.limiter.clone();
+        Box::pin(async move {
+            let permit = limiter.acquire(key).await?;
+            let io = http.call(dst).await?;
+            Ok(Stream {
+                io,
+                established: Established {
+                    at: Instant::now(),
+                    retiring: Arc::default(),
+                },
+                _permit: permit,
+            })
+        })
+    }
+}
+
+/// Upstream TCP stream, releasing its [`HostLimiter`] slot on drop
+pub struct Stream {
+    io: TokioIo<TcpStream>,
+    established: Established,
+    _permit: Option<Slot>,
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        self.io.connected().extra(self.established.clone())
+    }
+}
+
+/// How the upstreams of a route are reached (`connect_to`, `sni`)
+#[derive(Debug, PartialEq, Eq)]
+pub struct Via {
+    /// Address dialed instead of resolving the upstream host, on the
+    /// upstream port unless one is given
+    pub connect_to: Option<(IpAddr, Option<u16>)>,
+    /// Name sent as TLS SNI and verified in the certificate instead of the
+    /// upstream host
+    pub sni: Option<String>,
+}
+
+/// Vias requests were sent with, the connector learns a request's from the
+/// `viaN` user of its URI, which also keeps its connections apart in the
+/// pool
+#[derive(Clone, Default)]
+pub struct Vias(Arc<Mutex<Vec<Arc<Via>>>>);
+
+impl Vias {
+    fn id(&self, via: &Arc<Via>) -> usize {
+        let mut vias = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match vias.iter().position(|other| other == via) {
+            Some(id) => id,
+            None => {
+                vias.push(via.clone());
+                vias.len() - 1
+            }
+        }
+    }
+
+    fn get(&self, id: usize) -> Option<Arc<Via>> {
+        let vias = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        vias.get(id).cloned()
+    }
+}
+
+/// `uri` for the connections of via `id`
+fn via_uri(uri: &Uri, id: usize) -> Option<Uri> {
+    let host = uri.authority()?.as_str().rsplit('@').next()?;
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(format!("via{}@{}", id, host).parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Via id of a connection URI, and the URI without it
+fn split_via(uri: Uri) -> (Option<usize>, Uri) {
+    let Some((user, host)) = uri
+        .authority()
+        .and_then(|authority| authority.as_str().split_once('@'))
+    else {
+        return (None, uri);
+    };
+    let id = user.strip_prefix("via").and_then(|id| id.parse().ok());
+    let mut parts = uri.clone().into_parts();
+    match host.parse() {
+        Ok(host) => {
+            parts.authority = Some(host);
+            match Uri::from_parts(parts) {
+                Ok(stripped) => (id, stripped),
+                Err(_) => (None, uri),
+            }
+        }
+        Err(_) => (None, uri),
+    }
+}
+
+/// Connector for the pool: plain and TLS connections to the upstream host,
+/// or where the [`Via`] of their route points
+#[derive(Clone)]
+pub struct Routed {
+    https: HttpsConnector<Connector>,
+    http: Connector,
+    tls: tokio_native_tls::TlsConnector,
+    https_only: bool,
+    vias: Vias,
+}
+
+impl Routed {
+    pub fn new(
+        http: Connector,
+        tls: native_tls::TlsConnector,
+        https_only: bool,
+        vias: Vias,
+    ) -> Self {
+        let tls = tokio_native_tls::TlsConnector::from(tls);
+        let mut https = HttpsConnector::from((http.clone(), tls.clone()));
+        https.https_only(https_only);
+        Routed {
+            https,
+            http,
+            tls,
+            https_only,
+            vias,
+        }
+    }
+}
+
+impl Service<Uri> for Routed {
+    type Response = MaybeHttpsStream<Stream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.https.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let (id, uri) = split_via(uri);
+        let Some(via) = id.and_then(|id| self.vias.get(id)) else {
+            return Box::pin(self.https.call(uri));
+        };
+        let is_https = uri.scheme_str() == Some("https");
+        if !is_https && self.https_only {
+            return Box::pin(async { Err("https required but URI was not https".into()) });
+        }
+        let sni = match &via.sni {
+            Some(sni) => sni.clone(),
+            None => uri
+                .host()
+                .unwrap_or("")
+                .trim_matches(['[', ']'])
+                .to_string(),
+        };
+        let dst = match via.connect_to {
+            Some((ip, port)) => {
+                let addr = SocketAddr::new(ip, port.unwrap_or(uri_port(&uri)));
+                match Uri::builder()
+                    .scheme(uri.scheme_str().unwrap_or("http"))
+                    .authority(addr.to_string())
+                    .path_and_query("/")
+                    .build()
+                {
+                    Ok(dst) => dst,
+                    Err(e) => return Box::pin(async move { Err(e.into()) }),
+                }
+            }
+            None => uri,
+        };
+        let connecting = self.http.call(dst);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if !is_https {
+                return Ok(MaybeHttpsStream::Http(tcp));
+            }
+            let tls = tls.connect(&sni, TokioIo::new(tcp)).await?;
+            Ok(MaybeHttpsStream::Https(TokioIo::new(tls)))
+        })
+    }
+}
+
+/// When a connection was opened, in its metadata
+#[derive(Clone)]
+struct Established {
+    at: Instant,
+    /// Set once a request scheduled the end of the connection
+    retiring: Arc<AtomicBool>,
+}
+
+/// Upstream client that stops reusing connections older than
+/// `--conn-max-lifetime`
+#[derive(Clone)]
+pub struct Client {
+    inner: legacy::Client<Pinned, Body>,
+    max_lifetime: Option<Duration>,
+    vias: Vias,
+    /// Via of the requests of this client
+    via: Option<usize>,
+}
+
+impl Client {
+    pub fn new(
+        inner: legacy::Client<Pinned, Body>,
+        max_lifetime: Option<Duration>,
+        vias: Vias,
+    ) -> Self {
+        Client {
+            inner,
+            max_lifetime,
+            vias,
+            via: None,
+        }
+    }
+
+    /// Client sending its requests over connections made with `via`
+    pub fn via(&self, via: &Arc<Via>) -> Self {
+        Client {
+            via: Some(self.vias.id(via)),
+            ..self.clone()
+        }
+    }
+
+    pub fn request(
+        &self,
+        mut request: Request<Body>,
+    ) -> Pin<Box<dyn Future<Output = Result<Response<Incoming>, legacy::Error>> + Send>> {
+        if let Some(uri) = self.via.and_then(|id| via_uri(request.uri(), id)) {
+            *request.uri_mut() = uri;
+        }
+        let capture = self
+            .max_lifetime
+            .map(|lifetime| (capture_connection(&mut request), lifetime));
+        let response = self.inner.request(request);
+        Box::pin(async move {
+            let response = response.await;
+            if let Some((capture, lifetime)) = capture {
+                retire(capture, lifetime);
+            }
+            response
+        })
+    }
+}
+
+/// Poison the connection of a request once it is `lifetime` old, so the
+/// pool drops it when idle instead of handing it out again
+fn retire(capture: CaptureConnection, lifetime: Duration) {
+    let age = {
+        let metadata = capture.connection_metadata();
+        let Some(connected) = metadata.as_ref() else {
+            return;
+        };
+        let mut extensions = hyper::http::Extensions::new();
+        connected.get_extras(&mut extensions);
+        let Some(established) = extensions.get::<Established>() else {
+            return;
+        };
+        let age = established.at.elapsed();
+        if age >= lifetime {
+            connected.poison();
+            return;
+        }
+        if established.retiring.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        age
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(lifetime - age).await;
+        if let Some(connected) = capture.connection_metadata().as_ref() {
+            connected.poison();
+        }
+    });
+}
+
+impl Read for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl Write for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+}
+
+/// TCP options applied to both accepted and outbound sockets
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Configure the outbound connector
+    pub fn apply_to_connector(&self, http: &mut HttpConnector<Resolver>) {
+        http.set_nodelay(self.nodelay);
+        http.set_keepalive(self.keepalive);
+        http.set_keepalive_interval(self.keepalive_interval);
+        http.set_send_buffer_size(self.send_buffer_size);
+        http.set_recv_buffer_size(self.recv_buffer_size);
+    }
+
+    /// Configure an accepted client socket
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn via_uris() {
+        let uri: Uri = "https://user@example.com:8443/a?b".parse().unwrap();
+        let routed = via_uri(&uri, 3).unwrap();
+        assert_eq!(routed, "https://via3@example.com:8443/a?b");
+        assert_eq!(routed.host(), Some("example.com"));
+        let (id, stripped) = split_via(routed);
+        assert_eq!(id, Some(3));
+        assert_eq!(stripped, "https://example.com:8443/a?b");
+        let (id, plain) = split_via("http://[::1]/".parse().unwrap());
+        assert_eq!(id, None);
+        assert_eq!(plain, "http://[::1]/");
+
+        let vias = Vias::default();
+        let via = |sni: &str| {
+            Arc::new(Via {
+                connect_to: None,
+                sni: Some(sni.to_string()),
+            })
+        };
+        assert_eq!(vias.id(&via("a.example.com")), 0);
+        assert_eq!(vias.id(&via("b.example.com")), 1);
+        assert_eq!(vias.id(&via("a.example.com")), 0);
+        assert_eq!(vias.get(1), Some(via("b.example.com")));
+    }
+
+    #[tokio::test]
+    async fn host_limits() {
+        let limiter = HostLimiter::new(1, Duration::from_millis(50));
+        let hosts = || limiter.hosts.lock().unwrap().len();
+        let first = limiter.acquire("a:80".to_string()).await.unwrap();
+        let started = Instant::now();
+        let full = limiter.acquire("a:80".to_string()).await;
+        assert!(full.err().unwrap().is::<ConnLimitExceeded>());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let other = limiter.acquire("b:80".to_string()).await.unwrap();
+        assert_eq!(hosts(), 2);
+
+        // A waiting request takes the slot once it is free
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("a:80".to_string()).await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(first);
+        assert!(waiting.await.unwrap());
+        drop(other);
+        assert_eq!(hosts(), 0);
+
+        let limiter = HostLimiter::new(1, Duration::ZERO);
+        let _first = limiter.acquire("a:80".to_string()).await.unwrap();
+        assert!(limiter.acquire("a:80".to_string()).await.is_err());
+        assert!(
+            HostLimiter::new(0, Duration::ZERO)
+                .acquire("a:80".to_string())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
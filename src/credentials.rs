@@ -0,0 +1,99 @@
+use anyhow::{Result, anyhow};
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+use url::Url;
+
+use crate::config::{self, CredentialConfig};
+use crate::host;
+
+/// Headers added to outbound requests by target host (`[[credential]]`)
+#[derive(Default)]
+pub struct Credentials {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    /// Lowercase host, or `*.domain` for its subdomains
+    host: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Credentials {
+    pub fn parse(configs: &[CredentialConfig]) -> Result<Self> {
+        let mut rules = Vec::new();
+        for config in configs {
+            let mut headers = Vec::new();
+            for (name, value) in &config.headers {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow!("Credential {}: invalid header {}", config.host, name))?;
+                let mut value =
+                    HeaderValue::from_str(&config::interpolate(value)?).map_err(|_| {
+                        anyhow!("Credential {}: invalid value for {}", config.host, name)
+                    })?;
+                // Keeps it out of debug output
+                value.set_sensitive(true);
+                headers.push((name, value));
+            }
+            rules.push(Rule {
+                host: config.host.to_ascii_lowercase(),
+                headers,
+            });
+        }
+        Ok(Credentials { rules })
+    }
+
+    /// Set the headers of the first rule matching the host of `url`,
+    /// replacing any the client sent
+    pub fn apply(&self, url: &Url, headers: &mut HeaderMap) {
+        let Some(host) = url.host_str() else {
+            return;
+        };
+        let host = host.to_ascii_lowercase();
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| host::matches(&rule.host, &host))
+        {
+            for (name, value) in &rule.headers {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_rules() {
+        let credentials = Credentials::parse(&[
+            CredentialConfig {
+                host: "ghcr.io".to_string(),
+                headers: [("Authorization".to_string(), "Bearer abc".to_string())].into(),
+            },
+            CredentialConfig {
+                host: "*.example.com".to_string(),
+                headers: [("X-Api-Key".to_string(), "key".to_string())].into(),
+            },
+        ])
+        .unwrap();
+        let headers = |url: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("authorization", "Basic client".parse().unwrap());
+            credentials.apply(&Url::parse(url).unwrap(), &mut headers);
+            headers
+        };
+
+        let ghcr = headers("https://GHCR.io/v2/");
+        assert_eq!(ghcr["authorization"], "Bearer abc");
+        assert!(ghcr["authorization"].is_sensitive());
+        assert_eq!(headers("https://api.example.com/")["x-api-key"], "key");
+        assert!(!headers("https://example.com/").contains_key("x-api-key"));
+        assert!(!headers("https://badexample.com/").contains_key("x-api-key"));
+        assert_eq!(
+            headers("https://other.io/")["authorization"],
+            "Basic client"
+        );
+    }
+}
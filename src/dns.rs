@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use hickory_resolver::TokioResolver;
+use hickory_resolver::config::{ConnectionConfig, NameServerConfig, ResolverConfig};
+use hickory_resolver::net::runtime::TokioRuntimeProvider;
+use hyper::Uri;
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use tower::Service;
+use tracing::warn;
+use url::Url;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Names whose last answer is kept for `--dns-stale-max-age`, the oldest
+/// is dropped for new ones
+const MAX_STALE_NAMES: usize = 1000;
+
+/// Address family policy for upstream connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddrFamily {
+    /// Keep the resolver's order
+    #[default]
+    Any,
+    /// Try IPv6 addresses first, IPv4 as the fallback
+    PreferIpv6,
+    /// Only connect over IPv4
+    Ipv4Only,
+}
+
+impl AddrFamily {
+    fn apply(self, addrs: &mut Vec<SocketAddr>) {
+        match self {
+            AddrFamily::Any => {}
+            // Stable sort keeps the resolver's order within each family
+            AddrFamily::PreferIpv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+            AddrFamily::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+        }
+    }
+}
+
+/// Resolver used by the upstream connector
+#[derive(Clone)]
+pub struct Resolver {
+    backend: Backend,
+    family: AddrFamily,
+    stale: Option<Arc<StaleAnswers>>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    /// Operating system resolver (getaddrinfo)
+    System(GaiResolver),
+    /// Explicitly configured DNS or DNS-over-HTTPS servers
+    Hickory(Arc<TokioResolver>),
+}
+
+impl Resolver {
+    /// Build a resolver from `--dns-server` and `--doh` options,
+    /// falling back to the system resolver when neither is given
+    pub async fn new(
+        dns_servers: &[String],
+        doh: Option<&str>,
+        family: AddrFamily,
+        stale_max_age: Option<Duration>,
+    ) -> Result<Self> {
+        let backend = Self::backend(dns_servers, doh).await?;
+        Ok(Resolver {
+            backend,
+            family,
+            stale: stale_max_age.map(|max_age| Arc::new(StaleAnswers::new(max_age))),
+        })
+    }
+
+    async fn backend(dns_servers: &[String], doh: Option<&str>) -> Result<Backend> {
+        let name_servers = if let Some(doh) = doh {
+            doh_name_servers(doh).await?
+        } else if !dns_servers.is_empty() {
+            dns_servers
+                .iter()
+                .map(|s| plain_name_server(s))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            return Ok(Backend::System(GaiResolver::new()));
+        };
+
+        let config = ResolverConfig::from_name_servers(name_servers);
+        let resolver =
+            TokioResolver::builder_with_config(config, TokioRuntimeProvider::default()).build()?;
+        Ok(Backend::Hickory(Arc::new(resolver)))
+    }
+}
+
+/// Parse `IP` or `IP:PORT` into a UDP/TCP name server
+fn plain_name_server(server: &str) -> Result<NameServerConfig> {
+    let (ip, port) = if let Ok(addr) = server.parse::<SocketAddr>() {
+        (addr.ip(), addr.port())
+    } else if let Ok(ip) = server.parse::<IpAddr>() {
+        (ip, 53)
+    } else {
+        return Err(anyhow!("Invalid DNS server address: {}", server));
+    };
+
+    let mut udp = ConnectionConfig::udp();
+    udp.port = port;
+    let mut tcp = ConnectionConfig::tcp();
+    tcp.port = port;
+    Ok(NameServerConfig::new(ip, true, vec![udp, tcp]))
+}
+
+/// Build DNS-over-HTTPS name servers from an endpoint URL
+///
+/// The endpoint host itself is bootstrapped once through the system resolver.
+async fn doh_name_servers(endpoint: &str) -> Result<Vec<NameServerConfig>> {
+    let url = Url::parse(endpoint).map_err(|e| anyhow!("Invalid DoH URL {}: {}", endpoint, e))?;
+    if url.scheme() != "https" {
+        return Err(anyhow!("DoH URL must use https: {}", endpoint));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("DoH URL has no host: {}", endpoint))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port().unwrap_or(443);
+
+    let ips: Vec<IpAddr> = if let Ok(ip) = host.parse::<IpAddr>() {
+        vec![ip]
+    } else {
+        tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .map(|addr| addr.ip())
+            .collect()
+    };
+    if ips.is_empty() {
+        return Err(anyhow!("Could not resolve DoH host: {}", host));
+    }
+
+    let server_name: Arc<str> = Arc::from(host.as_str());
+    let path: Arc<str> = Arc::from(url.path());
+    Ok(ips
+        .into_iter()
+        .map(|ip| {
+            let mut https = ConnectionConfig::https(server_name.clone(), Some(path.clone()));
+            https.port = port;
+            NameServerConfig::new(ip, true, vec![https])
+        })
+        .collect())
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let Resolver {
+            backend,
+            family,
+            stale,
+        } = self.clone();
+        Box::pin(async move {
+            let lookup: Result<Vec<SocketAddr>, BoxError> = match backend {
+                Backend::System(mut gai) => gai
+                    .call(name.clone())
+                    .await
+                    .map(Iterator::collect)
+                    .map_err(Into::into),
+                Backend::Hickory(hickory) => hickory
+                    .lookup_ip(name.as_str())
+                    .await
+                    .map(|lookup| lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect())
+                    .map_err(Into::into),
+            };
+            let mut addrs = match (lookup, &stale) {
+                (Ok(addrs), Some(stale)) => {
+                    stale.store(name.as_str(), &addrs);
+                    addrs
+                }
+                (Ok(addrs), None) => addrs,
+                (Err(e), Some(stale)) => match stale.get(name.as_str()) {
+                    Some((age, addrs)) => {
+                        warn!(
+                            "Failed to resolve {} ({}), using the answer from {}s ago",
+                            name,
+                            e,
+                            age.as_secs()
+                        );
+                        addrs
+                    }
+                    None => return Err(e),
+                },
+                (Err(e), None) => return Err(e),
+            };
+            family.apply(&mut addrs);
+            if addrs.is_empty() {
+                return Err(format!("no usable address for {}", name).into());
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Last successful answer per name, used when resolving it fails
+/// (`--dns-stale-max-age`)
+struct StaleAnswers {
+    max_age: Duration,
+    answers: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl StaleAnswers {
+    fn new(max_age: Duration) -> Self {
+        StaleAnswers {
+            max_age,
+            answers: Mutex::default(),
+        }
+    }
+
+    fn store(&self, name: &str, addrs: &[SocketAddr]) {
+        if addrs.is_empty() {
+            return;
+        }
+        let mut answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        if answers.len() >= MAX_STALE_NAMES && !answers.contains_key(name) {
+            answers.retain(|_, (at, _)| at.elapsed() <= self.max_age);
+            if answers.len() >= MAX_STALE_NAMES
+                && let Some(oldest) = answers
+                    .iter()
+                    .min_by_key(|(_, (at, _))| *at)
+                    .map(|(name, _)| name.clone())
+            {
+                answers.remove(&oldest);
+            }
+        }
+        answers.insert(name.to_string(), (Instant::now(), addrs.to_vec()));
+    }
+
+    /// Answer for `name` and its age, if it is not older than `max_age`
+    fn get(&self, name: &str) -> Option<(Duration, Vec<SocketAddr>)> {
+        let mut answers = self.answers.lock().unwrap_or_else(|e| e.into_inner());
+        let (at, addrs) = answers.get(name)?;
+        let age = at.elapsed();
+        if age > self.max_age {
+            answers.remove(name);
+            return None;
+        }
+        Some((age, addrs.clone()))
+    }
+}
+
+/// Static `HOST:PORT -> ADDR` overrides, like curl's `--resolve`
+#[derive(Clone, Default)]
+pub struct Overrides(Arc<HashMap<(String, u16), SocketAddr>>);
+
+impl Overrides {
+    /// Parse `HOST:PORT:ADDR` entries
+    pub fn parse(entries: &[String]) -> Result<Self> {
+        let mut map = HashMap::new();
+        for entry in entries {
+            let mut fields = entry.splitn(3, ':');
+            let (Some(host), Some(port), Some(addr)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(anyhow!(
+                    "Invalid resolve entry (expected HOST:PORT:ADDR): {}",
+                    entry
+                ));
+            };
+            let port: u16 = port
+                .parse()
+                .map_err(|_| anyhow!("Invalid port in resolve entry: {}", entry))?;
+            let addr: IpAddr = addr
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| anyhow!("Invalid address in resolve entry: {}", entry))?;
+            map.insert(
+                (host.to_ascii_lowercase(), port),
+                SocketAddr::new(addr, port),
+            );
+        }
+        Ok(Overrides(Arc::new(map)))
+    }
+
+    pub fn lookup(&self, uri: &Uri) -> Option<SocketAddr> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let port = uri_port(uri);
+        self.0.get(&(host, port)).copied()
+    }
+}
+
+/// Explicit port of a URI, or the scheme default
+pub fn uri_port(uri: &Uri) -> u16 {
+    uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::config::ProtocolConfig;
+
+    use super::*;
+
+    #[test]
+    fn resolve_overrides() {
+        let overrides = Overrides::parse(&[
+            "Example.com:443:192.0.2.1".to_string(),
+            "example.net:80:[2001:db8::1]".to_string(),
+            "example.com:443:192.0.2.2".to_string(),
+        ])
+        .unwrap();
+        let lookup = |uri: &str| overrides.lookup(&uri.parse().unwrap());
+        assert_eq!(
+            lookup("https://EXAMPLE.com/a"),
+            Some("192.0.2.2:443".parse().unwrap())
+        );
+        assert_eq!(
+            lookup("http://example.net/"),
+            Some("[2001:db8::1]:80".parse().unwrap())
+        );
+        assert_eq!(lookup("http://example.com/"), None);
+        assert_eq!(lookup("https://example.org/"), None);
+        for entry in [
+            "example.com:443",
+            "example.com:https:192.0.2.1",
+            "example.com:443:host",
+        ] {
+            assert!(Overrides::parse(&[entry.to_string()]).is_err(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn plain_name_servers() {
+        let ports = |server: &NameServerConfig| {
+            server
+                .connections
+                .iter()
+                .map(|c| c.port)
+                .collect::<Vec<_>>()
+        };
+        let server = plain_name_server("192.0.2.53").unwrap();
+        assert_eq!(server.ip, "192.0.2.53".parse::<IpAddr>().unwrap());
+        assert_eq!(ports(&server), [53, 53]);
+        let server = plain_name_server("[2001:db8::53]:5353").unwrap();
+        assert_eq!(server.ip, "2001:db8::53".parse::<IpAddr>().unwrap());
+        assert_eq!(ports(&server), [5353, 5353]);
+        for server in ["dns.example.com", "192.0.2.53:dns", ""] {
+            assert!(plain_name_server(server).is_err(), "{}", server);
+        }
+    }
+
+    #[tokio::test]
+    async fn doh_endpoints() {
+        let servers = doh_name_servers("https://[2001:db8::53]:8443/dns-query")
+            .await
+            .unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].ip, "2001:db8::53".parse::<IpAddr>().unwrap());
+        let https = &servers[0].connections[0];
+        assert_eq!(https.port, 8443);
+        assert!(matches!(
+            &https.protocol,
+            ProtocolConfig::Https { server_name, path }
+                if &**server_name == "2001:db8::53" && &**path == "/dns-query"
+        ));
+        for endpoint in [
+            "http://192.0.2.53/dns-query",
+            "https:///dns-query",
+            "https://[2001:db8::53/dns-query",
+            "dns.example.com",
+        ] {
+            assert!(doh_name_servers(endpoint).await.is_err(), "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn stale_answers() {
+        let addrs: Vec<SocketAddr> = vec!["192.0.2.1:0".parse().unwrap()];
+        let stale = StaleAnswers::new(Duration::from_secs(60));
+        stale.store("example.com", &addrs);
+        stale.store("empty.example.com", &[]);
+        assert_eq!(stale.get("example.com").unwrap().1, addrs);
+        assert!(stale.get("empty.example.com").is_none());
+        assert!(stale.get("example.org").is_none());
+
+        let expired = StaleAnswers::new(Duration::ZERO);
+        expired.store("example.com", &addrs);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(expired.get("example.com").is_none());
+
+        for i in 0..MAX_STALE_NAMES + 10 {
+            stale.store(&format!("{}.example.net", i), &addrs);
+        }
+        let answers = stale.answers.lock().unwrap().len();
+        assert_eq!(answers, MAX_STALE_NAMES);
+        assert!(stale.get("example.com").is_none());
+        assert!(
+            stale
+                .get(&format!("{}.example.net", MAX_STALE_NAMES))
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::HeaderMap;
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use tracing::info;
+
+use crate::body::{self, Body, BoxError};
+use crate::har;
+
+/// Bytes of each body logged with `--dump-traffic full`
+const BODY_LIMIT: usize = 1024;
+
+/// What `--dump-traffic` logs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dump {
+    /// Request and response heads
+    Headers,
+    /// Heads and the start of bodies
+    Full,
+}
+
+impl Dump {
+    /// Log a request or response head, e.g. `upstream >`, with secrets
+    /// redacted
+    pub fn head(self, request_id: &str, direction: &str, first_line: &str, headers: &HeaderMap) {
+        info!(
+            "{} {} {}{}",
+            request_id,
+            direction,
+            first_line,
+            format_headers(headers)
+        );
+    }
+
+    /// Log the start of `body` once it is done, for `full` dumps
+    pub fn body(self, request_id: &str, direction: &str, body: Body) -> Body {
+        if self == Dump::Headers {
+            return body;
+        }
+        body::boxed(DumpBody {
+            inner: body,
+            label: format!("{} {} body", request_id, direction),
+            data: Vec::new(),
+            size: 0,
+        })
+    }
+}
+
+fn format_headers(headers: &HeaderMap) -> String {
+    let mut out = String::new();
+    for (name, value) in headers {
+        let value = if har::is_secret(name, value) {
+            har::REDACTED.into()
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        let _ = write!(out, "\n    {}: {}", name, value);
+    }
+    out
+}
+
+/// Keeps the start of a body and logs it when dropped
+struct DumpBody {
+    inner: Body,
+    label: String,
+    data: Vec<u8>,
+    size: u64,
+}
+
+impl HttpBody for DumpBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            let data = data.clone();
+            self.size += data.len() as u64;
+            let room = BODY_LIMIT.saturating_sub(self.data.len());
+            self.data.extend_from_slice(&data[..data.len().min(room)]);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for DumpBody {
+    fn drop(&mut self) {
+        if self.size > 0 {
+            info!(
+                "{} ({} bytes): {:?}{}",
+                self.label,
+                self.size,
+                String::from_utf8_lossy(&self.data),
+                if self.size > self.data.len() as u64 {
+                    "..."
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", "*/*".parse().unwrap());
+        headers.insert("cookie", "session=abc".parse().unwrap());
+        assert_eq!(
+            format_headers(&headers),
+            "\n    accept: */*\n    cookie: [redacted]"
+        );
+    }
+}
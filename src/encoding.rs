@@ -0,0 +1,304 @@
+use std::io;
+
+use async_compression::Level;
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder, ZstdDecoder,
+    ZstdEncoder,
+};
+use futures_util::TryStreamExt;
+use http_body_util::BodyDataStream;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{Method, StatusCode};
+use tokio_util::io::StreamReader;
+
+use crate::body::Body;
+use crate::filter::{self, ContentTypes};
+use crate::pool;
+
+/// Content codings the proxy understands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coding {
+    Br,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    /// Codings the proxy produces, in server preference order
+    const ENCODABLE: &[Coding] = &[Coding::Br, Coding::Zstd, Coding::Gzip];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Coding::Br => "br",
+            Coding::Zstd => "zstd",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Coding::Br),
+            "zstd" => Some(Coding::Zstd),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// Pick the coding the client weighs highest, ties broken by server preference
+pub fn negotiate(headers: &HeaderMap, supported: &[Coding]) -> Option<Coding> {
+    let accepted: Vec<(String, f32)> = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, q))
+        })
+        .collect();
+    let weight = |name: &str| {
+        let explicit = accepted.iter().find(|(n, _)| n == name);
+        let wildcard = accepted.iter().find(|(n, _)| n == "*");
+        explicit.or(wildcard).map_or(0.0, |(_, q)| *q)
+    };
+
+    let mut best: Option<(Coding, f32)> = None;
+    for coding in supported {
+        let q = weight(coding.as_str());
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+/// Compress a streaming body
+pub fn encode(body: Body, coding: Coding) -> Body {
+    let reader = StreamReader::new(BodyDataStream::new(body).map_err(io::Error::other));
+    match coding {
+        Coding::Br => pool::stream(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+        Coding::Zstd => pool::stream(ZstdEncoder::new(reader)),
+        Coding::Gzip => pool::stream(GzipEncoder::new(reader)),
+        Coding::Deflate => pool::stream(ZlibEncoder::new(reader)),
+    }
+}
+
+/// Decompress a streaming body
+pub fn decode(body: Body, coding: Coding) -> Body {
+    let reader = StreamReader::new(BodyDataStream::new(body).map_err(io::Error::other));
+    match coding {
+        Coding::Br => pool::stream(BrotliDecoder::new(reader)),
+        Coding::Zstd => pool::stream(ZstdDecoder::new(reader)),
+        Coding::Gzip => pool::stream(GzipDecoder::new(reader)),
+        // HTTP "deflate" is the zlib format (RFC 9110 8.4.1.2)
+        Coding::Deflate => pool::stream(ZlibDecoder::new(reader)),
+    }
+}
+
+/// On-the-fly compression of identity responses (`--compress`)
+pub struct Compression {
+    /// Smallest Content-Length worth compressing
+    pub min_size: u64,
+    /// Content types to compress
+    pub types: ContentTypes,
+}
+
+impl Compression {
+    /// Compress the response if the client accepts it and it is worth it
+    pub fn apply(
+        &self,
+        method: &Method,
+        request_headers: &HeaderMap,
+        parts: &mut Parts,
+        body: Body,
+    ) -> Body {
+        if !self.should_compress(method, parts) {
+            return body;
+        }
+        let Some(coding) = negotiate(request_headers, Coding::ENCODABLE) else {
+            return body;
+        };
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(coding.as_str()),
+        );
+        add_vary_accept_encoding(&mut parts.headers);
+        weaken_etag(&mut parts.headers);
+        encode(body, coding)
+    }
+
+    fn should_compress(&self, method: &Method, parts: &Parts) -> bool {
+        let headers = &parts.headers;
+        if method == Method::HEAD
+            || parts.status == StatusCode::NO_CONTENT
+            || parts.status == StatusCode::NOT_MODIFIED
+            || parts.status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(header::CONTENT_RANGE)
+            || headers.contains_key(header::TRAILER)
+        {
+            return false;
+        }
+        // Already encoded by upstream
+        if headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|e| !e.as_bytes().eq_ignore_ascii_case(b"identity"))
+        {
+            return false;
+        }
+        if header_has_token(headers, header::CACHE_CONTROL, "no-transform") {
+            return false;
+        }
+        if let Some(length) = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            && length < self.min_size
+        {
+            return false;
+        }
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|content_type| self.compressible(content_type))
+    }
+
+    fn compressible(&self, content_type: &str) -> bool {
+        let mime = filter::mime(content_type);
+        mime.ends_with("+json") || mime.ends_with("+xml") || self.types.matches(&mime)
+    }
+}
+
+/// Decoding of upstream codings the client does not accept (`--decompress`)
+///
+/// The body is re-encoded with the best coding the client accepts, identity
+/// otherwise. Requests without Accept-Encoding accept any coding.
+pub struct Decompression;
+
+impl Decompression {
+    pub fn apply(
+        &self,
+        method: &Method,
+        request_headers: &HeaderMap,
+        parts: &mut Parts,
+        body: Body,
+    ) -> Body {
+        if method == Method::HEAD
+            || !request_headers.contains_key(header::ACCEPT_ENCODING)
+            || header_has_token(&parts.headers, header::CACHE_CONTROL, "no-transform")
+        {
+            return body;
+        }
+        // Only a single, known coding can be undone
+        let Some(coding) = parts
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.contains(','))
+            .and_then(Coding::parse)
+        else {
+            return body;
+        };
+        if negotiate(request_headers, &[coding]).is_some() {
+            return body;
+        }
+
+        let body = decode(body, coding);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.remove(header::CONTENT_ENCODING);
+        add_vary_accept_encoding(&mut parts.headers);
+        weaken_etag(&mut parts.headers);
+        if let Some(coding) = negotiate(request_headers, Coding::ENCODABLE) {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(coding.as_str()),
+            );
+            return encode(body, coding);
+        }
+        body
+    }
+}
+
+pub fn header_has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn add_vary_accept_encoding(headers: &mut HeaderMap) {
+    if header_has_token(headers, header::VARY, "accept-encoding")
+        || header_has_token(headers, header::VARY, "*")
+    {
+        return;
+    }
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+}
+
+/// The representation changed, so a strong validator no longer holds
+pub fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(header::ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+    {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+        if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+            headers.insert(header::ETAG, weak);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiation() {
+        let all = Coding::ENCODABLE;
+        assert_eq!(
+            negotiate(&accept("gzip, deflate, br"), all),
+            Some(Coding::Br)
+        );
+        assert_eq!(negotiate(&accept("gzip"), all), Some(Coding::Gzip));
+        assert_eq!(negotiate(&accept("gzip, zstd"), all), Some(Coding::Zstd));
+        assert_eq!(
+            negotiate(&accept("br;q=0.5, gzip"), all),
+            Some(Coding::Gzip)
+        );
+        assert_eq!(negotiate(&accept("br;q=0, *"), all), Some(Coding::Zstd));
+        assert_eq!(negotiate(&accept("identity"), all), None);
+        assert_eq!(negotiate(&HeaderMap::new(), all), None);
+    }
+
+    #[test]
+    fn compressible_types() {
+        let compression = Compression {
+            min_size: 0,
+            types: ContentTypes::new(&["text/*".into(), "application/json".into()]),
+        };
+        assert!(compression.compressible("text/html; charset=utf-8"));
+        assert!(compression.compressible("application/json"));
+        assert!(compression.compressible("application/vnd.api+json"));
+        assert!(!compression.compressible("image/png"));
+        assert!(!compression.compressible("application/octet-stream"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+use hyper::{HeaderMap, Response, StatusCode, header};
+use serde_json::json;
+
+use crate::body::{self, Body};
+use crate::local::escape_html;
+
+/// Header carrying the request id, from the client or generated
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// Extension of the error responses the proxy made itself, as opposed to
+/// ones from upstreams
+#[derive(Clone, Copy, Debug)]
+pub struct Rendered;
+
+/// Body format of error responses (`--error-format`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Plain text message, or HTML from `*.html` templates
+    #[default]
+    Text,
+    /// `{"error": {"code", "message", "request_id"}}`, or `*.json` templates
+    Json,
+}
+
+impl ErrorFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ErrorFormat::Text => "html",
+            ErrorFormat::Json => "json",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ErrorFormat::Text => "text/html; charset=utf-8",
+            ErrorFormat::Json => "application/json",
+        }
+    }
+
+    /// Escape a value substituted into a template
+    fn escape(self, value: &str) -> String {
+        match self {
+            ErrorFormat::Text => escape_html(value),
+            ErrorFormat::Json => {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+        }
+    }
+}
+
+/// Error responses, optionally rendered from templates (`--error-pages`)
+///
+/// Templates are looked up as e.g. `502.html`, then `5xx.html`, then
+/// `error.html` (`.json` with `--error-format json`), and may use
+/// `{{status}}`, `{{reason}}`, `{{message}}` and `{{request_id}}`.
+#[derive(Default)]
+pub struct ErrorPages {
+    format: ErrorFormat,
+    templates: HashMap<String, String>,
+}
+
+impl ErrorPages {
+    /// Load every template for `format` in `dir`
+    pub fn load(dir: Option<&Path>, format: ErrorFormat) -> Result<Self> {
+        let mut templates = HashMap::new();
+        let Some(dir) = dir else {
+            return Ok(ErrorPages { format, templates });
+        };
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| anyhow!("Failed to read error pages {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == format.extension())
+                && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+            {
+                let template = std::fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+                templates.insert(stem.to_ascii_lowercase(), template);
+            }
+        }
+        Ok(ErrorPages { format, templates })
+    }
+
+    /// Build an error response; `message` is shown to the client, so it
+    /// must not contain internal details
+    pub fn render(&self, status: StatusCode, message: &str, request_id: &str) -> Response<Body> {
+        let code = status.as_u16().to_string();
+        let class = format!("{}xx", status.as_u16() / 100);
+        let template = [code.as_str(), class.as_str(), "error"]
+            .iter()
+            .find_map(|name| self.templates.get(*name));
+
+        let format = self.format;
+        let (content_type, content) = match (template, format) {
+            (Some(template), _) => {
+                let page = template
+                    .replace("{{status}}", &code)
+                    .replace(
+                        "{{reason}}",
+                        &format.escape(status.canonical_reason().unwrap_or("")),
+                    )
+                    .replace("{{message}}", &format.escape(message))
+                    .replace("{{request_id}}", &format.escape(request_id));
+                (format.content_type(), page)
+            }
+            (None, ErrorFormat::Text) => ("text/plain; charset=utf-8", message.to_string()),
+            (None, ErrorFormat::Json) => {
+                let error = json!({
+                    "error": {
+                        "code": status.as_u16(),
+                        "message": message,
+                        "request_id": request_id,
+                    }
+                });
+                (format.content_type(), error.to_string())
+            }
+        };
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(REQUEST_ID, request_id)
+            .extension(Rendered)
+            .body(body::full(content))
+            .unwrap()
+    }
+}
+
+/// The client's request id if it sent a sane one, a new one otherwise
+pub fn request_id(headers: &HeaderMap) -> String {
+    if let Some(id) = headers.get(REQUEST_ID).and_then(|v| v.to_str().ok())
+        && !id.is_empty()
+        && id.len() <= 64
+        && id.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return id.to_string();
+    }
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn text(response: Response<Body>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn template_lookup() {
+        let pages = ErrorPages {
+            format: ErrorFormat::Text,
+            templates: HashMap::from([
+                (
+                    "5xx".to_string(),
+                    "{{status}} {{reason}}: {{message}}".to_string(),
+                ),
+                ("error".to_string(), "error {{request_id}}".to_string()),
+            ]),
+        };
+        let response = pages.render(StatusCode::BAD_GATEWAY, "<upstream>", "id");
+        assert_eq!(response.headers()[REQUEST_ID], "id");
+        assert_eq!(text(response).await, "502 Bad Gateway: &lt;upstream&gt;");
+        let response = pages.render(StatusCode::NOT_FOUND, "Not found", "id");
+        assert_eq!(text(response).await, "error id");
+
+        let response = ErrorPages::default().render(StatusCode::NOT_FOUND, "Not found", "id");
+        assert_eq!(text(response).await, "Not found");
+    }
+
+    #[tokio::test]
+    async fn json_errors() {
+        let pages = ErrorPages {
+            format: ErrorFormat::Json,
+            templates: HashMap::new(),
+        };
+        let response = pages.render(StatusCode::BAD_GATEWAY, "Upstream \"x\" failed", "id");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let error: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(
+            error,
+            json!({"error": {"code": 502, "message": "Upstream \"x\" failed", "request_id": "id"}})
+        );
+
+        let pages = ErrorPages {
+            format: ErrorFormat::Json,
+            templates: HashMap::from([(
+                "error".to_string(),
+                "{\"msg\": \"{{message}}\"}".to_string(),
+            )]),
+        };
+        let response = pages.render(StatusCode::BAD_GATEWAY, "a \"b\"", "id");
+        assert_eq!(text(response).await, "{\"msg\": \"a \\\"b\\\"\"}");
+    }
+
+    #[test]
+    fn client_request_ids() {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID, "abc-123".parse().unwrap());
+        assert_eq!(request_id(&headers), "abc-123");
+        headers.insert(REQUEST_ID, "has space".parse().unwrap());
+        assert_eq!(request_id(&headers).len(), 16);
+        assert_ne!(request_id(&HeaderMap::new()), request_id(&HeaderMap::new()));
+    }
+}
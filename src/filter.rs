@@ -0,0 +1,218 @@
+use std::process::Stdio;
+
+use anyhow::{Result, anyhow};
+use futures_util::TryStreamExt;
+use http_body_util::BodyDataStream;
+use hyper::StatusCode;
+use hyper::header::{self, HeaderValue};
+use hyper::http::request;
+use hyper::http::response::Parts;
+use tokio::process::Command;
+use tokio_util::io::StreamReader;
+use tracing::warn;
+
+use crate::body::Body;
+use crate::encoding::{self, Coding};
+use crate::pool;
+
+/// Content type patterns; `text/*` style wildcards are allowed
+#[derive(Clone, Debug, Default)]
+pub struct ContentTypes(Vec<String>);
+
+impl ContentTypes {
+    pub fn new(patterns: &[String]) -> Self {
+        ContentTypes(
+            patterns
+                .iter()
+                .map(|p| p.trim().to_ascii_lowercase())
+                .collect(),
+        )
+    }
+
+    /// Whether a Content-Type value matches any pattern, ignoring parameters
+    pub fn matches(&self, content_type: &str) -> bool {
+        let mime = mime(content_type);
+        self.0
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(top) => mime.split('/').next() == Some(top),
+                None => *pattern == mime,
+            })
+    }
+}
+
+/// Media type of a Content-Type value, lowercased and without parameters
+pub fn mime(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Transformation of decoded response bodies
+///
+/// Filters see the client request and may edit the response head, e.g. to
+/// change Content-Type, but always take and return an identity body.
+pub trait Filter: Send + Sync {
+    fn apply(&self, request: &request::Parts, parts: &mut Parts, body: Body) -> Body;
+}
+
+/// Filters registered against content types, run in registration order
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<(ContentTypes, Box<dyn Filter>)>,
+}
+
+impl Pipeline {
+    pub fn register(&mut self, types: ContentTypes, filter: impl Filter + 'static) {
+        self.stages.push((types, Box::new(filter)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Run every matching filter over the response
+    ///
+    /// Encoded bodies are decoded first and re-encoded with the same coding
+    /// afterwards, so the client gets what it asked upstream for.
+    pub fn apply(&self, request: &request::Parts, parts: &mut Parts, mut body: Body) -> Body {
+        if request.method == hyper::Method::HEAD
+            || parts.status == StatusCode::NO_CONTENT
+            || parts.status == StatusCode::NOT_MODIFIED
+            || parts.status == StatusCode::PARTIAL_CONTENT
+            || parts.headers.contains_key(header::CONTENT_RANGE)
+            || encoding::header_has_token(&parts.headers, header::CACHE_CONTROL, "no-transform")
+        {
+            return body;
+        }
+        let Some(content_type) = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+        else {
+            return body;
+        };
+        let mut stages = self
+            .stages
+            .iter()
+            .filter(|(types, _)| types.matches(&content_type))
+            .peekable();
+        if stages.peek().is_none() {
+            return body;
+        }
+
+        // Only a single, known coding can be undone
+        let coding = match parts.headers.get(header::CONTENT_ENCODING) {
+            None => None,
+            Some(v) if v.as_bytes().eq_ignore_ascii_case(b"identity") => None,
+            Some(v) => match v
+                .to_str()
+                .ok()
+                .filter(|v| !v.contains(','))
+                .and_then(Coding::parse)
+            {
+                Some(coding) => Some(coding),
+                None => return body,
+            },
+        };
+        if let Some(coding) = coding {
+            body = encoding::decode(body, coding);
+            parts.headers.remove(header::CONTENT_ENCODING);
+        }
+        parts.headers.remove(header::CONTENT_LENGTH);
+        encoding::weaken_etag(&mut parts.headers);
+
+        for (_, filter) in stages {
+            body = filter.apply(request, parts, body);
+        }
+
+        if let Some(coding) = coding {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(coding.as_str()),
+            );
+            body = encoding::encode(body, coding);
+        }
+        body
+    }
+}
+
+/// Pipe bodies through an external command (`--filter TYPES=COMMAND`)
+///
+/// The command runs under `sh -c`, reading the body on stdin and writing the
+/// replacement to stdout.
+pub struct CommandFilter {
+    command: String,
+}
+
+impl CommandFilter {
+    /// Parse a `TYPES=COMMAND` option, with comma separated content types
+    pub fn parse(option: &str) -> Result<(ContentTypes, Self)> {
+        let Some((types, command)) = option.split_once('=') else {
+            return Err(anyhow!(
+                "Invalid filter (expected TYPES=COMMAND): {}",
+                option
+            ));
+        };
+        let types: Vec<String> = types
+            .split(',')
+            .filter(|t| !t.trim().is_empty())
+            .map(str::to_string)
+            .collect();
+        if types.is_empty() || command.trim().is_empty() {
+            return Err(anyhow!(
+                "Invalid filter (expected TYPES=COMMAND): {}",
+                option
+            ));
+        }
+        Ok((
+            ContentTypes::new(&types),
+            CommandFilter {
+                command: command.to_string(),
+            },
+        ))
+    }
+}
+
+impl Filter for CommandFilter {
+    fn apply(&self, _request: &request::Parts, _parts: &mut Parts, body: Body) -> Body {
+        let spawned = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run filter {:?}: {}", self.command, e);
+                return body;
+            }
+        };
+        let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return body;
+        };
+
+        let mut reader =
+            StreamReader::new(BodyDataStream::new(body).map_err(std::io::Error::other));
+        let command = self.command.clone();
+        tokio::spawn(async move {
+            // The command may stop reading early, which is not an error
+            let _ = tokio::io::copy(&mut reader, &mut stdin).await;
+            drop(stdin);
+            match child.wait().await {
+                Ok(status) if !status.success() => {
+                    warn!("Filter {:?} exited with {}", command, status)
+                }
+                Err(e) => warn!("Filter {:?} failed: {}", command, e),
+                _ => {}
+            }
+        });
+        pool::stream(stdout)
+    }
+}
@@ -0,0 +1,291 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value};
+
+/// Start of the metadata section at the end of a database
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// Levels of maps, arrays and pointers a value may have
+const MAX_DEPTH: usize = 32;
+
+/// Country database in the MaxMind DB format, e.g. GeoLite2-Country
+/// (`--geoip-db`)
+pub struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ipv6: bool,
+    /// Node where IPv4 addresses start, after 96 zero bits in an IPv6 tree
+    ipv4_start: usize,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(data).map_err(|e| anyhow!("Invalid GeoIP database {}: {}", path.display(), e))
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self> {
+        let start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("no metadata"))?
+            + METADATA_MARKER.len();
+        let metadata = Decoder {
+            data: &data[start..],
+        }
+        .decode(0)?
+        .0;
+        let field = |name: &str| {
+            metadata[name]
+                .as_u64()
+                .map(|value| value as usize)
+                .ok_or_else(|| anyhow!("no {} in metadata", name))
+        };
+        let (node_count, record_size, ip_version) = (
+            field("node_count")?,
+            field("record_size")?,
+            field("ip_version")?,
+        );
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(anyhow!("unsupported record size {}", record_size));
+        }
+        if node_count * record_size / 4 + 16 > start {
+            return Err(anyhow!("truncated search tree"));
+        }
+        let mut database = Database {
+            data,
+            node_count,
+            record_size,
+            ipv6: ip_version == 6,
+            ipv4_start: 0,
+        };
+        if database.ipv6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, false);
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /// Left or right record of a search tree node
+    fn record(&self, node: usize, right: bool) -> usize {
+        let size = self.record_size * 2 / 8;
+        let bytes = &self.data[node * size..(node + 1) * size];
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, b| n << 8 | *b as usize);
+        match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            (28, false) => (bytes[3] as usize & 0xf0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        }
+    }
+
+    /// ISO code of the country of `ip`, `None` for addresses not in the
+    /// database such as private ones
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let (bits, width, mut node) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32, self.ipv4_start),
+            IpAddr::V6(_) if !self.ipv6 => return None,
+            IpAddr::V6(ip) => (u128::from(ip), 128, 0),
+        };
+        for i in (0..width).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bits >> i & 1 == 1);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = node.checked_sub(self.node_count + 16)?;
+        let section = self.node_count * self.record_size / 4 + 16;
+        let decoder = Decoder {
+            data: &self.data[section..],
+        };
+        let record = decoder.decode(offset).ok()?.0;
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|key| record[key]["iso_code"].as_str())
+            // Ends up in metric labels
+            .filter(|code| code.len() <= 3 && code.bytes().all(|b| b.is_ascii_alphanumeric()))
+            .map(str::to_string)
+    }
+}
+
+/// Whether a client in `country` passes `--allow-countries` and
+/// `--deny-countries`; unknown countries only fail allow lists
+pub fn allowed(country: Option<&str>, allow: &[String], deny: &[String]) -> bool {
+    let listed = |list: &[String]| country.is_some_and(|country| list.iter().any(|c| c == country));
+    (allow.is_empty() || listed(allow)) && !listed(deny)
+}
+
+/// Reads values of the data section format
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        self.data
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("data out of bounds"))
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u64> {
+        Ok(self
+            .bytes(offset, len)?
+            .iter()
+            .fold(0, |n, b| n << 8 | *b as u64))
+    }
+
+    /// Value at `offset` and the offset after it
+    fn decode(&self, offset: usize) -> Result<(Value, usize)> {
+        self.value(offset, 0)
+    }
+
+    fn value(&self, offset: usize, depth: usize) -> Result<(Value, usize)> {
+        // Pointers can form loops in a corrupt database
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("data nested too deeply"));
+        }
+        let control = *self.bytes(offset, 1)?.first().unwrap_or(&0);
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            // Pointer, followed to a value elsewhere in the section
+            let extra = (control >> 3 & 3) as usize + 1;
+            let low = (control & 7) as u64;
+            let target = match extra {
+                1 => low << 8 | self.uint(offset, 1)?,
+                2 => (low << 16 | self.uint(offset, 2)?) + 2048,
+                3 => (low << 24 | self.uint(offset, 3)?) + 526_336,
+                _ => self.uint(offset, 4)?,
+            };
+            let (value, _) = self.value(target as usize, depth + 1)?;
+            return Ok((value, offset + extra));
+        }
+        if kind == 0 {
+            kind = 7 + self.uint(offset, 1)? as u8;
+            offset += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        let extra = size.saturating_sub(28);
+        if extra > 0 {
+            let base = [29, 285, 65_821][extra - 1];
+            size = base + self.uint(offset, extra)? as usize;
+            offset += extra;
+        }
+        let value = match kind {
+            2 => Value::from(String::from_utf8_lossy(self.bytes(offset, size)?).into_owned()),
+            3 => Value::from(f64::from_be_bytes(
+                self.bytes(offset, 8)?.try_into().unwrap_or_default(),
+            )),
+            4 => Value::from(self.bytes(offset, size)?.to_vec()),
+            5 | 6 | 9 | 10 if size <= 8 => Value::from(self.uint(offset, size)?),
+            10 => Value::Null,
+            8 => Value::from(self.uint(offset, size)? as u32 as i32),
+            7 => {
+                let mut map = Map::new();
+                let mut next = offset;
+                for _ in 0..size {
+                    let (key, after_key) = self.value(next, depth + 1)?;
+                    let (value, after_value) = self.value(after_key, depth + 1)?;
+                    map.insert(key.as_str().unwrap_or_default().to_string(), value);
+                    next = after_value;
+                }
+                return Ok((Value::Object(map), next));
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(1024));
+                let mut next = offset;
+                for _ in 0..size {
+                    let (item, after) = self.value(next, depth + 1)?;
+                    items.push(item);
+                    next = after;
+                }
+                return Ok((Value::Array(items), next));
+            }
+            14 => return Ok((Value::from(size != 0), offset)),
+            15 => Value::from(f32::from_be_bytes(
+                self.bytes(offset, 4)?.try_into().unwrap_or_default(),
+            )),
+            _ => return Err(anyhow!("unsupported data type {}", kind)),
+        };
+        let len = match kind {
+            3 => 8,
+            15 => 4,
+            _ => size,
+        };
+        Ok((value, offset + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Database of one IPv4 tree node: the lower half of the address space
+    /// is in DE, the upper half unknown
+    fn database() -> Vec<u8> {
+        let mut db = vec![0, 0, 17, 0, 0, 1];
+        db.extend_from_slice(&[0; 16]);
+        db.extend_from_slice(b"\xe1\x47country\xe1\x48iso_code\x42DE");
+        db.extend_from_slice(METADATA_MARKER);
+        db.extend_from_slice(b"\xe3\x4anode_count\xc1\x01");
+        db.extend_from_slice(b"\x4brecord_size\xa1\x18\x4aip_version\xa1\x04");
+        db
+    }
+
+    #[test]
+    fn countries() {
+        let db = Database::parse(database()).unwrap();
+        assert_eq!(
+            db.country("10.0.0.1".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(
+            db.country("::ffff:10.0.0.1".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(db.country("192.0.2.1".parse().unwrap()), None);
+        assert_eq!(db.country("2001:db8::1".parse().unwrap()), None);
+        assert!(Database::parse(b"no metadata".to_vec()).is_err());
+    }
+
+    #[test]
+    fn country_lists() {
+        let (de, us) = (vec!["DE".to_string()], vec!["US".to_string()]);
+        assert!(allowed(Some("DE"), &de, &[]));
+        assert!(!allowed(Some("US"), &de, &[]));
+        assert!(!allowed(None, &de, &[]));
+        assert!(!allowed(Some("US"), &[], &us));
+        assert!(allowed(None, &[], &us));
+        assert!(allowed(Some("DE"), &[], &[]));
+    }
+
+    #[test]
+    fn data_types() {
+        let mut data = b"\x20\x02\x44abcd\x02\x04\xa1\x07\x01\x07\x5d\x00".to_vec();
+        data.extend_from_slice(&[b'x'; 29]);
+        let decoder = Decoder { data: &data };
+        // A pointer to the string after it
+        assert_eq!(decoder.decode(0).unwrap(), (Value::from("abcd"), 2));
+        assert_eq!(
+            decoder.decode(7).unwrap(),
+            (Value::from(vec![Value::from(7), Value::from(true)]), 13)
+        );
+        let (long, _) = decoder.decode(13).unwrap();
+        assert_eq!(long.as_str().unwrap().len(), 29);
+    }
+}
@@ -0,0 +1,589 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{Result, anyhow};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Method, Response, StatusCode, header};
+use serde_json::{Value, json};
+use tokio::sync::mpsc;
+use tracing::warn;
+use url::Url;
+
+use crate::body::{self, Body, BoxError};
+use crate::date::Utc;
+
+/// Headers whose values never end up in a recording
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-amz-security-token",
+];
+/// Query parameters are redacted if their name contains one of these
+const SECRET_PARAMS: &[&str] = &[
+    "token",
+    "secret",
+    "password",
+    "signature",
+    "credential",
+    "key",
+];
+pub const REDACTED: &str = "[redacted]";
+
+/// Exchanges written to a HAR file (`--record`)
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Value>,
+    body_limit: usize,
+}
+
+impl Recorder {
+    /// Start writing to `path`, keeping at most `body_limit` bytes of each
+    /// body
+    pub fn new(path: PathBuf, body_limit: usize) -> Result<Self> {
+        let (file, end) =
+            start(&path).map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("har-writer".to_string())
+            .spawn(move || writer(path, file, end, rx))
+            .map_err(|e| anyhow!("Failed to start the HAR writer: {}", e))?;
+        Ok(Recorder { tx, body_limit })
+    }
+
+    /// Start recording an exchange, keeping the start of the request body
+    pub fn exchange(&self, request_body: Body) -> (Exchange, Body) {
+        let request = Captured::new(self.body_limit);
+        let body = body::boxed(Tee {
+            inner: request_body,
+            captured: request.clone(),
+        });
+        let exchange = Exchange {
+            tx: self.tx.clone(),
+            body_limit: self.body_limit,
+            started: SystemTime::now(),
+            start: Instant::now(),
+            request_body: request,
+            request: Value::Null,
+            response: None,
+        };
+        (exchange, body)
+    }
+}
+
+/// One request, written once its response body is done
+pub struct Exchange {
+    tx: mpsc::UnboundedSender<Value>,
+    body_limit: usize,
+    started: SystemTime,
+    start: Instant,
+    request_body: Captured,
+    request: Value,
+    /// Response without content, and milliseconds until its headers
+    response: Option<(Value, f64)>,
+}
+
+impl Exchange {
+    /// The request as sent upstream
+    pub fn request(&mut self, method: &Method, url: &Url, headers: &HeaderMap) {
+        let url = redact_url(url);
+        let query: Vec<Value> = url
+            .query_pairs()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        self.request = json!({
+            "method": method.as_str(),
+            "url": url.as_str(),
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": header_list(headers),
+            "queryString": query,
+            "headersSize": -1,
+        });
+    }
+
+    /// The response as received from upstream
+    pub fn response(&mut self, parts: &Parts) {
+        let location = parts
+            .headers
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let response = json!({
+            "status": parts.status.as_u16(),
+            "statusText": parts.status.canonical_reason().unwrap_or(""),
+            "httpVersion": format!("{:?}", parts.version),
+            "cookies": [],
+            "headers": header_list(&parts.headers),
+            "redirectURL": location,
+            "headersSize": -1,
+        });
+        self.response = Some((response, millis(self.start)));
+    }
+
+    /// Record `body` as it is read, the entry is written when it is dropped
+    pub fn finish(self, body: Body) -> Body {
+        let captured = Captured::new(self.body_limit);
+        let tee = body::boxed(Tee {
+            inner: body,
+            captured: captured.clone(),
+        });
+        body::with_guard(
+            tee,
+            Pending {
+                exchange: Some(self),
+                response_body: captured,
+            },
+        )
+    }
+
+    fn entry(self, response_body: &Captured) -> Value {
+        let total = millis(self.start);
+        let (mut response, wait) = self.response.unwrap_or_default();
+        let mime = |message: &Value| {
+            message["headers"]
+                .as_array()
+                .and_then(|headers| {
+                    headers.iter().find(|h| {
+                        h["name"]
+                            .as_str()
+                            .is_some_and(|name| name.eq_ignore_ascii_case("content-type"))
+                    })
+                })
+                .and_then(|h| h["value"].as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+
+        let mut request = self.request;
+        let request_body = self.request_body.lock();
+        request["bodySize"] = request_body.size.into();
+        if request_body.size > 0 {
+            let mut post = content(&request_body, mime(&request));
+            post.as_object_mut().unwrap().remove("size");
+            request["postData"] = post;
+        }
+        let response_body = response_body.lock();
+        response["bodySize"] = response_body.size.into();
+        response["content"] = content(&response_body, mime(&response));
+
+        json!({
+            "startedDateTime": Utc::from(self.started).iso8601(),
+            "time": total,
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": {
+                "send": 0,
+                "wait": wait,
+                "receive": (total - wait).max(0.0),
+            },
+        })
+    }
+}
+
+/// Writes the entry of an exchange when the response body is dropped
+struct Pending {
+    exchange: Option<Exchange>,
+    response_body: Captured,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(exchange) = self.exchange.take() {
+            let tx = exchange.tx.clone();
+            let _ = tx.send(exchange.entry(&self.response_body));
+        }
+    }
+}
+
+/// Start of a body and its full size
+#[derive(Clone)]
+struct Captured(Arc<Mutex<Capture>>);
+
+struct Capture {
+    data: Vec<u8>,
+    size: u64,
+    limit: usize,
+}
+
+impl Captured {
+    fn new(limit: usize) -> Self {
+        Captured(Arc::new(Mutex::new(Capture {
+            data: Vec::new(),
+            size: 0,
+            limit,
+        })))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Capture> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Copies the data of a body into a [`Captured`] while passing it on
+struct Tee {
+    inner: Body,
+    captured: Captured,
+}
+
+impl HttpBody for Tee {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            let mut capture = self.captured.lock();
+            capture.size += data.len() as u64;
+            let room = capture.limit.saturating_sub(capture.data.len());
+            capture
+                .data
+                .extend_from_slice(&data[..data.len().min(room)]);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// HAR content of a captured body, base64 unless it is text
+fn content(capture: &Capture, mime: String) -> Value {
+    let mut content = json!({ "size": capture.size, "mimeType": mime });
+    match std::str::from_utf8(&capture.data) {
+        Ok(text) => content["text"] = text.into(),
+        Err(_) => {
+            content["text"] = base64(&capture.data).into();
+            content["encoding"] = "base64".into();
+        }
+    }
+    if capture.data.len() as u64 != capture.size {
+        content["comment"] = format!("truncated to {} bytes", capture.data.len()).into();
+    }
+    content
+}
+
+/// `{name, value}` pairs with secret values redacted
+pub fn header_list(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_secret(name, value) {
+                REDACTED.into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes())
+            };
+            json!({ "name": name.as_str(), "value": value })
+        })
+        .collect()
+}
+
+/// Whether a header value must not be recorded or logged
+pub fn is_secret(name: &HeaderName, value: &HeaderValue) -> bool {
+    value.is_sensitive() || SECRET_HEADERS.contains(&name.as_str())
+}
+
+/// `url` with the values of secret looking query parameters replaced
+pub fn redact_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    let secret = |name: &str| {
+        let name = name.to_ascii_lowercase();
+        SECRET_PARAMS.iter().any(|part| name.contains(part))
+    };
+    if url.query_pairs().any(|(name, _)| secret(&name)) {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if secret(&name) {
+                    REDACTED.into()
+                } else {
+                    value
+                };
+                (name.into_owned(), value.into_owned())
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url
+}
+
+/// Responses served from a HAR file instead of upstreams (`--replay`)
+pub struct Replay {
+    /// Responses by method and redacted URL, served in turn
+    responses: HashMap<(String, String), (Vec<Recorded>, AtomicUsize)>,
+}
+
+struct Recorded {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let har: Value = serde_json::from_slice(&text)
+            .map_err(|e| anyhow!("Invalid HAR {}: {}", path.display(), e))?;
+        let entries = har["log"]["entries"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Invalid HAR {}: no entries", path.display()))?;
+        let mut responses: HashMap<_, (Vec<Recorded>, AtomicUsize)> = HashMap::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let (key, recorded) = recorded(entry)
+                .ok_or_else(|| anyhow!("Invalid HAR {}: entry {}", path.display(), i))?;
+            responses.entry(key).or_default().0.push(recorded);
+        }
+        Ok(Replay { responses })
+    }
+
+    /// Next recorded response to `method` `url`, repeating the last one
+    pub fn response(&self, method: &Method, url: &Url) -> Option<Response<Body>> {
+        let key = (method.to_string(), redact_url(url).to_string());
+        let (responses, next) = self.responses.get(&key)?;
+        let i = next
+            .fetch_add(1, Ordering::Relaxed)
+            .min(responses.len() - 1);
+        let recorded = &responses[i];
+        let mut response = Response::new(body::full(recorded.body.clone()));
+        *response.status_mut() = recorded.status;
+        *response.headers_mut() = recorded.headers.clone();
+        Some(response)
+    }
+}
+
+/// Key and response of a HAR entry
+fn recorded(entry: &Value) -> Option<((String, String), Recorded)> {
+    let request = &entry["request"];
+    let key = (
+        request["method"].as_str()?.to_string(),
+        Url::parse(request["url"].as_str()?).ok()?.to_string(),
+    );
+    let response = &entry["response"];
+    let status = StatusCode::from_u16(response["status"].as_u64()?.try_into().ok()?).ok()?;
+    let mut headers = HeaderMap::new();
+    for pair in response["headers"].as_array()? {
+        let (name, value) = (pair["name"].as_str()?, pair["value"].as_str()?);
+        // The body may be truncated, its length is set from what was kept
+        if value == REDACTED || name.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    let content = &response["content"];
+    let text = content["text"].as_str().unwrap_or("");
+    let body = match content["encoding"].as_str() {
+        Some("base64") => Bytes::from(base64_decode(text)?),
+        _ => Bytes::from(text.to_string()),
+    };
+    Some((
+        key,
+        Recorded {
+            status,
+            headers,
+            body,
+        },
+    ))
+}
+
+fn millis(start: Instant) -> f64 {
+    (start.elapsed().as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0
+}
+
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut n, mut bits) = (0u32, 0);
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        n = n << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Closes the entries array and the document after the last entry
+const HAR_END: &[u8] = b"\n]}}\n";
+
+/// Write an empty HAR document, returning the file and the offset of its end
+fn start(path: &Path) -> std::io::Result<(File, u64)> {
+    let creator = json!({ "name": "m2proxy", "version": env!("CARGO_PKG_VERSION") });
+    let head = format!(
+        r#"{{"log":{{"version":"1.2","creator":{},"entries":["#,
+        creator
+    );
+    let mut file = File::create(path)?;
+    file.write_all(head.as_bytes())?;
+    file.write_all(HAR_END)?;
+    Ok((file, head.len() as u64))
+}
+
+/// Append each batch of entries over the end of the document and write the
+/// end again after them, so the file stays a complete HAR document without
+/// keeping the entries in memory
+fn writer(path: PathBuf, mut file: File, mut end: u64, mut rx: mpsc::UnboundedReceiver<Value>) {
+    let mut empty = true;
+    while let Some(entry) = rx.blocking_recv() {
+        let mut batch = Vec::new();
+        for entry in std::iter::once(entry).chain(std::iter::from_fn(|| rx.try_recv().ok())) {
+            batch.extend_from_slice(if empty && batch.is_empty() {
+                b"\n"
+            } else {
+                b",\n"
+            });
+            let _ = serde_json::to_writer(&mut batch, &entry);
+        }
+        let len = batch.len() as u64;
+        batch.extend_from_slice(HAR_END);
+        match file
+            .seek(SeekFrom::Start(end))
+            .and_then(|_| file.write_all(&batch))
+        {
+            Ok(()) => {
+                end += len;
+                empty = false;
+            }
+            Err(e) => warn!("Failed to write {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redaction() {
+        let url =
+            Url::parse("https://example.com/a?page=2&access_token=abc&X-Amz-Signature=d").unwrap();
+        assert_eq!(
+            redact_url(&url).as_str(),
+            "https://example.com/a?page=2&access_token=%5Bredacted%5D&X-Amz-Signature=%5Bredacted%5D"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("accept", "*/*".parse().unwrap());
+        headers.insert("authorization", "Bearer abc".parse().unwrap());
+        let mut token = HeaderValue::from_static("secret");
+        token.set_sensitive(true);
+        headers.insert("x-custom-auth", token);
+        assert_eq!(
+            header_list(&headers),
+            vec![
+                json!({ "name": "accept", "value": "*/*" }),
+                json!({ "name": "authorization", "value": REDACTED }),
+                json!({ "name": "x-custom-auth", "value": REDACTED }),
+            ]
+        );
+    }
+
+    #[test]
+    fn contents() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        for data in [&b""[..], b"f", b"fo", b"foo", &[0xff, 0x00, 0x80, 0x7f]] {
+            assert_eq!(base64_decode(&base64(data)).unwrap(), data);
+        }
+        assert_eq!(base64_decode("not base64!"), None);
+
+        let capture = Capture {
+            data: vec![0xff, 0x00],
+            size: 10,
+            limit: 2,
+        };
+        assert_eq!(
+            content(&capture, "image/png".to_string()),
+            json!({
+                "size": 10,
+                "mimeType": "image/png",
+                "text": "/wA=",
+                "encoding": "base64",
+                "comment": "truncated to 2 bytes",
+            })
+        );
+    }
+
+    #[test]
+    fn appended_entries() {
+        let path = std::env::temp_dir().join(format!("m2proxy-har-{}.har", std::process::id()));
+        let read = || -> Value { serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap() };
+        let (file, end) = start(&path).unwrap();
+        assert_eq!(read()["log"]["entries"], json!([]));
+        assert_eq!(read()["log"]["creator"]["name"], "m2proxy");
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let written = {
+            let path = path.clone();
+            std::thread::spawn(move || writer(path, file, end, rx))
+        };
+        // One batch, then another appended after it
+        tx.send(json!({ "n": 0 })).unwrap();
+        while read()["log"]["entries"] == json!([]) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        tx.send(json!({ "n": 1 })).unwrap();
+        tx.send(json!({ "n": 2 })).unwrap();
+        drop(tx);
+        written.join().unwrap();
+        let entries = read()["log"]["entries"].clone();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries, json!([{ "n": 0 }, { "n": 1 }, { "n": 2 }]));
+    }
+}
@@ -0,0 +1,26 @@
+/// Whether `host` is matched by `pattern`, a lowercase host or `*.domain`
+/// for its subdomains (not `domain` itself)
+pub fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("example.com", "example.com"));
+        assert!(!matches("example.com", "api.example.com"));
+        assert!(matches("*.example.com", "api.example.com"));
+        assert!(matches("*.example.com", "a.b.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", ".example.com"));
+        assert!(!matches("*.example.com", "badexample.com"));
+    }
+}
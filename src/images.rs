@@ -0,0 +1,178 @@
+use std::io::Cursor;
+
+use futures_util::stream;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Body as HttpBody, Bytes, Frame};
+use hyper::header;
+use hyper::http::{request, response};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use tokio::sync::oneshot;
+use url::form_urlencoded;
+
+use crate::body::{self, Body, BoxError};
+use crate::filter::{self, Filter};
+
+/// Query parameters consumed by the proxy instead of being forwarded
+pub const PARAMS: [&str; 2] = ["w", "q"];
+
+/// Content types the optimizer can decode and re-encode
+pub const TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+/// Largest image buffered for optimization, bigger ones pass through
+const MAX_SIZE: u64 = 32 * 1024 * 1024;
+
+/// JPEG quality used when only a width is requested
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Requested transformation, from `?w=WIDTH&q=QUALITY` on the proxy URL
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    width: Option<u32>,
+    quality: Option<u8>,
+}
+
+impl Options {
+    /// Parse the image parameters, `None` if the query has none
+    pub fn from_query(query: Option<&str>) -> Option<Self> {
+        let mut options = Options::default();
+        for (key, value) in form_urlencoded::parse(query?.as_bytes()) {
+            match &*key {
+                "w" => options.width = value.parse().ok().filter(|w| *w > 0),
+                "q" => options.quality = value.parse().ok().filter(|q| (1..=100).contains(q)),
+                _ => {}
+            }
+        }
+        (options != Options::default()).then_some(options)
+    }
+}
+
+/// Resize and recompress images on the fly (`--image-optimize`)
+pub struct ImageFilter;
+
+impl Filter for ImageFilter {
+    fn apply(&self, request: &request::Parts, parts: &mut response::Parts, body: Body) -> Body {
+        let Some(options) = Options::from_query(request.uri.query()) else {
+            return body;
+        };
+        let format = match parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(filter::mime)
+            .as_deref()
+        {
+            Some("image/jpeg") => ImageFormat::Jpeg,
+            Some("image/png") => ImageFormat::Png,
+            Some("image/webp") => ImageFormat::WebP,
+            _ => return body,
+        };
+        if body.size_hint().lower() > MAX_SIZE {
+            return body;
+        }
+
+        // Decoding and encoding are CPU bound, keep them off the runtime
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let result = transform(body, format, options).await;
+            let _ = tx.send(result);
+        });
+        body::boxed(StreamBody::new(stream::once(async move {
+            match rx.await {
+                Ok(result) => result.map(Frame::data),
+                Err(e) => Err(BoxError::from(e)),
+            }
+        })))
+    }
+}
+
+/// Buffer the whole image and optimize it on the blocking pool
+async fn transform(
+    mut body: Body,
+    format: ImageFormat,
+    options: Options,
+) -> Result<Bytes, BoxError> {
+    let mut data = Vec::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(chunk) = frame?.into_data() {
+            if (data.len() + chunk.len()) as u64 > MAX_SIZE {
+                return Err("image too large to optimize".into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+    let data = Bytes::from(data);
+    let out = tokio::task::spawn_blocking(move || optimize(&data, format, options).unwrap_or(data));
+    Ok(out.await?)
+}
+
+/// Re-encode an image, `None` when the result would not be an improvement
+fn optimize(data: &[u8], format: ImageFormat, options: Options) -> Option<Bytes> {
+    let mut image = image::load_from_memory_with_format(data, format).ok()?;
+    let resized = match options.width {
+        // Never upscale; the height follows the aspect ratio
+        Some(width) if width < image.width() => {
+            image = image.resize(width, u32::MAX, FilterType::Triangle);
+            true
+        }
+        _ => false,
+    };
+
+    let mut out = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+            let encoder = JpegEncoder::new_with_quality(&mut out, quality);
+            DynamicImage::from(image.to_rgb8())
+                .write_with_encoder(encoder)
+                .ok()?;
+        }
+        _ => image.write_to(&mut out, format).ok()?,
+    }
+    let out = out.into_inner();
+    (resized || out.len() < data.len()).then(|| Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_from_query() {
+        assert_eq!(Options::from_query(None), None);
+        assert_eq!(Options::from_query(Some("page=2")), None);
+        assert_eq!(Options::from_query(Some("w=0&q=500")), None);
+        assert_eq!(
+            Options::from_query(Some("w=800&q=75&page=2")),
+            Some(Options {
+                width: Some(800),
+                quality: Some(75),
+            })
+        );
+    }
+
+    #[test]
+    fn resize_keeps_aspect_ratio() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(400, 200)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let options = Options {
+            width: Some(100),
+            quality: None,
+        };
+        let out = optimize(&png, ImageFormat::Png, options).unwrap();
+        let image = image::load_from_memory_with_format(&out, ImageFormat::Png).unwrap();
+        assert_eq!((image.width(), image.height()), (100, 50));
+
+        // Images are never upscaled
+        let options = Options {
+            width: Some(800),
+            quality: None,
+        };
+        assert_eq!(optimize(&png, ImageFormat::Png, options), None);
+    }
+}
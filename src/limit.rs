@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Per-client-IP cap on concurrent inbound connections
+#[derive(Clone)]
+pub struct ClientLimiter {
+    max: usize,
+    clients: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimiter {
+    /// `max == 0` disables the limit
+    pub fn new(max: usize) -> Self {
+        ClientLimiter {
+            max,
+            clients: Arc::default(),
+        }
+    }
+
+    /// Reserve a connection slot for `ip`, or `None` if it already has `max` open
+    pub fn acquire(&self, ip: IpAddr) -> Option<ClientGuard> {
+        // IPv4 clients of a dual-stack listener count as themselves
+        let ip = ip.to_canonical();
+        if self.max == 0 {
+            return Some(ClientGuard { limiter: None, ip });
+        }
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        let count = clients.entry(ip).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ClientGuard {
+            limiter: Some(self.clone()),
+            ip,
+        })
+    }
+}
+
+/// Releases the client's connection slot on drop
+pub struct ClientGuard {
+    limiter: Option<ClientLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            let mut clients = limiter.clients.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(count) = clients.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    clients.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_limits() {
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let limiter = ClientLimiter::new(2);
+        let first = limiter.acquire(a).unwrap();
+        let second = limiter.acquire(a).unwrap();
+        assert!(limiter.acquire(a).is_none());
+        // Other clients have their own count
+        let other = limiter.acquire(b).unwrap();
+        drop(first);
+        let third = limiter.acquire(a).unwrap();
+        assert!(limiter.acquire(a).is_none());
+        assert!(
+            limiter
+                .acquire("::ffff:192.0.2.1".parse().unwrap())
+                .is_none()
+        );
+        drop((second, third, other));
+        assert!(limiter.clients.lock().unwrap().is_empty());
+
+        let unlimited = ClientLimiter::new(0);
+        let guards: Vec<_> = (0..10).map(|_| unlimited.acquire(a).unwrap()).collect();
+        assert_eq!(guards.len(), 10);
+        assert!(unlimited.clients.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Response, StatusCode};
+use serde_json::json;
+
+use crate::ban::Bans;
+use crate::body::{self, Body};
+use crate::errors::ErrorPages;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::target::Targets;
+
+/// Default prefix of the local endpoints, which are never proxied
+pub const DEFAULT_PREFIX: &str = "/__m2proxy";
+
+const FAVICON: &str = "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 16 16\">\
+    <rect width=\"16\" height=\"16\" rx=\"3\" fill=\"#2b6cb0\"/>\
+    <path d=\"M4 8h6M8 5l3 3-3 3\" stroke=\"#fff\" stroke-width=\"1.5\" fill=\"none\"/></svg>";
+
+/// Serve a request below the local prefix; `path` has the prefix removed
+pub fn handle(
+    method: &Method,
+    path: &str,
+    metrics: &Metrics,
+    targets: &Targets,
+    errors: &ErrorPages,
+    request_id: &str,
+) -> Response<Body> {
+    if method != Method::GET && method != Method::HEAD {
+        let mut response = errors.render(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+            request_id,
+        );
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return response;
+    }
+    let (content_type, content) = match path {
+        "/health" => ("text/plain; charset=utf-8", "ok\n".to_string()),
+        "/metrics" => ("text/plain; version=0.0.4", metrics.render()),
+        "/stats" => ("application/json", targets.stats().to_string()),
+        "/version" => ("application/json", crate::version::info().to_string()),
+        "/favicon.svg" => ("image/svg+xml", FAVICON.to_string()),
+        _ => return errors.render(StatusCode::NOT_FOUND, "Not found", request_id),
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body::full(content))
+        .unwrap()
+}
+
+/// Request the health endpoint of the proxy at `addr`, failing unless it
+/// answers 200 within `timeout` (`m2proxy ping`)
+pub fn ping(addr: &str, prefix: &str, timeout: Duration) -> Result<()> {
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| anyhow!("Invalid address {}: {}", addr, e))?
+        .collect();
+    let mut stream = addrs
+        .iter()
+        .find_map(|addr| TcpStream::connect_timeout(addr, timeout).ok())
+        .ok_or_else(|| anyhow!("Failed to connect to {}", addr))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {}/health HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        prefix, addr
+    )?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        Some(code) => Err(anyhow!("Health check of {} returned {}", addr, code)),
+        None => Err(anyhow!("Health check of {} got no response", addr)),
+    }
+}
+
+/// Open client connections and their bytes so far, for trusted clients
+/// only since it lists their addresses
+pub fn connections(
+    method: &Method,
+    trusted: bool,
+    metrics: &Metrics,
+    errors: &ErrorPages,
+    request_id: &str,
+) -> Response<Body> {
+    if method != Method::GET && method != Method::HEAD {
+        let mut response = errors.render(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+            request_id,
+        );
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return response;
+    }
+    if !trusted {
+        return errors.render(StatusCode::FORBIDDEN, "Forbidden", request_id);
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body::full(metrics.connections().to_string()))
+        .unwrap()
+}
+
+/// `POST /log-level?host=HOST&level=LEVEL` from a trusted client sets the
+/// log level of requests to a target host, without `level` it is reset
+pub fn log_level(
+    method: &Method,
+    query: Option<&str>,
+    trusted: bool,
+    errors: &ErrorPages,
+    request_id: &str,
+) -> Response<Body> {
+    if method != Method::POST {
+        let mut response = errors.render(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+            request_id,
+        );
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("POST"));
+        return response;
+    }
+    if !trusted {
+        return errors.render(StatusCode::FORBIDDEN, "Forbidden", request_id);
+    }
+    let (mut host, mut level) = (None, None);
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        match key.as_ref() {
+            "host" => host = Some(value.into_owned()),
+            "level" => level = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let Some(host) = host else {
+        return errors.render(StatusCode::BAD_REQUEST, "Missing host", request_id);
+    };
+    if let Err(e) = logging::set_host_level(&host, level.as_deref()) {
+        return errors.render(StatusCode::BAD_REQUEST, &e.to_string(), request_id);
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body::full("ok\n"))
+        .unwrap()
+}
+
+/// `GET /bans` lists banned clients, `POST /bans?ip=IP&seconds=N` bans one
+/// (for the `--ban-time` without `seconds`) and `DELETE /bans?ip=IP` lifts
+/// a ban; trusted clients only
+pub fn bans(
+    method: &Method,
+    query: Option<&str>,
+    trusted: bool,
+    bans: &Bans,
+    errors: &ErrorPages,
+    request_id: &str,
+) -> Response<Body> {
+    if ![Method::GET, Method::HEAD, Method::POST, Method::DELETE].contains(method) {
+        let mut response = errors.render(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+            request_id,
+        );
+        response.headers_mut().insert(
+            header::ALLOW,
+            HeaderValue::from_static("GET, HEAD, POST, DELETE"),
+        );
+        return response;
+    }
+    if !trusted {
+        return errors.render(StatusCode::FORBIDDEN, "Forbidden", request_id);
+    }
+    if method == Method::GET || method == Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body::full(bans.list().to_string()))
+            .unwrap();
+    }
+    let (mut ip, mut seconds) = (None, None);
+    for (key, value) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        match key.as_ref() {
+            "ip" => ip = Some(value.into_owned()),
+            "seconds" => seconds = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    let Some(ip) = ip else {
+        return errors.render(StatusCode::BAD_REQUEST, "Missing ip", request_id);
+    };
+    let Ok(ip) = ip.parse() else {
+        return errors.render(StatusCode::BAD_REQUEST, "Invalid ip", request_id);
+    };
+    if method == Method::DELETE {
+        if !bans.unban(ip) {
+            return errors.render(StatusCode::NOT_FOUND, "Not banned", request_id);
+        }
+    } else {
+        let duration = match seconds.map(|seconds| seconds.parse()) {
+            Some(Ok(seconds)) => Duration::from_secs(seconds),
+            Some(Err(_)) => {
+                return errors.render(StatusCode::BAD_REQUEST, "Invalid seconds", request_id);
+            }
+            None => bans.duration(),
+        };
+        bans.ban(ip, duration);
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body::full("ok\n"))
+        .unwrap()
+}
+
+/// Default `/robots.txt`, keeping crawlers from mirroring the internet
+pub const ROBOTS_TXT: &str = "User-agent: *\nDisallow: /\n";
+
+/// Serve `/robots.txt`
+pub fn robots_txt(content: &Bytes) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body::full(content.clone()))
+        .unwrap()
+}
+
+/// Built-in usage page served at `/`
+///
+/// `origin` is where clients reach the proxy, e.g. `http://localhost:1234`.
+/// `default_scheme` is used for targets without one, `None` if they are rejected.
+pub fn landing_page(
+    origin: &str,
+    prefix: &str,
+    default_scheme: Option<&str>,
+    json: bool,
+) -> Response<Body> {
+    let example = format!("{}/https://example.com/path", origin);
+    let (content_type, content) = if json {
+        let info = json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "usage": format!("{}/<target URL>", origin),
+            "example": example,
+            "default_scheme": default_scheme,
+        });
+        ("application/json", info.to_string())
+    } else {
+        let page = format!(
+            "<!DOCTYPE html>\n\
+             <html>\n\
+             <head><meta charset=\"utf-8\"><title>m2proxy</title>\
+             <link rel=\"icon\" href=\"{prefix}/favicon.svg\"></head>\n\
+             <body>\n\
+             <h1>m2proxy {version}</h1>\n\
+             <p>Append the target URL to this address to proxy it:</p>\n\
+             <pre>{example}</pre>\n\
+             <p>{scheme}</p>\n\
+             </body>\n\
+             </html>\n",
+            version = env!("CARGO_PKG_VERSION"),
+            example = escape_html(&example),
+            prefix = escape_html(prefix),
+            scheme = match default_scheme {
+                Some(scheme) => format!(
+                    "Targets without a scheme use {}, e.g. <code>{}/example.com</code>.",
+                    scheme,
+                    escape_html(origin)
+                ),
+                None => "Targets must include the scheme.".to_string(),
+            },
+        );
+        ("text/html; charset=utf-8", page)
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body::full(content))
+        .unwrap()
+}
+
+/// Escape text for HTML; the origin comes from the client's Host header
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Result, anyhow};
+use tracing::{Level, Metadata};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Name of the span around each proxied request, with the `host` field set
+/// to the target host
+pub const REQUEST_SPAN: &str = "request";
+
+/// Filter of the global subscriber and the levels raised per host
+struct Filter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    base: String,
+    hosts: Mutex<BTreeMap<String, LevelFilter>>,
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+/// Where log lines go (`--log-output`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// Appended to `--log-file`
+    File,
+    /// The local syslog daemon at `/dev/log`
+    Syslog,
+    /// The native journald protocol
+    Journald,
+}
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = env!("CARGO_PKG_NAME");
+
+/// Install the global subscriber, filtered by `RUST_LOG` (default `info`)
+pub fn init(output: Output, file: Option<&Path>) -> Result<()> {
+    let base = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+    let filter = EnvFilter::try_new(&base).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    let fmt = tracing_subscriber::fmt::layer();
+    match output {
+        Output::Stdout => registry.with(fmt).init(),
+        Output::File => {
+            let path = file.ok_or_else(|| anyhow!("--log-output file needs --log-file"))?;
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+            registry
+                .with(fmt.with_ansi(false).with_writer(Mutex::new(file)))
+                .init();
+        }
+        Output::Syslog | Output::Journald => {
+            let path = match output {
+                Output::Syslog => SYSLOG_SOCKET,
+                _ => JOURNALD_SOCKET,
+            };
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(path)
+                .map_err(|e| anyhow!("Failed to connect to {}: {}", path, e))?;
+            let writer = Datagrams {
+                socket,
+                journald: output == Output::Journald,
+            };
+            // The daemon adds time and level
+            registry
+                .with(
+                    fmt.with_ansi(false)
+                        .without_time()
+                        .with_level(false)
+                        .with_writer(writer),
+                )
+                .init();
+        }
+    }
+    let _ = FILTER.set(Filter {
+        handle,
+        base,
+        hosts: Mutex::new(BTreeMap::new()),
+    });
+    Ok(())
+}
+
+/// Sends each log line as one datagram to syslog or journald
+struct Datagrams {
+    socket: UnixDatagram,
+    journald: bool,
+}
+
+struct Datagram<'a> {
+    sink: &'a Datagrams,
+    level: Level,
+}
+
+impl<'a> MakeWriter<'a> for Datagrams {
+    type Writer = Datagram<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Datagram {
+            sink: self,
+            level: Level::INFO,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Datagram {
+            sink: self,
+            level: *meta.level(),
+        }
+    }
+}
+
+impl Write for Datagram<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let message = String::from_utf8_lossy(buf);
+        let message = message.trim_end_matches('\n');
+        let datagram = if self.sink.journald {
+            journald_datagram(self.level, message)
+        } else {
+            syslog_datagram(self.level, message)
+        };
+        // Losing a line beats blocking or failing the request
+        let _ = self.sink.socket.send(&datagram);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Syslog severity of a level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// `<PRI>m2proxy[PID]: message`, with the daemon facility
+fn syslog_datagram(level: Level, message: &str) -> Vec<u8> {
+    const DAEMON: u8 = 3;
+    format!(
+        "<{}>{}[{}]: {}",
+        DAEMON * 8 + severity(level),
+        IDENTIFIER,
+        std::process::id(),
+        message
+    )
+    .into_bytes()
+}
+
+/// Fields of the journald native protocol, multi-line messages length
+/// prefixed
+fn journald_datagram(level: Level, message: &str) -> Vec<u8> {
+    let mut datagram = format!(
+        "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nSYSLOG_PID={}\n",
+        severity(level),
+        IDENTIFIER,
+        std::process::id()
+    )
+    .into_bytes();
+    if message.contains('\n') {
+        datagram.extend_from_slice(b"MESSAGE\n");
+        datagram.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        datagram.extend_from_slice(message.as_bytes());
+        datagram.push(b'\n');
+    } else {
+        datagram.extend_from_slice(format!("MESSAGE={}\n", message).as_bytes());
+    }
+    datagram
+}
+
+/// Log requests to `host` at `level`, or as everything else for `None`
+pub fn set_host_level(host: &str, level: Option<&str>) -> Result<()> {
+    let filter = FILTER
+        .get()
+        .ok_or_else(|| anyhow!("Log levels cannot be changed"))?;
+    let host = host.to_ascii_lowercase();
+    if host.is_empty()
+        || !host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b))
+    {
+        return Err(anyhow!("Invalid host {}", host));
+    }
+    let mut hosts = filter.hosts.lock().unwrap_or_else(|e| e.into_inner());
+    match level {
+        Some(level) => {
+            let level = level
+                .parse()
+                .map_err(|_| anyhow!("Invalid level {}", level))?;
+            hosts.insert(host, level);
+        }
+        None => {
+            hosts.remove(&host);
+        }
+    }
+    let directives = directives(&filter.base, &hosts);
+    let env_filter = EnvFilter::try_new(&directives)?;
+    filter.handle.reload(env_filter)?;
+    Ok(())
+}
+
+/// `base` with a span directive for each host
+fn directives(base: &str, hosts: &BTreeMap<String, LevelFilter>) -> String {
+    let mut directives = base.to_string();
+    for (host, level) in hosts {
+        directives += &format!(",[{}{{host={}}}]={}", REQUEST_SPAN, host, level);
+    }
+    directives
+}
+
+/// Lets 1 in `every` lines through (`--log-sample`)
+pub struct Sampler {
+    every: u64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(every: u64) -> Self {
+        Sampler {
+            every: every.max(1),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sample(&self) -> bool {
+        self.count
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every)
+    }
+}
+
+/// Log line of a request, written once its response body was sent or
+/// dropped so that it has the body bytes of both directions
+pub struct RequestLog {
+    /// Method, URI and status
+    pub line: String,
+    pub received: Arc<AtomicU64>,
+    pub sent: Arc<AtomicU64>,
+    pub span: tracing::Span,
+}
+
+impl Drop for RequestLog {
+    fn drop(&mut self) {
+        let _entered = self.span.enter();
+        tracing::debug!(
+            "{}, {} bytes received, {} bytes sent",
+            self.line,
+            self.received.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::Layer;
+
+    /// Counts the events that pass the filter
+    struct Count(Arc<AtomicU64>);
+
+    impl<S: tracing::Subscriber> Layer<S> for Count {
+        fn on_event(&self, _: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn host_levels() {
+        let hosts = BTreeMap::from([("example.com".to_string(), LevelFilter::DEBUG)]);
+        let directives = directives("info", &hosts);
+        assert_eq!(directives, "info,[request{host=example.com}]=debug");
+
+        let count = Arc::new(AtomicU64::new(0));
+        let subscriber = Registry::default()
+            .with(EnvFilter::try_new(&directives).unwrap())
+            .with(Count(count.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            for host in ["example.com", "other.com"] {
+                let span = tracing::trace_span!(REQUEST_SPAN, host = host);
+                let _entered = span.enter();
+
+                tracing::debug!("detail");
+            }
+        });
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn daemon_datagrams() {
+        let pid = std::process::id();
+        assert_eq!(
+            syslog_datagram(Level::WARN, "slow upstream"),
+            format!("<28>m2proxy[{}]: slow upstream", pid).into_bytes()
+        );
+        assert_eq!(
+            journald_datagram(Level::INFO, "started"),
+            format!(
+                "PRIORITY=6\nSYSLOG_IDENTIFIER=m2proxy\nSYSLOG_PID={}\nMESSAGE=started\n",
+                pid
+            )
+            .into_bytes()
+        );
+        let multi = journald_datagram(Level::ERROR, "a\nb");
+        let tail: &[u8] = b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n";
+        assert!(multi.starts_with(b"PRIORITY=3\n"));
+        assert!(multi.ends_with(tail));
+    }
+
+    /// Keeps the messages of the events
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for Messages {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Message(String);
+            impl tracing::field::Visit for Message {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+    }
+
+    #[test]
+    fn request_lines() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Registry::default()
+            .with(LevelFilter::DEBUG)
+            .with(Messages(messages.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let log = RequestLog {
+                line: "GET /a -> 200 OK".to_string(),
+                received: Arc::new(AtomicU64::new(3)),
+                sent: Arc::default(),
+                span: tracing::Span::none(),
+            };
+            log.sent.fetch_add(5, Ordering::Relaxed);
+            assert!(messages.lock().unwrap().is_empty());
+            drop(log);
+        });
+        assert_eq!(
+            *messages.lock().unwrap(),
+            ["GET /a -> 200 OK, 3 bytes received, 5 bytes sent"]
+        );
+    }
+
+    #[test]
+    fn sampling() {
+        let sampler = Sampler::new(3);
+        let sampled: Vec<bool> = (0..6).map(|_| sampler.sample()).collect();
+        assert_eq!(sampled, [true, false, false, true, false, false]);
+    }
+}
@@ -1,23 +1,82 @@
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use clap::Parser;
-use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
+use anyhow::{Result, anyhow};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use futures_util::FutureExt;
+use hyper::body::{Body as HttpBody, Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response, StatusCode, Uri, body::Incoming};
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
-use tracing::{error, info};
-use tracing_subscriber;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{Instrument, error, info, warn};
 use url::Url;
 
+use crate::body::{Body, BoxError};
+
+mod audit;
+mod ban;
+mod body;
+mod cidr;
+mod coalesce;
+mod config;
+mod connect;
+mod credentials;
+mod date;
+mod dns;
+mod dump;
+mod encoding;
+mod errors;
+mod filter;
+mod geoip;
+mod har;
+mod host;
+mod images;
+mod limit;
+mod local;
+mod logging;
+mod man;
+mod metrics;
+mod oauth;
+mod origin;
+mod pin;
+mod pool;
+mod progress;
+mod redirect;
+mod resume;
+mod retry;
+mod rewrite;
+mod route;
+mod scan;
+mod segment;
+mod sigv4;
+mod sniff;
+mod statsd;
+mod target;
+mod upstream;
+mod user_agent;
+mod version;
+mod warm;
+
+/// Request header with which trusted clients override `--upstream-timeout`
+const TIMEOUT_HEADER: &str = "x-proxy-timeout";
+/// Request header with which trusted clients get the outbound request as
+/// JSON instead of sending it
+const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+type HttpClient = connect::Client;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-#[command(disable_help_flag = true)]
+#[command(disable_help_flag = true, disable_version_flag = true)]
 struct Args {
     /// Host to bind to
     #[arg(short = 'h', long = "host", default_value = "0.0.0.0")]
@@ -27,164 +86,1814 @@ struct Args {
     #[arg(short = 'p', long = "port", default_value_t = 1234)]
     port: u16,
 
+    /// DNS server for upstream resolution, IP or IP:PORT (repeatable)
+    #[arg(long = "dns-server", value_name = "ADDR")]
+    dns_server: Vec<String>,
+
+    /// DNS-over-HTTPS endpoint for upstream resolution
+    #[arg(long = "doh", value_name = "URL", conflicts_with = "dns_server")]
+    doh: Option<String>,
+
+    /// Seconds a resolved address may still be used when resolving the
+    /// host again fails, 0 to fail right away
+    #[arg(long = "dns-stale-max-age", value_name = "SECS", default_value_t = 0)]
+    dns_stale_max_age: u64,
+
+    /// Pin HOST:PORT to a fixed address, HOST:PORT:ADDR (repeatable, or
+    /// `resolve` in the config file)
+    #[arg(long = "resolve", value_name = "HOST:PORT:ADDR")]
+    resolve: Vec<String>,
+
+    /// Prefer IPv6 addresses when connecting to upstreams
+    #[arg(long = "prefer-ipv6", conflicts_with = "ipv4_only")]
+    prefer_ipv6: bool,
+
+    /// Only connect to upstreams over IPv4
+    #[arg(long = "ipv4-only")]
+    ipv4_only: bool,
+
+    /// Delay in milliseconds before racing the fallback address family (Happy Eyeballs)
+    #[arg(
+        long = "happy-eyeballs-timeout",
+        value_name = "MS",
+        default_value_t = 300
+    )]
+    happy_eyeballs_timeout: u64,
+
+    /// Oldest TLS version accepted from upstreams
+    #[arg(long = "tls-min-version", value_name = "VERSION", value_enum)]
+    tls_min_version: Option<TlsVersion>,
+
+    /// Newest TLS version offered to upstreams
+    #[arg(long = "tls-max-version", value_name = "VERSION", value_enum)]
+    tls_max_version: Option<TlsVersion>,
+
+    /// Local address for outbound connections, one IPv4 and/or one IPv6 (repeatable)
+    #[arg(long = "outbound-bind", value_name = "ADDR")]
+    outbound_bind: Vec<IpAddr>,
+
+    /// Network interface for outbound connections (SO_BINDTODEVICE)
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    #[arg(long = "outbound-interface", value_name = "NAME")]
+    outbound_interface: Option<String>,
+
+    /// Maximum simultaneous connections per upstream host, 0 for unlimited
+    #[arg(long = "max-conns-per-host", value_name = "N", default_value_t = 0)]
+    max_conns_per_host: usize,
+
+    /// Milliseconds to queue for a free upstream connection before answering 503
+    #[arg(long = "max-conns-wait", value_name = "MS", default_value_t = 0)]
+    max_conns_wait: u64,
+
+    /// Seconds an idle pooled upstream connection is kept, 0 to never expire
+    #[arg(
+        long = "pool-idle-timeout",
+        visible_alias = "conn-max-idle",
+        value_name = "SECS",
+        default_value_t = 90
+    )]
+    pool_idle_timeout: u64,
+
+    /// Seconds after which an upstream connection is no longer reused, 0
+    /// for no limit
+    #[arg(long = "conn-max-lifetime", value_name = "SECS", default_value_t = 0)]
+    conn_max_lifetime: u64,
+
+    /// Maximum idle pooled connections per upstream host, 0 disables reuse
+    #[arg(long = "pool-max-idle-per-host", value_name = "N")]
+    pool_max_idle_per_host: Option<usize>,
+
+    /// Close client connections after each response instead of keeping them alive
+    #[arg(long = "no-keep-alive")]
+    no_keep_alive: bool,
+
+    /// Set TCP_NODELAY on client and upstream sockets
+    #[arg(long = "tcp-nodelay")]
+    tcp_nodelay: bool,
+
+    /// Idle seconds before TCP keepalive probes are sent (SO_KEEPALIVE)
+    #[arg(long = "tcp-keepalive", value_name = "SECS")]
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds between TCP keepalive probes
+    #[arg(
+        long = "tcp-keepalive-interval",
+        value_name = "SECS",
+        requires = "tcp_keepalive"
+    )]
+    tcp_keepalive_interval: Option<u64>,
+
+    /// Socket send buffer size in bytes (SO_SNDBUF)
+    #[arg(long = "send-buffer-size", value_name = "BYTES")]
+    send_buffer_size: Option<usize>,
+
+    /// Socket receive buffer size in bytes (SO_RCVBUF)
+    #[arg(long = "recv-buffer-size", value_name = "BYTES")]
+    recv_buffer_size: Option<usize>,
+
+    /// Maximum number of request headers accepted from clients
+    #[arg(long = "max-headers", value_name = "N", default_value_t = 100)]
+    max_headers: usize,
+
+    /// Maximum bytes buffered while reading a request head (at least 8192)
+    #[arg(long = "max-buf-size", value_name = "BYTES", value_parser = clap::value_parser!(u64).range(8192..))]
+    max_buf_size: Option<u64>,
+
+    /// Seconds a client may take to send the request headers, 0 to disable
+    #[arg(
+        long = "header-read-timeout",
+        value_name = "SECS",
+        default_value_t = 30
+    )]
+    header_read_timeout: u64,
+
+    /// Seconds a client may take to send the whole request body, 0 to disable
+    #[arg(long = "body-read-timeout", value_name = "SECS", default_value_t = 0)]
+    body_read_timeout: u64,
+
+    /// Maximum concurrent connections per client IP, 0 for unlimited
+    #[arg(long = "max-conns-per-ip", value_name = "N", default_value_t = 0)]
+    max_conns_per_ip: usize,
+
+    /// Compress identity responses with brotli, zstd or gzip when the client accepts it
+    #[arg(long = "compress")]
+    compress: bool,
+
+    /// Minimum response size in bytes for --compress
+    #[arg(
+        long = "compress-min-size",
+        value_name = "BYTES",
+        default_value_t = 1024
+    )]
+    compress_min_size: u64,
+
+    /// Content types compressed by --compress, comma separated (`text/*` wildcards allowed)
+    #[arg(
+        long = "compress-types",
+        value_name = "TYPES",
+        value_delimiter = ',',
+        default_value = "text/*,application/json,application/javascript,application/xml,image/svg+xml,application/wasm"
+    )]
+    compress_types: Vec<String>,
+
+    /// Decode upstream content codings the client does not accept
+    #[arg(long = "decompress")]
+    decompress: bool,
+
+    /// Pipe bodies of matching content types through a shell command,
+    /// as `TYPES=COMMAND` (repeatable)
+    #[arg(long = "filter", value_name = "TYPES=COMMAND")]
+    filter: Vec<String>,
+
+    /// Resize and recompress images when the proxy URL carries `?w=` or `?q=`
+    #[arg(long = "image-optimize")]
+    image_optimize: bool,
+
+    /// Proxy `/` like any other path instead of serving the usage page
+    #[arg(long = "no-landing-page")]
+    no_landing_page: bool,
+
+    /// File served as /robots.txt instead of the built-in Disallow-all one
+    #[arg(
+        long = "robots-txt",
+        value_name = "FILE",
+        conflicts_with = "no_robots_txt"
+    )]
+    robots_txt: Option<std::path::PathBuf>,
+
+    /// Proxy /robots.txt like any other path
+    #[arg(long = "no-robots-txt")]
+    no_robots_txt: bool,
+
+    /// Path prefix of the local endpoints (health, metrics, favicon), never proxied
+    #[arg(
+        long = "local-prefix",
+        value_name = "PATH",
+        default_value = local::DEFAULT_PREFIX,
+        value_parser = parse_local_prefix
+    )]
+    local_prefix: String,
+
+    /// Upper bounds of the request duration histogram in seconds, comma separated
+    #[arg(
+        long = "duration-buckets",
+        value_name = "SECONDS",
+        value_delimiter = ',',
+        default_value = metrics::DURATION_BUCKETS
+    )]
+    duration_buckets: Vec<f64>,
+
+    /// Upper bounds of the response size histogram in bytes, comma separated
+    #[arg(
+        long = "size-buckets",
+        value_name = "BYTES",
+        value_delimiter = ',',
+        default_value = metrics::SIZE_BUCKETS
+    )]
+    size_buckets: Vec<u64>,
+
+    /// StatsD or Datadog agent address (HOST:PORT) to push metrics to
+    #[arg(long = "statsd-addr", value_name = "ADDR")]
+    statsd_addr: Option<String>,
+
+    /// Directory of error page templates (`502.html`, `5xx.html`, `error.html`)
+    #[arg(long = "error-pages", value_name = "DIR")]
+    error_pages: Option<std::path::PathBuf>,
+
+    /// Body format of error responses
+    #[arg(
+        long = "error-format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = errors::ErrorFormat::Text
+    )]
+    error_format: errors::ErrorFormat,
+
+    /// Only proxy these request methods, comma separated (default: all)
+    #[arg(
+        long = "allow-methods",
+        value_name = "METHODS",
+        value_delimiter = ',',
+        value_parser = parse_method
+    )]
+    allow_methods: Vec<Method>,
+
+    /// Only proxy requests whose Origin, or Referer without one, is one of
+    /// these sites, comma separated: `https://app.example.com`, or a host
+    /// for any scheme and port, `*.example.com` for subdomains (default: all)
+    #[arg(long = "allow-origins", value_name = "SITES", value_delimiter = ',')]
+    allow_origins: Vec<origin::Site>,
+
+    /// Scheme used for targets without one, or `reject` to refuse them
+    #[arg(
+        long = "default-scheme",
+        value_name = "SCHEME",
+        value_enum,
+        default_value_t = DefaultScheme::Https
+    )]
+    default_scheme: DefaultScheme,
+
+    /// Rewrite http:// targets to https:// and never connect upstream in cleartext
+    #[arg(long = "upgrade-insecure")]
+    upgrade_insecure: bool,
+
+    /// Derive targets from the Host header below this domain, e.g.
+    /// `example-com.DOMAIN` proxies `https://example.com`
+    #[arg(long = "vhost-domain", value_name = "DOMAIN")]
+    vhost_domain: Option<String>,
+
+    /// Send the client's Host header upstream instead of the target's
+    #[arg(long = "preserve-host")]
+    preserve_host: bool,
+
+    /// Log the progress of responses of at least this size (0 disables)
+    #[arg(long = "progress-threshold", value_name = "BYTES", default_value_t = 0)]
+    progress_threshold: u64,
+
+    /// Seconds between progress log lines
+    #[arg(long = "progress-interval", value_name = "SECS", default_value_t = 10)]
+    progress_interval: u64,
+
+    /// Resume upstream bodies that break off with up to N Range requests
+    #[arg(long = "resume-attempts", value_name = "N", default_value_t = 0)]
+    resume_attempts: u32,
+
+    /// Retry failed GET and HEAD requests up to N times
+    #[arg(long = "retries", value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Longest wait before a retry in seconds; responses asking for a
+    /// longer Retry-After are passed through
+    #[arg(long = "retry-max-wait", value_name = "SECS", default_value_t = 10)]
+    retry_max_wait: u64,
+
+    /// Percentage of requests that may be retried (0 for no limit)
+    #[arg(long = "retry-budget", value_name = "PERCENT", default_value_t = 20)]
+    retry_budget: u64,
+
+    /// Answer identical GET requests that arrive while one is in flight
+    /// with the same upstream response
+    #[arg(long = "coalesce")]
+    coalesce: bool,
+
+    /// Send Retry-After dates of 429 and 503 responses as seconds
+    #[arg(long = "translate-retry-after")]
+    translate_retry_after: bool,
+
+    /// Seconds to wait for upstream response headers (0 waits forever)
+    #[arg(long = "upstream-timeout", value_name = "SECS", default_value_t = 0)]
+    upstream_timeout: u64,
+
+    /// Client networks allowed to use control headers such as
+    /// X-Proxy-Timeout, comma separated
+    #[arg(long = "trusted-clients", value_name = "CIDRS", value_delimiter = ',')]
+    trusted_clients: Vec<cidr::Cidr>,
+
+    /// Upper bound for X-Proxy-Timeout in seconds
+    #[arg(long = "max-timeout", value_name = "SECS", default_value_t = 600)]
+    max_timeout: u64,
+
+    /// Download large responses in this many parallel Range requests
+    /// (0 disables)
+    #[arg(long = "segmented-download", value_name = "N", default_value_t = 0)]
+    segmented_download: u64,
+
+    /// Minimum size of responses downloaded in segments
+    #[arg(
+        long = "segment-min-size",
+        value_name = "BYTES",
+        default_value_t = 16 * 1024 * 1024
+    )]
+    segment_min_size: u64,
+
+    /// Record proxied exchanges to this HAR file, with secrets redacted
+    #[arg(long = "record", value_name = "FILE")]
+    record: Option<std::path::PathBuf>,
+
+    /// Log request and response headers (and with `full`, the start of
+    /// bodies) of every exchange, secrets redacted
+    #[arg(long = "dump-traffic", value_name = "MODE")]
+    dump_traffic: Option<dump::Dump>,
+
+    /// Runtime worker threads (default: one per CPU core)
+    #[arg(long = "workers", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+
+    /// Threads for blocking work such as image optimization and file writes
+    #[arg(
+        long = "max-blocking-threads",
+        value_name = "N",
+        default_value_t = 512,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_blocking_threads: u64,
+
+    /// Run everything on the main thread, for small machines
+    #[arg(long = "current-thread", conflicts_with = "workers")]
+    current_thread: bool,
+
+    /// Where log lines go
+    #[arg(long = "log-output", value_name = "OUTPUT", default_value = "stdout")]
+    log_output: logging::Output,
+
+    /// File appended to with `--log-output file`
+    #[arg(long = "log-file", value_name = "FILE")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Log 1 in N successful requests, error responses are always logged
+    #[arg(long = "log-sample", value_name = "N", default_value_t = 1)]
+    log_sample: u64,
+
+    /// Serve responses from this HAR file instead of contacting upstreams
+    #[arg(long = "replay", value_name = "FILE")]
+    replay: Option<std::path::PathBuf>,
+
+    /// Bytes of each request and response body kept in the recording
+    #[arg(long = "record-body-limit", value_name = "BYTES", default_value_t = 64 * 1024)]
+    record_body_limit: usize,
+
+    /// Append a JSON line per proxied request with the client, target, status
+    /// and bytes transferred to this file
+    #[arg(long = "audit-log", value_name = "FILE")]
+    audit_log: Option<std::path::PathBuf>,
+
+    /// Size in bytes at which the audit log is rotated, 0 to never rotate
+    #[arg(long = "audit-log-max-size", value_name = "BYTES", default_value_t = 100 * 1024 * 1024)]
+    audit_log_max_size: u64,
+
+    /// Rotated audit logs kept as FILE.1 to FILE.N
+    #[arg(long = "audit-log-keep", value_name = "N", default_value_t = 10)]
+    audit_log_keep: usize,
+
+    /// MaxMind country database, e.g. GeoLite2-Country.mmdb, to filter and
+    /// count clients by country
+    #[arg(long = "geoip-db", value_name = "FILE")]
+    geoip_db: Option<std::path::PathBuf>,
+
+    /// Only serve clients in these countries, comma separated ISO codes
+    #[arg(long = "allow-countries", value_name = "CODES", value_delimiter = ',')]
+    allow_countries: Vec<String>,
+
+    /// Refuse clients in these countries, comma separated ISO codes
+    #[arg(long = "deny-countries", value_name = "CODES", value_delimiter = ',')]
+    deny_countries: Vec<String>,
+
+    /// Refuse responses declared or recognized as these content types,
+    /// comma separated, `application/*` style wildcards allowed
+    #[arg(
+        long = "deny-content-types",
+        value_name = "TYPES",
+        value_delimiter = ','
+    )]
+    deny_content_types: Vec<String>,
+
+    /// Refuse responses whose content is recognized as another type than
+    /// their Content-Type, e.g. an executable served as an image
+    #[arg(long = "deny-type-mismatch")]
+    deny_type_mismatch: bool,
+
+    /// Shell command that checks uploads before they are forwarded: the
+    /// body is on stdin, exit status 0 allows it
+    #[arg(long = "scan-command", value_name = "COMMAND")]
+    scan_command: Option<String>,
+
+    /// Endpoint that checks uploads before they are forwarded: the body is
+    /// POSTed to it, a 2xx status allows it and a 4xx rejects it
+    #[arg(long = "scan-url", value_name = "URL", conflicts_with = "scan_command")]
+    scan_url: Option<Url>,
+
+    /// Uploads smaller than this are forwarded unscanned
+    #[arg(long = "scan-min-size", value_name = "BYTES", default_value_t = 0)]
+    scan_min_size: u64,
+
+    /// Uploads larger than this are refused, scanned ones are held in memory
+    #[arg(long = "scan-max-size", value_name = "BYTES", default_value_t = 64 * 1024 * 1024)]
+    scan_max_size: u64,
+
+    /// Seconds a scan may take before the upload is refused
+    #[arg(long = "scan-timeout", value_name = "SECONDS", default_value_t = 30)]
+    scan_timeout: u64,
+
+    /// Ban a client after this many requests denied by the proxy within
+    /// `--ban-window`, 0 to only ban through the admin API
+    #[arg(long = "ban-after", value_name = "N", default_value_t = 0)]
+    ban_after: u32,
+
+    /// Seconds in which `--ban-after` denials ban a client
+    #[arg(long = "ban-window", value_name = "SECONDS", default_value_t = 60)]
+    ban_window: u64,
+
+    /// Seconds a ban lasts
+    #[arg(long = "ban-time", value_name = "SECONDS", default_value_t = 600)]
+    ban_time: u64,
+
+    /// TOML configuration file with `[[route]]` and `[[rewrite]]` entries
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<std::path::PathBuf>,
+
+    /// Print the effective options and config file, with secrets masked, and exit
+    #[arg(long = "print-config")]
+    print_config: bool,
+
+    /// Print version
+    #[arg(short = 'V', long = "version")]
+    version: bool,
+
+    /// With --version, also print the git commit, build date, target and features
+    #[arg(long = "verbose", requires = "version")]
+    verbose: bool,
+
     /// Print help
     #[arg(long = "help", action = clap::ArgAction::Help)]
     help: Option<bool>,
+
+    #[command(subcommand)]
+    command: Option<Subcommand>,
 }
 
-async fn proxy_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+// Tasks run instead of the proxy; a doc comment would become the about
+// text of the whole command
+#[derive(clap::Subcommand, Debug)]
+enum Subcommand {
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+    /// Print the manual page in roff format
+    Man,
+    /// Check the health endpoint of a running proxy and exit 0 if it
+    /// answers, e.g. as a container health check
+    Ping {
+        /// Address of the proxy
+        #[arg(
+            long = "addr",
+            value_name = "HOST:PORT",
+            default_value = "127.0.0.1:1234"
+        )]
+        addr: String,
+        /// Seconds to wait for the answer
+        #[arg(long = "timeout", value_name = "SECONDS", default_value_t = 5)]
+        timeout: u64,
+    },
+}
+
+/// TLS protocol version of `--tls-min-version` and `--tls-max-version`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum TlsVersion {
+    #[value(name = "1.0")]
+    Tls10,
+    #[value(name = "1.1")]
+    Tls11,
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// The native TLS protocol, `None` for 1.3 which it has no name for
+    fn protocol(self) -> Option<native_tls::Protocol> {
+        match self {
+            TlsVersion::Tls10 => Some(native_tls::Protocol::Tlsv10),
+            TlsVersion::Tls11 => Some(native_tls::Protocol::Tlsv11),
+            TlsVersion::Tls12 => Some(native_tls::Protocol::Tlsv12),
+            TlsVersion::Tls13 => None,
+        }
+    }
+}
+
+/// Handling of targets given without a scheme
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum DefaultScheme {
+    Https,
+    Http,
+    Reject,
+}
+
+impl DefaultScheme {
+    fn scheme(self) -> Option<&'static str> {
+        match self {
+            DefaultScheme::Https => Some("https"),
+            DefaultScheme::Http => Some("http"),
+            DefaultScheme::Reject => None,
+        }
+    }
+}
+
+/// State shared by all connections
+struct Proxy {
+    client: HttpClient,
+    body_read_timeout: Option<Duration>,
+    compression: Option<encoding::Compression>,
+    decompression: Option<encoding::Decompression>,
+    filters: filter::Pipeline,
+    image_optimize: bool,
+    landing_page: bool,
+    robots_txt: Option<Bytes>,
+    local_prefix: String,
+    metrics: Arc<metrics::Metrics>,
+    errors: errors::ErrorPages,
+    allow_methods: Vec<Method>,
+    allow_origins: Vec<origin::Site>,
+    geoip: Option<geoip::Database>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    user_agents: user_agent::UserAgents,
+    scan: Option<scan::Scan>,
+    type_policy: Option<sniff::TypePolicy>,
+    default_scheme: DefaultScheme,
+    upgrade_insecure: bool,
+    preserve_host: bool,
+    targets: target::Targets,
+    rewrites: Vec<rewrite::Rewrite>,
+    redirects: Vec<redirect::Redirect>,
+    credentials: credentials::Credentials,
+    progress: Option<progress::Progress>,
+    upstream_timeout: Option<Duration>,
+    retry: Option<retry::Retry>,
+    coalesce: Option<coalesce::Coalescer>,
+    translate_retry_after: bool,
+    trusted_clients: Vec<cidr::Cidr>,
+    max_timeout: Duration,
+    resume: Option<resume::Resume>,
+    segmented: Option<segment::Segmented>,
+    recorder: Option<har::Recorder>,
+    audit: Option<audit::AuditLog>,
+    bans: ban::Bans,
+    replay: Option<har::Replay>,
+    dump: Option<dump::Dump>,
+    log_sample: logging::Sampler,
+}
+
+impl Proxy {
+    async fn new(args: &Args) -> Result<Self> {
+        let config = match &args.config {
+            Some(path) => config::Config::load(path)?,
+            None => config::Config::default(),
+        };
+        let routes = config
+            .routes
+            .iter()
+            .map(route::Route::parse)
+            .collect::<Result<Vec<_>>>()?;
+        let pins = pin::Pins::parse(&config.pins)?;
+        let client = build_client(args, &config.resolve, pins).await?;
+        let geoip = args
+            .geoip_db
+            .as_deref()
+            .map(geoip::Database::open)
+            .transpose()?;
+        let countries = |codes: &[String]| -> Vec<String> {
+            codes
+                .iter()
+                .map(|code| code.trim().to_ascii_uppercase())
+                .collect()
+        };
+        let (allow_countries, deny_countries) = (
+            countries(&args.allow_countries),
+            countries(&args.deny_countries),
+        );
+        if geoip.is_none() && !(allow_countries.is_empty() && deny_countries.is_empty()) {
+            return Err(anyhow!(
+                "--allow-countries and --deny-countries need --geoip-db"
+            ));
+        }
+        let replay = args.replay.as_deref().map(har::Replay::load).transpose()?;
+        let warms = config
+            .warms
+            .iter()
+            .map(warm::Warm::parse)
+            .collect::<Result<Vec<_>>>()?;
+        // Replays never contact upstreams
+        if replay.is_none() {
+            for route in &routes {
+                // Probed where the route's requests go
+                let client = match route.via() {
+                    Some(via) => client.via(via),
+                    None => client.clone(),
+                };
+                let host = route.host_header().cloned();
+                tokio::spawn(route.upstreams().clone().check_health(client, host));
+            }
+            for warm in warms {
+                tokio::spawn(warm.run(client.clone()));
+            }
+        }
+        let rewrites = config
+            .rewrites
+            .iter()
+            .map(rewrite::Rewrite::parse)
+            .collect::<Result<Vec<_>>>()?;
+        let redirects = config
+            .redirects
+            .iter()
+            .map(redirect::Redirect::parse)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Proxy {
+            rewrites,
+            redirects,
+            credentials: credentials::Credentials::parse(&config.credentials)?,
+            user_agents: user_agent::UserAgents::parse(&config.user_agents)?,
+            type_policy: (!args.deny_content_types.is_empty() || args.deny_type_mismatch).then(
+                || sniff::TypePolicy {
+                    deny: filter::ContentTypes::new(&args.deny_content_types),
+                    mismatch: args.deny_type_mismatch,
+                },
+            ),
+            scan: args
+                .scan_command
+                .clone()
+                .map(scan::Scanner::Command)
+                .or_else(|| args.scan_url.clone().map(scan::Scanner::Url))
+                .map(|scanner| scan::Scan {
+                    scanner,
+                    min_size: args.scan_min_size,
+                    max_size: args.scan_max_size,
+                    timeout: Duration::from_secs(args.scan_timeout),
+                }),
+            retry: (args.retries > 0).then_some(retry::Retry {
+                attempts: args.retries,
+                max_wait: Duration::from_secs(args.retry_max_wait),
+                budget: (args.retry_budget > 0).then(|| retry::Budget::new(args.retry_budget)),
+            }),
+            coalesce: args.coalesce.then(coalesce::Coalescer::default),
+            translate_retry_after: args.translate_retry_after,
+            upstream_timeout: (args.upstream_timeout > 0)
+                .then(|| Duration::from_secs(args.upstream_timeout)),
+            trusted_clients: args.trusted_clients.clone(),
+            max_timeout: Duration::from_secs(args.max_timeout),
+            progress: (args.progress_threshold > 0).then_some(progress::Progress {
+                threshold: args.progress_threshold,
+                interval: Duration::from_secs(args.progress_interval.max(1)),
+            }),
+            resume: (args.resume_attempts > 0).then_some(resume::Resume {
+                attempts: args.resume_attempts,
+            }),
+            replay,
+            dump: args.dump_traffic,
+            log_sample: logging::Sampler::new(args.log_sample),
+            recorder: args
+                .record
+                .as_ref()
+                .map(|path| har::Recorder::new(path.clone(), args.record_body_limit))
+                .transpose()?,
+            bans: ban::Bans::new(
+                args.ban_after,
+                Duration::from_secs(args.ban_window),
+                Duration::from_secs(args.ban_time),
+            ),
+            audit: args
+                .audit_log
+                .as_ref()
+                .map(|path| {
+                    audit::AuditLog::open(
+                        path.clone(),
+                        audit::Rotation {
+                            max_size: args.audit_log_max_size,
+                            keep: args.audit_log_keep,
+                        },
+                    )
+                })
+                .transpose()?,
+            segmented: (args.segmented_download > 1).then_some(segment::Segmented {
+                segments: args.segmented_download,
+                min_size: args.segment_min_size,
+            }),
+            client,
+            body_read_timeout: (args.body_read_timeout > 0)
+                .then(|| Duration::from_secs(args.body_read_timeout)),
+            compression: args.compress.then(|| encoding::Compression {
+                min_size: args.compress_min_size,
+                types: filter::ContentTypes::new(&args.compress_types),
+            }),
+            decompression: args.decompress.then_some(encoding::Decompression),
+            filters: build_filters(args)?,
+            image_optimize: args.image_optimize,
+            landing_page: !args.no_landing_page,
+            robots_txt: robots_txt(args)?,
+            local_prefix: args.local_prefix.clone(),
+            metrics: Arc::new(metrics::Metrics::new(
+                &args.duration_buckets,
+                &args.size_buckets,
+                args.statsd_addr
+                    .as_deref()
+                    .map(statsd::Statsd::connect)
+                    .transpose()?,
+            )),
+            allow_methods: args.allow_methods.clone(),
+            allow_origins: args.allow_origins.clone(),
+            geoip,
+            allow_countries,
+            deny_countries,
+            default_scheme: args.default_scheme,
+            upgrade_insecure: args.upgrade_insecure,
+            preserve_host: args.preserve_host,
+            targets: target::Targets::new(
+                args.default_scheme.scheme(),
+                args.vhost_domain.as_deref(),
+                routes,
+            ),
+            errors: errors::ErrorPages::load(args.error_pages.as_deref(), args.error_format)?,
+        })
+    }
+}
+
+/// Hop-by-hop headers, which must not be forwarded by proxies (RFC 9110 7.6.1)
+const HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, including any named in `Connection`
+fn remove_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+async fn proxy_handler(
+    req: Request<Incoming>,
+    proxy: Arc<Proxy>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
     let method = req.method().clone();
     let uri = req.uri().clone();
+    let request_id = errors::request_id(req.headers());
+    let started = Instant::now();
+    let _request = proxy.metrics.request();
+    let received = Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let req = req.map(|body| body::counted(body::boxed(body), received.clone()));
+    if let Some(dump) = proxy.dump {
+        let line = format!("{} {} {:?}", method, uri, req.version());
+        dump.head(&request_id, "client >", &line, req.headers());
+    }
+    let country = proxy.geoip.as_ref().map(|db| db.country(peer_addr.ip()));
+    // Tags every log line of the request
+    let span = match &country {
+        Some(country) => {
+            let country = country.as_deref().unwrap_or("unknown");
+            proxy.metrics.country(country);
+            tracing::info_span!("client", country)
+        }
+        None => tracing::Span::none(),
+    };
+    let country = country.flatten();
 
-    match proxy_request(req).await {
+    // A panic fails this request only, not the connection task
+    let result = AssertUnwindSafe(
+        proxy_request(
+            req,
+            &proxy,
+            local_addr,
+            peer_addr,
+            &request_id,
+            country.as_deref(),
+        )
+        .instrument(span.clone()),
+    )
+    .catch_unwind()
+    .await
+    .unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        Err(anyhow!("Handler panicked: {}", message))
+    });
+    let _entered = span.enter();
+    let mut logged = false;
+    let mut response = match result {
         Ok(response) => {
-            tracing::debug!("{} {} -> {}", method, uri, response.status());
-            Ok(response)
+            // Errors are always logged
+            logged = response.status().as_u16() >= 400 || proxy.log_sample.sample();
+            response
         }
         Err(e) => {
-            error!("Proxy error for {} {}: {}", method, uri, e);
-            Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::new(Bytes::from(format!("Proxy error: {}", e))))
-                .unwrap())
+            // Details stay in the log, the client only gets the request id
+            error!("Proxy error for {} {} ({}): {}", method, uri, request_id, e);
+            proxy.errors.render(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal proxy error",
+                &request_id,
+            )
         }
+    };
+    proxy.metrics.response(response.status());
+    if response.status().is_client_error()
+        && response.extensions().get::<errors::Rendered>().is_some()
+        && !cidr::any_contains(&proxy.trusted_clients, peer_addr.ip())
+        && proxy.bans.strike(peer_addr.ip())
+    {
+        warn!(
+            "Banning {} for {:?} after repeated denied requests",
+            peer_addr.ip(),
+            proxy.bans.duration()
+        );
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    if let Some(dump) = proxy.dump {
+        dump.head(
+            &request_id,
+            "client <",
+            response.status().as_str(),
+            response.headers(),
+        );
+        let body = std::mem::replace(response.body_mut(), body::empty());
+        *response.body_mut() = dump.body(&request_id, "client <", body);
+    }
+    let body = std::mem::replace(response.body_mut(), body::empty());
+    let mut body = proxy.metrics.measure(body, started);
+    if logged {
+        let log = logging::RequestLog {
+            line: format!("{} {} -> {}", method, uri, response.status()),
+            received,
+            sent: Arc::default(),
+            span: span.clone(),
+        };
+        body = body::with_guard(body::counted(body, log.sent.clone()), log);
     }
+    *response.body_mut() = body;
+    Ok(response)
 }
 
-async fn proxy_request(req: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+async fn proxy_request(
+    req: Request<Body>,
+    proxy: &Proxy,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    request_id: &str,
+    country: Option<&str>,
+) -> Result<Response<Body>> {
     let uri = req.uri();
     let path = uri.path();
 
-    // Extract target URL (remove leading '/')
-    let target_url_str = if path.starts_with('/') {
-        &path[1..]
-    } else {
-        path
-    };
+    // Local endpoints take precedence over target extraction
+    if let Some(rest) = path.strip_prefix(proxy.local_prefix.as_str())
+        && (rest.is_empty() || rest.starts_with('/'))
+    {
+        if rest == "/connections" {
+            return Ok(local::connections(
+                req.method(),
+                cidr::any_contains(&proxy.trusted_clients, peer_addr.ip()),
+                &proxy.metrics,
+                &proxy.errors,
+                request_id,
+            ));
+        }
+        if rest == "/bans" {
+            return Ok(local::bans(
+                req.method(),
+                uri.query(),
+                cidr::any_contains(&proxy.trusted_clients, peer_addr.ip()),
+                &proxy.bans,
+                &proxy.errors,
+                request_id,
+            ));
+        }
+        if rest == "/log-level" {
+            return Ok(local::log_level(
+                req.method(),
+                uri.query(),
+                cidr::any_contains(&proxy.trusted_clients, peer_addr.ip()),
+                &proxy.errors,
+                request_id,
+            ));
+        }
+        return Ok(local::handle(
+            req.method(),
+            rest,
+            &proxy.metrics,
+            &proxy.targets,
+            &proxy.errors,
+            request_id,
+        ));
+    }
+    if let Some(robots_txt) = &proxy.robots_txt
+        && path == "/robots.txt"
+    {
+        return Ok(local::robots_txt(robots_txt));
+    }
+    if proxy.landing_page && path == "/" && proxy.targets.path_based(req.headers()) {
+        let json = uri.query().is_some_and(|query| {
+            url::form_urlencoded::parse(query.as_bytes()).any(|(k, v)| k == "format" && v == "json")
+        });
+        let origin = local_origin(req.headers(), uri, local_addr);
+        return Ok(local::landing_page(
+            &origin,
+            &proxy.local_prefix,
+            proxy.default_scheme.scheme(),
+            json,
+        ));
+    }
 
-    // If no protocol prefix, default to https
-    let target_url_str =
-        if !target_url_str.starts_with("http://") && !target_url_str.starts_with("https://") {
-            format!("https://{}", target_url_str)
-        } else {
-            target_url_str.to_string()
-        };
+    if !proxy.allow_methods.is_empty() && !proxy.allow_methods.contains(req.method()) {
+        let allow: Vec<&str> = proxy.allow_methods.iter().map(Method::as_str).collect();
+        let mut response = proxy.errors.render(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed",
+            request_id,
+        );
+        if let Ok(allow) = HeaderValue::from_str(&allow.join(", ")) {
+            response.headers_mut().insert("allow", allow);
+        }
+        return Ok(response);
+    }
+
+    if !proxy.allow_origins.is_empty() && !origin::allowed(&proxy.allow_origins, req.headers()) {
+        tracing::debug!("Refusing {} {} from another site", req.method(), uri);
+        return Ok(proxy
+            .errors
+            .render(StatusCode::FORBIDDEN, "Origin not allowed", request_id));
+    }
+
+    if proxy.geoip.is_some()
+        && !geoip::allowed(country, &proxy.allow_countries, &proxy.deny_countries)
+        && !cidr::any_contains(&proxy.trusted_clients, peer_addr.ip())
+    {
+        tracing::debug!(
+            "Refusing {} {} from country {:?}",
+            req.method(),
+            uri,
+            country
+        );
+        return Ok(proxy
+            .errors
+            .render(StatusCode::FORBIDDEN, "Country not allowed", request_id));
+    }
+
+    if !proxy.user_agents.is_empty() {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        match proxy.user_agents.check(user_agent, peer_addr.ip()) {
+            user_agent::Verdict::Allow => {}
+            user_agent::Verdict::Block => {
+                tracing::debug!("Refusing {} {} from {:?}", req.method(), uri, user_agent);
+                return Ok(proxy.errors.render(
+                    StatusCode::FORBIDDEN,
+                    "User agent not allowed",
+                    request_id,
+                ));
+            }
+            user_agent::Verdict::Throttle(wait) => {
+                let mut response = proxy.errors.render(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "Too many requests",
+                    request_id,
+                );
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+                return Ok(response);
+            }
+        }
+    }
 
-    // Parse target URL
-    let target_url = match Url::parse(&target_url_str) {
-        Ok(url) => url,
-        Err(_) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Full::new(Bytes::from("Invalid target URL")))
-                .unwrap());
+    let mut target = match proxy.targets.extract(req.headers(), uri, peer_addr.ip()) {
+        Ok(target) => target,
+        Err(message) => {
+            return Ok(proxy
+                .errors
+                .render(StatusCode::BAD_REQUEST, message, request_id));
         }
     };
+    if let Some((status, location)) = proxy
+        .redirects
+        .iter()
+        .find_map(|redirect| redirect.apply(&target.url))
+    {
+        let client = client_location(
+            proxy,
+            target.source,
+            location.as_str(),
+            req.headers(),
+            uri,
+            local_addr,
+            &target.url,
+        );
+        match client.and_then(|client| HeaderValue::from_str(&client).ok()) {
+            Some(client) => {
+                return Ok(Response::builder()
+                    .status(status)
+                    .header(header::LOCATION, client)
+                    .body(body::empty())?);
+            }
+            // Header targets cannot be redirected through the proxy
+            None => target.url = location,
+        }
+    }
+    // Only 100-continue is defined; anything else must be refused (RFC 9110 10.1.1).
+    // Checked before anything reads the body, which makes hyper send 100 Continue.
+    if let Some(expect) = req.headers().get("expect")
+        && !expect.as_bytes().eq_ignore_ascii_case(b"100-continue")
+    {
+        return Ok(proxy.errors.render(
+            StatusCode::EXPECTATION_FAILED,
+            "Unsupported expectation",
+            request_id,
+        ));
+    }
+    // Covers the upload while it is scanned as well as while it is forwarded
+    let req = match proxy.body_read_timeout {
+        Some(deadline) => req.map(|body| body::boxed(body::TimeoutBody::new(body, deadline))),
+        None => req,
+    };
+    let req = match &proxy.scan {
+        Some(scan) => {
+            let (parts, body) = req.into_parts();
+            let content_type = parts.headers.get(header::CONTENT_TYPE);
+            match scan
+                .check(&proxy.client, &target.url, content_type, body)
+                .await?
+            {
+                scan::Verdict::Allow(body) => Request::from_parts(parts, body),
+                scan::Verdict::Deny(status, message) => {
+                    let mut response = proxy.errors.render(status, message, request_id);
+                    // The rest of a timed out upload is not waited for
+                    if status == StatusCode::REQUEST_TIMEOUT {
+                        response
+                            .headers_mut()
+                            .insert("connection", HeaderValue::from_static("close"));
+                    }
+                    return Ok(response);
+                }
+            }
+        }
+        None => req,
+    };
+    // Lets the log level be raised for one target host
+    let host = target.url.host_str().unwrap_or("").to_string();
+    let span = tracing::trace_span!(logging::REQUEST_SPAN, host = host);
+    let transferred = proxy.metrics.target(&host);
+    let mut audit = proxy
+        .audit
+        .as_ref()
+        .map(|audit| audit.entry(request_id, peer_addr.ip(), req.method(), &target.url));
+    let req = req.map(|body| {
+        let body = body::counted(body, transferred.received.clone());
+        match &audit {
+            Some(entry) => body::counted(body, entry.received()),
+            None => body,
+        }
+    });
+    let (leader, shared) = match proxy
+        .coalesce
+        .as_ref()
+        .and_then(|coalesce| coalesce.join(&req, &target.url))
+    {
+        Some(coalesce::Role::Leader(leader)) => (Some(leader), None),
+        Some(coalesce::Role::Follower(follower)) => (None, follower.response().await),
+        None => (None, None),
+    };
+    let forwarded = match shared {
+        Some(response) => Ok(response),
+        None => {
+            forward(req, proxy, local_addr, peer_addr, request_id, target)
+                .instrument(span)
+                .await
+        }
+    };
+    let response = match forwarded {
+        Ok(response) => match leader {
+            Some(leader) => leader.share(response),
+            None => response,
+        },
+        Err(e) => {
+            // Answered with a 500 by the handler
+            if let Some(entry) = &mut audit {
+                entry.status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            return Err(e);
+        }
+    };
+    let response = response.map(|body| body::counted(body, transferred.sent.clone()));
+    Ok(match audit {
+        Some(mut entry) => {
+            entry.status(response.status());
+            let sent = entry.sent();
+            response.map(|body| body::with_guard(body::counted(body, sent), entry))
+        }
+        None => response,
+    })
+}
 
-    // Build new request
-    let target_uri = Uri::from_str(&target_url.to_string())?;
+/// Send a request to its target and process the response
+async fn forward(
+    req: Request<Body>,
+    proxy: &Proxy,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    request_id: &str,
+    target: target::Target,
+) -> Result<Response<Body>> {
+    let mut target_url = target.url;
+    prepare_target_url(proxy, &mut target_url);
+    // The upstreams of a route are reached where its `connect_to` and `sni`
+    // say, other requests to them are not
+    let client = match &target.via {
+        Some(via) => proxy.client.via(via),
+        None => proxy.client.clone(),
+    };
 
-    // Collect original request body
+    // Stream the request body upstream. hyper sends the interim 100 Continue
+    // once the client starts polling it, so clients sending
+    // `Expect: 100-continue` only upload after the upstream accepted the
+    // connection and the request passed validation.
     let (parts, body) = req.into_parts();
-    let body_bytes = body.collect().await?.to_bytes();
+    let body = match proxy.dump {
+        Some(dump) => dump.body(request_id, "client >", body),
+        None => body,
+    };
+    let (mut exchange, body) = match &proxy.recorder {
+        Some(recorder) => {
+            let (exchange, body) = recorder.exchange(body);
+            (Some(exchange), body)
+        }
+        None => (None, body),
+    };
 
-    // Create new request
-    let mut new_req = Request::builder().method(parts.method).uri(&target_uri);
+    // Copy end-to-end headers. The expectation is fulfilled by this hop,
+    // hyper's client does not wait for an upstream 100 Continue.
+    let mut headers = parts.headers.clone();
+    remove_hop_headers(&mut headers);
+    headers.remove("expect");
+    headers.remove(target::TARGET_HEADER);
+    headers.remove(TIMEOUT_HEADER);
+    headers.remove(DRY_RUN_HEADER);
+    headers.remove(header::HOST);
+    if accepts_trailers(&parts.headers) {
+        headers.insert("te", HeaderValue::from_static("trailers"));
+    }
+    // The shadow gets the client's headers, not the Host, token or signature
+    // meant for the primary upstream
+    let shadow_headers = target.shadow.as_ref().map(|_| headers.clone());
+    // The Host of the target URL unless overridden or kept
+    let host = match &target.host_header {
+        Some(host) => Some(host),
+        None => parts
+            .headers
+            .get(header::HOST)
+            .filter(|_| proxy.preserve_host),
+    };
+    if let Some(host) = host {
+        headers.insert(header::HOST, host.clone());
+    }
 
-    // Copy all headers but replace Host
-    for (name, value) in parts.headers.iter() {
-        if name != "host" {
-            new_req = new_req.header(name, value);
+    // Only requests without a body can be sent twice
+    let replayable = matches!(parts.method, Method::GET | Method::HEAD) && body.is_end_stream();
+    let hedge = target.hedge.filter(|_| replayable);
+    let mut upstream = target.upstream;
+    if let Some(tokens) = &target.tokens
+        && proxy.replay.is_none()
+    {
+        match tokens.authorization(&proxy.client).await {
+            Ok(value) => {
+                headers.insert(header::AUTHORIZATION, value);
+            }
+            Err(e) => {
+                warn!("No token for {} to {}: {}", request_id, target_url, e);
+                return Ok(proxy.errors.render(
+                    StatusCode::BAD_GATEWAY,
+                    "Upstream credentials unavailable",
+                    request_id,
+                ));
+            }
         }
     }
+    let signing = match target.signer.as_ref().filter(|_| proxy.replay.is_none()) {
+        Some(signer) => match signer.signing(&proxy.client).await {
+            Ok(signing) => Some(signing),
+            Err(e) => {
+                warn!(
+                    "No credentials to sign {} for {}: {}",
+                    request_id, target_url, e
+                );
+                return Ok(proxy.errors.render(
+                    StatusCode::BAD_GATEWAY,
+                    "Upstream credentials unavailable",
+                    request_id,
+                ));
+            }
+        },
+        None => None,
+    };
 
-    // Set new Host header
-    if let Some(host) = target_url.host_str() {
-        let host_with_port = if let Some(port) = target_url.port() {
-            format!("{}:{}", host, port)
-        } else {
-            host.to_string()
-        };
-        new_req = new_req.header("host", host_with_port);
+    if parts
+        .headers
+        .get(DRY_RUN_HEADER)
+        .is_some_and(|v| v.as_bytes() == b"1")
+        && cidr::any_contains(&proxy.trusted_clients, peer_addr.ip())
+    {
+        let headers = outbound_headers(
+            proxy,
+            signing.as_ref(),
+            &parts.method,
+            &headers,
+            &target_url,
+        );
+        let request = upstream_request(&parts.method, &target_url, &headers, body::empty())?;
+        let preview = serde_json::json!({
+            "method": parts.method.as_str(),
+            "url": har::redact_url(&target_url).as_str(),
+            "headers": har::header_list(request.headers()),
+        });
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body::full(serde_json::to_vec_pretty(&preview)?))?);
+    }
+    if let Some(shadow) = target
+        .shadow
+        .filter(|shadow| replayable && proxy.replay.is_none() && shadow.sample())
+        && let Some(headers) = shadow_headers
+        && let Ok(mut url) = Url::parse(&shadow.url)
+    {
+        url.set_query(parts.uri.query());
+        prepare_target_url(proxy, &mut url);
+        let headers = outbound_headers(proxy, None, &parts.method, &headers, &url);
+        let request = upstream_request(&parts.method, &url, &headers, body::empty())?;
+        tokio::spawn(shadow_request(
+            proxy.client.clone(),
+            request,
+            proxy.upstream_timeout,
+            request_id.to_string(),
+        ));
     }
 
-    let new_req = new_req.body(Full::new(body_bytes))?;
-
-    // Send request - choose different client based on protocol
-    let response = if target_url.scheme() == "https" {
-        // HTTPS request
-        let https = hyper_tls::HttpsConnector::new();
-        let client =
-            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-                .build(https);
-        client.request(new_req).await
-    } else {
-        // HTTP request
-        let client =
-            hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-                .build_http();
-        client.request(new_req).await
+    // Send request - the connector handles both http and https
+    let mut started = Instant::now();
+    let mut primary = client.request(upstream_request(
+        &parts.method,
+        &target_url,
+        &outbound_headers(
+            proxy,
+            signing.as_ref(),
+            &parts.method,
+            &headers,
+            &target_url,
+        ),
+        body,
+    )?);
+    let send = async {
+        match hedge {
+            None => primary.await,
+            Some(hedge) => match tokio::time::timeout(hedge.delay, &mut primary).await {
+                Ok(response) => response,
+                Err(_) => match hedge_request(
+                    proxy,
+                    &hedge,
+                    upstream.as_ref(),
+                    &parts,
+                    &headers,
+                    signing.as_ref(),
+                ) {
+                    None => primary.await,
+                    Some((url, pick, request)) => {
+                        let hedged = Instant::now();
+                        let secondary = client.request(request);
+                        match first_success(primary, secondary).await {
+                            (response, false) => response,
+                            (response, true) => {
+                                target_url = url;
+                                upstream = Some(pick);
+                                started = hedged;
+                                response
+                            }
+                        }
+                    }
+                },
+            },
+        }
+    };
+    let deadline =
+        requested_timeout(proxy, &parts.headers, peer_addr.ip()).or(proxy.upstream_timeout);
+    let mut result = match &proxy.replay {
+        Some(replay) => {
+            drop(send);
+            match replay.response(&parts.method, &target_url) {
+                Some(response) => Some(Ok(response)),
+                None => {
+                    return Ok(proxy.errors.render(
+                        StatusCode::BAD_GATEWAY,
+                        "No recorded response",
+                        request_id,
+                    ));
+                }
+            }
+        }
+        None => within(deadline, send)
+            .await
+            .map(|result| result.map(|response| response.map(body::boxed))),
+    };
+    // Credentials of the URL that answered, which a hedge may have changed
+    let headers = outbound_headers(
+        proxy,
+        signing.as_ref(),
+        &parts.method,
+        &headers,
+        &target_url,
+    );
+    if let Some(retry) = &proxy.retry
+        && replayable
+        && proxy.replay.is_none()
+    {
+        if let Some(budget) = &retry.budget {
+            budget.deposit();
+        }
+        let mut attempt = 0;
+        while let Some(attempted) = &result
+            && let Some(delay) = retry.delay(attempt, attempted)
+        {
+            let denied = retry
+                .budget
+                .as_ref()
+                .is_some_and(|budget| !budget.withdraw());
+            proxy.metrics.retry(denied);
+            if denied {
+                warn!("Retry budget used up, not retrying {}", request_id);
+                break;
+            }
+            attempt += 1;
+            tracing::debug!(
+                "Retrying {} for {} in {:?} (attempt {})",
+                request_id,
+                target_url,
+                delay,
+                attempt + 1
+            );
+            // Release the failed response before waiting
+            drop(result.take());
+            tokio::time::sleep(delay).await;
+            let request = upstream_request(&parts.method, &target_url, &headers, body::empty())?;
+            started = Instant::now();
+            result = within(deadline, client.request(request))
+                .await
+                .map(|result| result.map(|response| response.map(body::boxed)));
+        }
+    }
+    let Some(response) = result else {
+        warn!(
+            "Upstream request {} for {} timed out after {:?}",
+            request_id,
+            target_url,
+            deadline.unwrap_or_default()
+        );
+        return Ok(proxy.errors.render(
+            StatusCode::GATEWAY_TIMEOUT,
+            "Upstream request timed out",
+            request_id,
+        ));
     };
 
+    if let (Ok(_), Some(pick)) = (&response, &upstream) {
+        pick.record_latency(started.elapsed());
+    }
+
     let response = match response {
         Ok(resp) => resp,
+        Err(e) if body::caused_by::<body::BodyTimeout>(&e) => {
+            let mut response = proxy.errors.render(
+                StatusCode::REQUEST_TIMEOUT,
+                "Request body timed out",
+                request_id,
+            );
+            response
+                .headers_mut()
+                .insert("connection", HeaderValue::from_static("close"));
+            return Ok(response);
+        }
+        Err(e) if body::caused_by::<connect::ConnLimitExceeded>(&e) => {
+            return Ok(proxy.errors.render(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many connections to upstream host",
+                request_id,
+            ));
+        }
         Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Full::new(Bytes::from(format!("Request failed: {}", e))))
-                .unwrap());
+            warn!(
+                "Upstream request {} for {} failed: {:?}",
+                request_id, target_url, e
+            );
+            return Ok(proxy.errors.render(
+                StatusCode::BAD_GATEWAY,
+                "Upstream request failed",
+                request_id,
+            ));
         }
     };
 
-    // Process response
-    let (mut resp_parts, resp_body) = response.into_parts();
-    let resp_body_bytes = resp_body.collect().await?.to_bytes();
-
-    // Process Location header
-    if let Some(location_header) = resp_parts.headers.get("location") {
-        if let Ok(location_str) = location_header.to_str() {
-            let new_location =
-                process_location_header(location_str, &parts.headers, &parts.uri, &target_url);
-            if let Some(new_loc) = new_location {
-                resp_parts
-                    .headers
-                    .insert("location", new_loc.parse().unwrap());
+    // Process response, the body (including trailers) is streamed through
+    // byte for byte, so Content-Encoding and Content-Length stay valid
+    let (mut resp_parts, mut resp_body) = response.into_parts();
+    if let Some(policy) = &proxy.type_policy
+        && parts.method != Method::HEAD
+    {
+        match policy.check(&resp_parts.headers, resp_body).await? {
+            sniff::Verdict::Allow(body) => resp_body = body,
+            sniff::Verdict::Deny(reason) => {
+                warn!(
+                    "Refused response of {} ({}): {}",
+                    har::redact_url(&target_url),
+                    request_id,
+                    reason
+                );
+                return Ok(proxy.errors.render(
+                    StatusCode::FORBIDDEN,
+                    "Response type not allowed",
+                    request_id,
+                ));
             }
         }
     }
+    if let Some(dump) = proxy.dump {
+        // Headers as sent, with Host set for the target
+        let request = upstream_request(&parts.method, &target_url, &headers, body::empty())?;
+        let line = format!("{} {}", parts.method, target_url);
+        dump.head(request_id, "upstream >", &line, request.headers());
+        dump.head(
+            request_id,
+            "upstream <",
+            resp_parts.status.as_str(),
+            &resp_parts.headers,
+        );
+    }
+    if let Some(exchange) = &mut exchange {
+        exchange.request(&parts.method, &target_url, &headers);
+        exchange.response(&resp_parts);
+    }
+    if proxy.translate_retry_after
+        && matches!(
+            resp_parts.status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+    {
+        retry::translate_retry_after(&mut resp_parts.headers);
+    }
+
+    // Process Location header
+    if let Some(location_header) = resp_parts.headers.get("location")
+        && let Ok(location_str) = location_header.to_str()
+        && let Some(new_loc) = client_location(
+            proxy,
+            target.source,
+            location_str,
+            &parts.headers,
+            &parts.uri,
+            local_addr,
+            &target_url,
+        )
+        && let Ok(value) = HeaderValue::from_str(&new_loc)
+    {
+        resp_parts.headers.insert("location", value);
+    }
+
+    // Build response; framing and connection semantics are re-derived for
+    // the client (e.g. no chunked encoding towards HTTP/1.0)
+    remove_hop_headers(&mut resp_parts.headers);
+    let mut resp_body = if parts.method == Method::HEAD {
+        // Nothing follows a HEAD response, dropping the upstream body right
+        // away returns the connection to the pool
+        body::empty()
+    } else {
+        resp_body
+    };
+    if let Some(resume) = &proxy.resume
+        && parts.method == Method::GET
+    {
+        resp_body = resume.apply(&client, &target_url, &headers, &resp_parts, resp_body);
+    }
+    if let Some(segmented) = &proxy.segmented
+        && parts.method == Method::GET
+    {
+        resp_body = segmented.apply(&client, &target_url, &headers, &resp_parts, resp_body);
+    }
+    if let Some(exchange) = exchange {
+        resp_body = exchange.finish(resp_body);
+    }
+    if !proxy.filters.is_empty() {
+        resp_body = proxy.filters.apply(&parts, &mut resp_parts, resp_body);
+    }
+    if let Some(decompression) = &proxy.decompression {
+        resp_body = decompression.apply(&parts.method, &parts.headers, &mut resp_parts, resp_body);
+    }
+    if let Some(compression) = &proxy.compression {
+        resp_body = compression.apply(&parts.method, &parts.headers, &mut resp_parts, resp_body);
+    }
+    if let Some(progress) = &proxy.progress {
+        let length = resp_parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let label = format!("{} {} ({})", parts.method, target_url, request_id);
+        resp_body = progress.apply(resp_body, label, length);
+    }
+    // The upstream stays busy until its response is fully sent
+    if let Some(pick) = upstream {
+        resp_body = body::with_guard(resp_body, pick);
+    }
 
-    // Build response
-    let mut response_builder = Response::builder()
-        .status(resp_parts.status)
-        .version(resp_parts.version);
+    let mut response_builder = Response::builder().status(resp_parts.status);
 
     for (name, value) in resp_parts.headers.iter() {
         response_builder = response_builder.header(name, value);
     }
 
-    Ok(response_builder.body(Full::new(resp_body_bytes))?)
+    Ok(response_builder.body(resp_body)?)
 }
 
-fn process_location_header(
-    location: &str,
-    request_headers: &hyper::HeaderMap,
-    request_uri: &Uri,
-    target_url: &Url,
-) -> Option<String> {
-    // If Location is a complete URL, return proxy version directly
-    if location.starts_with("http://") || location.starts_with("https://") {
-        if let Ok(_location_url) = Url::parse(location) {
-            // Get request origin
-            let request_origin = get_request_origin(request_headers, request_uri);
-            return Some(format!("{}/{}", request_origin, location));
+/// Apply rewrites and `--upgrade-insecure`, and drop the query parameters
+/// meant for the proxy
+fn prepare_target_url(proxy: &Proxy, url: &mut Url) {
+    for rewrite in &proxy.rewrites {
+        rewrite.apply(url);
+    }
+
+    if proxy.upgrade_insecure && url.scheme() == "http" {
+        // set_scheme keeps an explicit port, which is only wrong for the default one
+        if url.port() == Some(80) {
+            let _ = url.set_port(None);
         }
-    } else if location.starts_with('/') {
-        // Relative path, need to combine origin
-        let request_origin = get_request_origin(request_headers, request_uri);
-        let target_origin = format!(
-            "{}://{}",
-            target_url.scheme(),
-            target_url.host_str().unwrap_or("")
+        let _ = url.set_scheme("https");
+    }
+
+    // Drop the query parameters meant for the proxy
+    if proxy.image_optimize && images::Options::from_query(url.query()).is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| !images::PARAMS.contains(&key.as_ref()))
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        if pairs.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+    }
+}
+
+/// Await `future` unless `deadline` passes first
+async fn within<F: Future>(deadline: Option<Duration>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Send a copy of a request to a shadow upstream and discard the response
+async fn shadow_request(
+    client: HttpClient,
+    request: Request<Body>,
+    deadline: Option<Duration>,
+    request_id: String,
+) {
+    let uri = request.uri().clone();
+    let exchange = async {
+        let mut body = client.request(request).await?.into_body();
+        while let Some(frame) = http_body_util::BodyExt::frame(&mut body).await {
+            frame?;
+        }
+        Ok::<_, BoxError>(())
+    };
+    match within(deadline, exchange).await {
+        Some(Ok(())) => tracing::debug!("Shadow request {} to {} done", request_id, uri),
+        Some(Err(e)) => tracing::debug!("Shadow request {} to {} failed: {}", request_id, uri, e),
+        None => tracing::debug!("Shadow request {} to {} timed out", request_id, uri),
+    }
+}
+
+/// Copy of a bodiless request for the hedge upstream, if there is another
+/// healthy one
+fn hedge_request(
+    proxy: &Proxy,
+    hedge: &route::Hedge,
+    primary: Option<&upstream::Pick>,
+    parts: &hyper::http::request::Parts,
+    headers: &HeaderMap,
+    signing: Option<&sigv4::Signing>,
+) -> Option<(Url, upstream::Pick, Request<Body>)> {
+    let (url, pick) = hedge.resolve(primary?)?;
+    let mut url = Url::parse(&url).ok()?;
+    url.set_query(parts.uri.query());
+    prepare_target_url(proxy, &mut url);
+    let headers = outbound_headers(proxy, signing, &parts.method, headers, &url);
+    let request = upstream_request(&parts.method, &url, &headers, body::empty()).ok()?;
+    Some((url, pick, request))
+}
+
+/// `headers` with the configured credentials for `url`, signed for routes
+/// with `sigv4`
+fn outbound_headers(
+    proxy: &Proxy,
+    signing: Option<&sigv4::Signing>,
+    method: &Method,
+    headers: &HeaderMap,
+    url: &Url,
+) -> HeaderMap {
+    let mut headers = headers.clone();
+    proxy.credentials.apply(url, &mut headers);
+    if let Some(signing) = signing {
+        signing.sign(method, url, &mut headers);
+    }
+    headers
+}
+
+/// Deadline a trusted client asked for with `X-Proxy-Timeout`, capped by
+/// `--max-timeout`
+fn requested_timeout(proxy: &Proxy, headers: &HeaderMap, client_ip: IpAddr) -> Option<Duration> {
+    let value = headers.get(TIMEOUT_HEADER)?.to_str().ok()?;
+    if !cidr::any_contains(&proxy.trusted_clients, client_ip) {
+        return None;
+    }
+    let timeout = parse_duration(value.trim()).filter(|timeout| !timeout.is_zero())?;
+    Some(timeout.min(proxy.max_timeout))
+}
+
+/// Parse `500ms`, `120s`, `2m` or plain seconds
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = value
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((value, ""), |i| value.split_at(i));
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Upstream request for `url`, with Host set from it unless `headers` has
+/// one
+fn upstream_request(
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Request<Body>> {
+    let mut request = Request::builder()
+        .method(method.clone())
+        .uri(Uri::from_str(url.as_ref())?);
+    for (name, value) in headers.iter() {
+        request = request.header(name, value);
+    }
+    if !headers.contains_key(header::HOST)
+        && let Some(host) = url.host_str()
+    {
+        let host_with_port = if let Some(port) = url.port() {
+            format!("{}:{}", host, port)
+        } else {
+            host.to_string()
+        };
+        request = request.header("host", host_with_port);
+    }
+    Ok(request.body(body)?)
+}
+
+/// Wait for the first of two requests to succeed, cancelling the other one;
+/// `true` if it was the second
+async fn first_success<T, E>(
+    first: impl Future<Output = Result<T, E>>,
+    second: impl Future<Output = Result<T, E>>,
+) -> (Result<T, E>, bool) {
+    tokio::pin!(first, second);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(_) => (result, false),
+            Err(_) => (second.await, true),
+        },
+        result = &mut second => match result {
+            Ok(_) => (result, true),
+            Err(_) => (first.await, false),
+        },
+    }
+}
+
+/// Whether the client declared it can receive trailers (`TE: trailers`)
+fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("te")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// A location given for `target_url`, as the client has to request it
+/// through the proxy, `None` if it can stay as it is
+fn client_location(
+    proxy: &Proxy,
+    source: target::Source,
+    location: &str,
+    request_headers: &HeaderMap,
+    request_uri: &Uri,
+    local_addr: SocketAddr,
+    target_url: &Url,
+) -> Option<String> {
+    match source {
+        target::Source::Path | target::Source::Route => process_location_header(
+            location,
+            request_headers,
+            request_uri,
+            local_addr,
+            target_url,
+        ),
+        // Relative locations already resolve against the virtual host
+        target::Source::VirtualHost => Url::parse(location).ok().and_then(|location| {
+            let host = request_headers.get("host")?.to_str().ok()?;
+            let scheme = request_uri.scheme_str().unwrap_or("http");
+            proxy.targets.vhost_location(&location, scheme, host)
+        }),
+        // The client picks the target itself, relative locations keep
+        // working against the same header
+        target::Source::Header => None,
+    }
+}
+
+fn process_location_header(
+    location: &str,
+    request_headers: &HeaderMap,
+    request_uri: &Uri,
+    local_addr: SocketAddr,
+    target_url: &Url,
+) -> Option<String> {
+    // If Location is a complete URL, return proxy version directly
+    if location.starts_with("http://") || location.starts_with("https://") {
+        if let Ok(_location_url) = Url::parse(location) {
+            // Get request origin
+            let request_origin = get_request_origin(request_headers, request_uri, local_addr);
+            return Some(format!("{}/{}", request_origin, location));
+        }
+    } else if location.starts_with('/') {
+        // Relative path, need to combine origin
+        let request_origin = get_request_origin(request_headers, request_uri, local_addr);
+        let target_origin = format!(
+            "{}://{}",
+            target_url.scheme(),
+            target_url.host_str().unwrap_or("")
         );
 
         if let Some(port) = target_url.port() {
@@ -198,61 +1907,2000 @@ fn process_location_header(
     None
 }
 
-fn get_request_origin(headers: &hyper::HeaderMap, uri: &Uri) -> String {
+fn get_request_origin(headers: &HeaderMap, uri: &Uri, local_addr: SocketAddr) -> String {
     // First try to get from Origin header
-    if let Some(origin_header) = headers.get("origin") {
-        if let Ok(origin_str) = origin_header.to_str() {
-            return origin_str.to_string();
-        }
+    if let Some(origin_header) = headers.get("origin")
+        && let Ok(origin_str) = origin_header.to_str()
+    {
+        return origin_str.to_string();
     }
 
     // If no Origin header, build from request
-    let scheme = if let Some(scheme) = uri.scheme_str() {
-        scheme
-    } else {
-        "http" // Default protocol
+    local_origin(headers, uri, local_addr)
+}
+
+/// Origin the client used to reach the proxy, from the request itself
+fn local_origin(headers: &HeaderMap, uri: &Uri, local_addr: SocketAddr) -> String {
+    let scheme = uri.scheme_str().unwrap_or("http");
+
+    // HTTP/1.0 clients may omit Host, fall back to the address they connected to
+    let host = match headers.get("host").and_then(|h| h.to_str().ok()) {
+        Some(host) => host.to_string(),
+        None => local_addr.to_string(),
     };
 
-    let host = if let Some(host_header) = headers.get("host") {
-        if let Ok(host_str) = host_header.to_str() {
-            host_str
-        } else {
-            "localhost:1234" // Default value
+    format!("{}://{}", scheme, host)
+}
+
+/// Parse a request method, case-insensitively
+fn parse_method(method: &str) -> Result<Method> {
+    Method::from_str(&method.trim().to_ascii_uppercase())
+        .map_err(|_| anyhow!("invalid method: {}", method))
+}
+
+/// Every option with its value and where the value came from, as TOML
+/// (`--print-config`)
+fn effective_options(command: &clap::Command, matches: &clap::ArgMatches) -> String {
+    let mut out = String::from("# Options, from the command line or defaults\n");
+    for arg in command.get_arguments() {
+        let (Some(long), id) = (arg.get_long(), arg.get_id().as_str()) else {
+            continue;
+        };
+        if matches!(
+            arg.get_action(),
+            clap::ArgAction::Help | clap::ArgAction::Version
+        ) || ["print-config", "version", "verbose"].contains(&long)
+        {
+            continue;
         }
+        let source = match matches.value_source(id) {
+            Some(clap::parser::ValueSource::CommandLine) => "command line",
+            Some(clap::parser::ValueSource::EnvVariable) => "environment",
+            Some(_) => "default",
+            None => {
+                out += &format!("# {} is not set\n", long);
+                continue;
+            }
+        };
+        let value = if arg.get_action().takes_values() {
+            let values: Vec<String> = matches
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|value| toml_string(&value.to_string_lossy()))
+                .collect();
+            if matches!(arg.get_action(), clap::ArgAction::Append) || values.len() > 1 {
+                format!("[{}]", values.join(", "))
+            } else {
+                values.concat()
+            }
+        } else {
+            matches.get_flag(id).to_string()
+        };
+        out += &format!("{} = {} # {}\n", long, value, source);
+    }
+    out
+}
+
+/// `value` as a TOML basic string
+fn toml_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Validate `--local-prefix`, dropping any trailing slash
+fn parse_local_prefix(prefix: &str) -> Result<String> {
+    let prefix = prefix.trim_end_matches('/');
+    if !prefix.starts_with('/') {
+        return Err(anyhow!("must start with '/' and not be the root"));
+    }
+    Ok(prefix.to_string())
+}
+
+/// Content of `/robots.txt`, `None` when it is proxied
+fn robots_txt(args: &Args) -> Result<Option<Bytes>> {
+    if args.no_robots_txt {
+        return Ok(None);
+    }
+    let content = match &args.robots_txt {
+        Some(path) => std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?
+            .into(),
+        None => Bytes::from_static(local::ROBOTS_TXT.as_bytes()),
+    };
+    Ok(Some(content))
+}
+
+/// Build the response filter pipeline from `--filter` options
+fn build_filters(args: &Args) -> Result<filter::Pipeline> {
+    let mut pipeline = filter::Pipeline::default();
+    if args.image_optimize {
+        let types: Vec<String> = images::TYPES.iter().map(|t| t.to_string()).collect();
+        pipeline.register(filter::ContentTypes::new(&types), images::ImageFilter);
+    }
+    for option in &args.filter {
+        let (types, command) = filter::CommandFilter::parse(option)?;
+        pipeline.register(types, command);
+    }
+    Ok(pipeline)
+}
+
+/// Build the upstream client shared by all connections
+async fn build_client(args: &Args, resolve: &[String], pins: pin::Pins) -> Result<HttpClient> {
+    let family = if args.ipv4_only {
+        dns::AddrFamily::Ipv4Only
+    } else if args.prefer_ipv6 {
+        dns::AddrFamily::PreferIpv6
     } else {
-        "localhost:1234" // Default value
+        dns::AddrFamily::Any
     };
+    let stale_max_age =
+        (args.dns_stale_max_age > 0).then(|| Duration::from_secs(args.dns_stale_max_age));
+    let resolver =
+        dns::Resolver::new(&args.dns_server, args.doh.as_deref(), family, stale_max_age).await?;
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false);
+    bind_outbound(&mut http, args)?;
+    socket_options(args).apply_to_connector(&mut http);
+    http.set_happy_eyeballs_timeout(
+        (args.happy_eyeballs_timeout > 0)
+            .then(|| Duration::from_millis(args.happy_eyeballs_timeout)),
+    );
+    // Later entries win, so the command line over the config file
+    let resolve: Vec<String> = resolve.iter().chain(&args.resolve).cloned().collect();
+    let overrides = dns::Overrides::parse(&resolve)?;
+    let limiter = connect::HostLimiter::new(
+        args.max_conns_per_host,
+        Duration::from_millis(args.max_conns_wait),
+    );
+    let connector = connect::Connector::new(http, overrides, limiter);
+    let vias = connect::Vias::default();
+    // Refuse cleartext at the connector too, not only by rewriting targets
+    let routed = connect::Routed::new(
+        connector,
+        tls_connector(args)?,
+        args.upgrade_insecure,
+        vias.clone(),
+    );
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder.pool_timer(TokioTimer::new()).pool_idle_timeout(
+        (args.pool_idle_timeout > 0).then(|| Duration::from_secs(args.pool_idle_timeout)),
+    );
+    if let Some(max_idle) = args.pool_max_idle_per_host {
+        builder.pool_max_idle_per_host(max_idle);
+    }
+    let max_lifetime =
+        (args.conn_max_lifetime > 0).then(|| Duration::from_secs(args.conn_max_lifetime));
+    Ok(connect::Client::new(
+        builder.build(pin::Pinned::new(routed, pins)),
+        max_lifetime,
+        vias,
+    ))
+}
 
-    format!("{}://{}", scheme, host)
+/// TLS settings of upstream connections
+fn tls_connector(args: &Args) -> Result<native_tls::TlsConnector> {
+    if let (Some(min), Some(max)) = (args.tls_min_version, args.tls_max_version)
+        && min > max
+    {
+        return Err(anyhow!("--tls-min-version is above --tls-max-version"));
+    }
+    let mut tls = native_tls::TlsConnector::builder();
+    match args.tls_min_version {
+        Some(TlsVersion::Tls13) => {
+            return Err(anyhow!(
+                "--tls-min-version 1.3 is not supported by the TLS library"
+            ));
+        }
+        Some(min) => {
+            tls.min_protocol_version(min.protocol());
+        }
+        None => {}
+    }
+    if let Some(max) = args.tls_max_version {
+        tls.max_protocol_version(max.protocol());
+    }
+    Ok(tls.build()?)
+}
+
+fn socket_options(args: &Args) -> connect::SocketOptions {
+    connect::SocketOptions {
+        nodelay: args.tcp_nodelay,
+        keepalive: args.tcp_keepalive.map(Duration::from_secs),
+        keepalive_interval: args.tcp_keepalive_interval.map(Duration::from_secs),
+        send_buffer_size: args.send_buffer_size,
+        recv_buffer_size: args.recv_buffer_size,
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Apply `--outbound-bind` and `--outbound-interface` to the connector
+fn bind_outbound(http: &mut HttpConnector<dns::Resolver>, args: &Args) -> Result<()> {
+    let mut ipv4 = None;
+    let mut ipv6 = None;
+    for addr in &args.outbound_bind {
+        let duplicate = match addr {
+            IpAddr::V4(v4) => ipv4.replace(*v4).is_some(),
+            IpAddr::V6(v6) => ipv6.replace(*v6).is_some(),
+        };
+        if duplicate {
+            return Err(anyhow!(
+                "At most one IPv4 and one IPv6 --outbound-bind address may be given"
+            ));
+        }
+    }
+    match (ipv4, ipv6) {
+        (Some(v4), Some(v6)) => http.set_local_addresses(v4, v6),
+        (v4, v6) => http.set_local_address(v4.map(IpAddr::V4).or(v6.map(IpAddr::V6))),
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(interface) = &args.outbound_interface {
+        http.set_interface(interface.as_str());
+    }
+
+    Ok(())
+}
+
+/// Build the inbound HTTP/1 connection settings
+fn http_server(args: &Args) -> http1::Builder {
+    let mut server = http1::Builder::new();
+    server
+        .timer(TokioTimer::new())
+        .keep_alive(!args.no_keep_alive)
+        .max_headers(args.max_headers)
+        .header_read_timeout(
+            (args.header_read_timeout > 0).then(|| Duration::from_secs(args.header_read_timeout)),
+        );
+    if let Some(max_buf_size) = args.max_buf_size {
+        server.max_buf_size(max_buf_size as usize);
+    }
+    server
+}
+
+async fn serve_connection(server: &http1::Builder, stream: TcpStream, proxy: Arc<Proxy>) {
+    let (local_addr, peer_addr) = match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(peer)) => (local, peer),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to get connection addresses: {}", e);
+            return;
+        }
+    };
+    if proxy.bans.is_banned(peer_addr.ip()) {
+        tracing::debug!("Refusing connection from banned {}", peer_addr.ip());
+        return;
+    }
+    let state = proxy.clone();
+    let open = state.metrics.connection(peer_addr);
+    let connection = open.connection.clone();
+    let io = TokioIo::new(metrics::Metered::new(stream, connection.clone()));
+    let service = service_fn(move |req| proxy_handler(req, proxy.clone(), local_addr, peer_addr));
+    if let Err(err) = server.serve_connection(io, service).await {
+        error!("Error serving connection: {:?}", err);
+    }
+    tracing::debug!(
+        "Connection from {} closed, {} bytes received, {} bytes sent",
+        peer_addr,
+        connection.received(),
+        connection.sent()
+    );
+}
+
+/// First delay after a failure to accept connections, doubled up to 1.28s
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Whether an accept error only concerns one connection
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.version {
+        if args.verbose {
+            print!("{}", version::verbose());
+        } else {
+            println!("m2proxy {}", version::VERSION);
+        }
+        return Ok(());
+    }
+    if args.print_config {
+        print!("{}", effective_options(&Args::command(), &matches));
+        if let Some(path) = &args.config {
+            let config = config::Config::load(path)?.effective()?;
+            print!("\n# {}\n{}", path.display(), config.to_toml()?);
+        }
+        return Ok(());
+    }
+    match args.command {
+        Some(Subcommand::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Args::command(),
+                "m2proxy",
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Subcommand::Man) => {
+            print!("{}", man::render(Args::command())?);
+            return Ok(());
+        }
+        Some(Subcommand::Ping { addr, timeout }) => {
+            return local::ping(&addr, &args.local_prefix, Duration::from_secs(timeout));
+        }
+        None => {}
+    }
+
     // Initialize tracing with default info level
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
-        )
-        .init();
+    logging::init(args.log_output, args.log_file.as_deref())?;
+
+    let mut runtime = if args.current_thread {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut runtime = tokio::runtime::Builder::new_multi_thread();
+        if let Some(workers) = args.workers {
+            runtime.worker_threads(workers as usize);
+        }
+        runtime
+    };
+    runtime
+        .enable_all()
+        .max_blocking_threads(args.max_blocking_threads as usize)
+        .build()?
+        .block_on(run(args))
+}
 
-    let args = Args::parse();
+async fn run(args: Args) -> Result<()> {
+    let proxy = Arc::new(Proxy::new(&args).await?);
+    let client_limiter = limit::ClientLimiter::new(args.max_conns_per_ip);
+    let metrics = proxy.metrics.clone();
+    tokio::spawn(async move { metrics.push_gauges().await });
 
     let addr = SocketAddr::new(args.host.parse()?, args.port);
     let listener = TcpListener::bind(addr).await?;
 
     info!("Proxy is running on http://{}", addr);
 
+    let server = http_server(&args);
+    let socket_options = socket_options(&args);
+
+    let mut accept_errors = 0;
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => {
+                accept_errors = 0;
+                accepted
+            }
+            // Only this connection failed, e.g. the client gave up
+            Err(e) if is_connection_error(&e) => {
+                tracing::debug!("Failed to accept a connection: {}", e);
+                continue;
+            }
+            // Out of file descriptors or memory, wait for connections to close
+            Err(e) => {
+                let delay = ACCEPT_BACKOFF * 2u32.pow(accept_errors.min(7));
+                accept_errors += 1;
+                error!(
+                    "Failed to accept connections, retrying in {:?}: {}",
+                    delay, e
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+        let Some(guard) = client_limiter.acquire(peer.ip()) else {
+            warn!("Too many connections from {}, dropping", peer.ip());
+            continue;
+        };
+        if let Err(e) = socket_options.apply(&stream) {
+            error!("Failed to set socket options: {}", e);
+        }
+        let proxy = proxy.clone();
+        let server = server.clone();
 
         tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(proxy_handler))
+            serve_connection(&server, stream, proxy).await;
+            drop(guard);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CHUNKED: &str = "HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Connection: keep-alive\r\n\
+        Keep-Alive: timeout=5\r\n\r\n\
+        5\r\nhello\r\n0\r\n\r\n";
+
+    const SIZED: &str = "HTTP/1.1 200 OK\r\n\
+        Content-Length: 5\r\n\
+        Connection: keep-alive\r\n\r\n\
+        hello";
+
+    /// Read one complete HTTP/1.1 request (Content-Length or chunked body)
+    async fn read_request(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+                let body = &request[end + 4..];
+                let complete = if head.contains("transfer-encoding: chunked") {
+                    let last_chunk =
+                        body.starts_with(b"0\r\n") || body.windows(5).any(|w| w == b"\r\n0\r\n");
+                    last_chunk && body.ends_with(b"\r\n\r\n")
+                } else {
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |n| n.trim().parse().unwrap());
+                    body.len() >= length
+                };
+                if complete {
+                    return Some(request);
+                }
+            }
+            let n = stream.read(&mut buf).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Upstream answering every request with a fixed raw response
+    async fn fixed_upstream(response: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Upstream answering with `responses` in turn, repeating the last one
+    async fn sequence_upstream(responses: &'static [&'static str]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let next = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let next = next.clone();
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        let response = responses[i.min(responses.len() - 1)];
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Upstream accepting connections but never answering
+    async fn stalled_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                streams.push(listener.accept().await.unwrap().0);
+            }
+        });
+        addr
+    }
+
+    /// Upstream answering with a fixed raw response and reporting each raw request
+    async fn recording_upstream(
+        response: Vec<u8>,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (tx, response) = (tx.clone(), response.clone());
+                tokio::spawn(async move {
+                    while let Some(request) = read_request(&mut stream).await {
+                        tx.send(request).unwrap();
+                        if stream.write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Upstream answering every request by echoing the raw request back
+    async fn echo_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let request = read_request(&mut stream).await.unwrap();
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        request.len()
+                    );
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    stream.write_all(&request).await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    /// Start the proxy with default options on an ephemeral port
+    async fn start_proxy() -> SocketAddr {
+        start_proxy_with(&[]).await
+    }
+
+    /// Start the proxy with extra command line options on an ephemeral port
+    async fn start_proxy_with(options: &[&str]) -> SocketAddr {
+        let args = Args::parse_from(["m2proxy"].iter().chain(options));
+        let proxy = Arc::new(Proxy::new(&args).await.unwrap());
+        let server = http_server(&args);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (server, proxy) = (server.clone(), proxy.clone());
+                tokio::spawn(async move { serve_connection(&server, stream, proxy).await });
+            }
+        });
+        addr
+    }
+
+    /// Config file of a test proxy, removed on drop
+    struct TempConfig(std::path::PathBuf);
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Start a proxy reading `config` as its config file, with `options`
+    async fn start_proxy_with_config(config: &str, options: &[&str]) -> SocketAddr {
+        static CONFIGS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = CONFIGS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let file = TempConfig(std::env::temp_dir().join(format!(
+            "m2proxy-{}-{}.toml",
+            std::process::id(),
+            n
+        )));
+        std::fs::write(&file.0, config).unwrap();
+        let mut args = vec!["--config", file.0.to_str().unwrap()];
+        args.extend(options);
+        // Only read on start, the file is removed once the proxy runs
+        start_proxy_with(&args).await
+    }
+
+    /// Read from the proxy until the response ends with `terminator`
+    async fn read_response(stream: &mut TcpStream, terminator: &str) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !response.ends_with(terminator.as_bytes()) {
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
                 .await
-            {
-                error!("Error serving connection: {:?}", err);
+                .expect("response timed out")
+                .unwrap();
+            assert!(n > 0, "connection closed before the full response");
+            response.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(response).unwrap().to_ascii_lowercase()
+    }
+
+    /// Assert the proxy closes the connection without sending more data
+    async fn assert_closed(stream: &mut TcpStream) {
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn completion_scripts_cover_the_options() {
+        for shell in [clap_complete::Shell::Bash, clap_complete::Shell::Fish] {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut Args::command(), "m2proxy", &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("coalesce"), "{}", script);
+            assert!(script.contains("completions"), "{}", script);
+        }
+    }
+
+    #[test]
+    fn effective_options_show_their_source() {
+        let matches = Args::command()
+            .try_get_matches_from(["m2proxy", "-p", "8080", "--dns-server", "1.1.1.1"])
+            .unwrap();
+        let options = effective_options(&Args::command(), &matches);
+        assert!(
+            options.contains("\nport = \"8080\" # command line\n"),
+            "{}",
+            options
+        );
+        assert!(
+            options.contains("\nhost = \"0.0.0.0\" # default\n"),
+            "{}",
+            options
+        );
+        assert!(
+            options.contains("\ndns-server = [\"1.1.1.1\"] # command line\n"),
+            "{}",
+            options
+        );
+        assert!(
+            options.contains("\ncompress = false # default\n"),
+            "{}",
+            options
+        );
+        assert!(options.contains("\n# config is not set\n"), "{}", options);
+    }
+
+    #[tokio::test]
+    async fn ping_checks_the_health_endpoint() {
+        let proxy = start_proxy().await.to_string();
+        let ping = |addr: String| {
+            tokio::task::spawn_blocking(move || {
+                local::ping(&addr, local::DEFAULT_PREFIX, Duration::from_secs(5))
+            })
+        };
+        ping(proxy.clone()).await.unwrap().unwrap();
+        // Not the proxy, its local endpoints are missing
+        let upstream = fixed_upstream("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(ping(upstream.to_string()).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn http10_request_without_host() {
+        let upstream = fixed_upstream(CHUNKED).await;
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET /http://{}/ HTTP/1.0\r\n\r\n", upstream);
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        // Length is unknown and chunked is not available, so the body is
+        // delimited by closing the connection
+        let response = read_response(&mut stream, "hello").await;
+        assert!(response.starts_with("http/1.0 200"), "{}", response);
+        assert!(!response.contains("transfer-encoding"), "{}", response);
+        assert!(!response.contains("keep-alive"), "{}", response);
+        assert_closed(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn http10_keep_alive() {
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/ HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            upstream
+        );
+        for _ in 0..2 {
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut stream, "hello").await;
+            assert!(response.starts_with("http/1.0 200"), "{}", response);
+            assert!(
+                response.contains("connection: keep-alive\r\n"),
+                "{}",
+                response
+            );
+            assert!(response.contains("content-length: 5\r\n"), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn expect_100_continue() {
+        let upstream = echo_upstream().await;
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nContent-Length: 5\r\n\
+             Expect: 100-continue\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        // The interim response arrives before any body byte is sent
+        let mut buf = [0u8; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("no interim response")
+            .unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 100 Continue\r\n\r\n"));
+
+        stream.write_all(b"hello").await.unwrap();
+        let response = read_response(&mut stream, "hello").await;
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+        assert!(!response.contains("expect:"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn unknown_expectation_is_refused() {
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream
+            .write_all(b"GET /http://127.0.0.1:1/ HTTP/1.1\r\nHost: proxy\r\nExpect: magic\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 417"));
+    }
+
+    #[tokio::test]
+    async fn response_trailers_are_forwarded() {
+        let upstream = fixed_upstream(
+            "HTTP/1.1 200 OK\r\n\
+             Transfer-Encoding: chunked\r\n\
+             Trailer: x-checksum\r\n\r\n\
+             5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n",
+        )
+        .await;
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nTE: trailers\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_response(&mut stream, "\r\n0\r\nx-checksum: abc\r\n\r\n").await;
+        assert!(response.contains("trailer: x-checksum\r\n"), "{}", response);
+        assert!(
+            response.contains("transfer-encoding: chunked\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn request_trailers_are_forwarded() {
+        let upstream = echo_upstream().await;
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nTransfer-Encoding: chunked\r\n\
+             Trailer: x-checksum\r\n\r\n5\r\nhello\r\n0\r\nx-checksum: abc\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let response = read_response(&mut stream, "x-checksum: abc\r\n\r\n").await;
+        assert!(
+            response.contains("\r\ntrailer: x-checksum\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_responses_pass_through() {
+        // Arbitrary non-UTF-8 payload, the proxy must not look inside it
+        let payload: &[u8] = &[0x1f, 0x8b, 0x08, 0x00, 0xff, 0xfe, 0x00, 0x80, 0x7f, 0x01];
+
+        for encoding in ["gzip", "br", "zstd"] {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\
+                 Vary: Accept-Encoding\r\n\r\n",
+                encoding,
+                payload.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(payload);
+            let (upstream, mut requests) = recording_upstream(response).await;
+            let proxy = start_proxy().await;
+
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let request = format!(
+                "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nAccept-Encoding: gzip, br\r\n\
+                 Connection: close\r\n\r\n",
+                upstream
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut raw = Vec::new();
+            stream.read_to_end(&mut raw).await.unwrap();
+
+            let upstream_request = String::from_utf8(requests.recv().await.unwrap()).unwrap();
+            assert!(
+                upstream_request
+                    .to_ascii_lowercase()
+                    .contains("accept-encoding: gzip, br\r\n"),
+                "{}",
+                upstream_request
+            );
+
+            let end = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let head = String::from_utf8_lossy(&raw[..end]).to_ascii_lowercase();
+            assert!(
+                head.contains(&format!("content-encoding: {}\r\n", encoding)),
+                "{}",
+                head
+            );
+            assert!(
+                head.contains(&format!("content-length: {}\r\n", payload.len())),
+                "{}",
+                head
+            );
+            assert!(head.contains("vary: accept-encoding\r\n"), "{}", head);
+            assert_eq!(&raw[end + 4..], payload);
+        }
+    }
+
+    #[test]
+    fn origin_falls_back_to_local_addr() {
+        let local_addr: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let uri: Uri = "/https://example.com/".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            get_request_origin(&headers, &uri, local_addr),
+            "http://192.0.2.1:1234"
+        );
+
+        headers.insert("host", "proxy.example".parse().unwrap());
+        assert_eq!(
+            get_request_origin(&headers, &uri, local_addr),
+            "http://proxy.example"
+        );
+    }
+
+    #[test]
+    fn hop_headers_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, x-custom".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("x-custom", "1".parse().unwrap());
+        headers.insert("trailer", "x-checksum".parse().unwrap());
+
+        remove_hop_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("trailer"));
+    }
+
+    #[tokio::test]
+    async fn decompress_for_identity_clients() {
+        // gzip of "hello"
+        const GZIP_HELLO: [u8; 25] = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00, 0x00,
+        ];
+        let mut response = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\
+            Content-Length: 25\r\n\r\n"
+            .to_vec();
+        response.extend_from_slice(&GZIP_HELLO);
+        let (upstream, _requests) = recording_upstream(response).await;
+        let proxy = start_proxy_with(&["--decompress"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nAccept-Encoding: identity\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut stream, "\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+        assert!(!response.contains("content-encoding"), "{}", response);
+        assert!(response.contains("vary: accept-encoding"), "{}", response);
+        assert!(response.contains("hello"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn command_filter_rewrites_matching_bodies() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\
+            Content-Length: 5\r\n\r\nhello"
+            .to_vec();
+        let (upstream, _requests) = recording_upstream(response).await;
+        let proxy = start_proxy_with(&["--filter", "text/*=tr a-z A-Z"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(!response.contains("content-length"), "{}", response);
+        assert!(response.contains("HELLO"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn query_is_forwarded_without_image_params() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        let proxy = start_proxy_with(&["--image-optimize"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/a.png?page=2&w=800&q=75 HTTP/1.1\r\nHost: proxy\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        read_response(&mut stream, "hello").await;
+
+        let upstream_request = String::from_utf8(requests.recv().await.unwrap()).unwrap();
+        assert!(
+            upstream_request.starts_with("GET /a.png?page=2 HTTP/1.1\r\n"),
+            "{}",
+            upstream_request
+        );
+    }
+
+    #[tokio::test]
+    async fn landing_page() {
+        let proxy = start_proxy().await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET /?format=json HTTP/1.1\r\nHost: proxy.test\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(
+            response.contains("content-type: application/json"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\"example\":\"http://proxy.test/https://example.com/path\""),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn local_paths_are_never_proxied() {
+        let proxy = start_proxy().await;
+
+        for (path, status) in [("/__m2proxy/health", "200"), ("/__m2proxy/unknown", "404")] {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+                path
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut raw = Vec::new();
+            stream.read_to_end(&mut raw).await.unwrap();
+            let response = String::from_utf8(raw).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn disallowed_methods_are_refused() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        let proxy = start_proxy_with(&["--allow-methods", "get,head"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        assert!(response.contains("allow: GET, HEAD\r\n"), "{}", response);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn other_origins_are_refused() {
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with(&["--allow-origins", "https://app.example.com"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        for (origin, terminator) in [
+            ("https://app.example.com", "hello"),
+            ("https://evil.example.com", "Origin not allowed"),
+        ] {
+            let request = format!(
+                "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nOrigin: {}\r\n\r\n",
+                upstream, origin
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut stream, terminator).await;
+            let status = if terminator == "hello" { "200" } else { "403" };
+            assert!(
+                response.starts_with(&format!("http/1.1 {}", status)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn moved_targets_are_redirected() {
+        let proxy = start_proxy_with_config(
+            "[[redirect]]\nhost = \"old.example\"\npattern = \"^/pkg/(.*)$\"\n\
+             to = \"https://new.example/packages/$1\"\n",
+            &[],
+        )
+        .await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream
+            .write_all(b"GET /https://old.example/pkg/a.tgz?v=1 HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut stream, "\r\n\r\n").await;
+        assert!(response.starts_with("http/1.1 308"), "{}", response);
+        assert!(
+            response.contains("location: http://proxy/https://new.example/packages/a.tgz?v=1\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn user_agents_are_filtered() {
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with_config(
+            "[[user_agent]]\npattern = \"(?i)bot\"\naction = \"block\"\n\n\
+             [[user_agent]]\npattern = \"^curl/\"\naction = \"throttle\"\nrate = 1\n",
+            &[],
+        )
+        .await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        for (user_agent, terminator) in [
+            ("ScraperBot/1.0", "User agent not allowed"),
+            ("curl/8.5", "hello"),
+            ("curl/8.5", "Too many requests"),
+            ("pip/24.0", "hello"),
+        ] {
+            let request = format!(
+                "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nUser-Agent: {}\r\n\r\n",
+                upstream, user_agent
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut stream, terminator).await;
+            if terminator == "Too many requests" {
+                assert!(response.starts_with("http/1.1 429"), "{}", response);
+                assert!(response.contains("retry-after: "), "{}", response);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_refused_by_type() {
+        const DISGUISED: &str = "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\
+            Content-Length: 4\r\n\r\nMZ\x00\x00";
+        let disguised = fixed_upstream(DISGUISED).await;
+        let plain = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with(&[
+            "--deny-content-types",
+            "application/x-dosexec",
+            "--deny-type-mismatch",
+        ])
+        .await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", disguised);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut stream, "Response type not allowed").await;
+        assert!(response.starts_with("http/1.1 403"), "{}", response);
+
+        let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", plain);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut stream, "hello").await;
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_scanned() {
+        let upstream = echo_upstream().await;
+        let proxy =
+            start_proxy_with(&["--scan-command", "! grep -q secret", "--scan-min-size", "8"]).await;
+
+        for (body, status) in [
+            ("public data", "200"),
+            ("top secret", "403"),
+            // Below --scan-min-size
+            ("secret", "200"),
+        ] {
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let request = format!(
+                "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                upstream,
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut raw = Vec::new();
+            stream.read_to_end(&mut raw).await.unwrap();
+            let response = String::from_utf8(raw).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}",
+                response
+            );
+            if status == "200" {
+                assert!(response.ends_with(body), "{}", response);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn scanned_uploads_are_validated_and_timed_first() {
+        let upstream = echo_upstream().await;
+        let proxy = start_proxy_with(&["--scan-command", "cat", "--body-read-timeout", "1"]).await;
+
+        // Refused before hyper is asked to read the body
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nContent-Length: 5\r\n\
+             Expect: magic\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 417"));
+
+        // A stalled upload times out while it is scanned
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/ HTTP/1.1\r\nHost: proxy\r\nContent-Length: 10\r\n\r\nhello",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut raw))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn repeatedly_denied_clients_are_banned() {
+        let proxy = start_proxy_with(&["--allow-methods", "get", "--ban-after", "2"]).await;
+        let request = "POST /http://example.com/ HTTP/1.1\r\nHost: proxy\r\n\
+            Content-Length: 0\r\n\r\n";
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let first = read_response(&mut stream, "Method not allowed").await;
+        assert!(first.starts_with("http/1.1 405"), "{}", first);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let second = String::from_utf8(raw).unwrap();
+        assert!(second.contains("connection: close\r\n"), "{}", second);
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let _ = stream.write_all(request.as_bytes()).await;
+        let mut raw = Vec::new();
+        let _ = stream.read_to_end(&mut raw).await;
+        assert!(raw.is_empty(), "{}", String::from_utf8_lossy(&raw));
+    }
+
+    #[tokio::test]
+    async fn head_keeps_representation_headers() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Encoding: gzip\r\n\r\n";
+        let (upstream, _requests) = recording_upstream(response.to_vec()).await;
+        let proxy = start_proxy_with(&["--decompress"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "HEAD /http://{}/ HTTP/1.1\r\nHost: proxy\r\nAccept-Encoding: identity\r\n\r\n",
+            upstream
+        );
+        for _ in 0..2 {
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_response(&mut stream, "\r\n\r\n").await;
+            assert!(response.starts_with("http/1.1 200"), "{}", response);
+            assert!(response.contains("content-length: 5\r\n"), "{}", response);
+            assert!(
+                response.contains("content-encoding: gzip\r\n"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn default_scheme() {
+        let upstream = fixed_upstream(SIZED).await;
+        for (scheme, status) in [("http", "200"), ("reject", "400")] {
+            let proxy = start_proxy_with(&["--default-scheme", scheme]).await;
+            let mut stream = TcpStream::connect(proxy).await.unwrap();
+            let request = format!(
+                "GET /{}/ HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+                upstream
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut raw = Vec::new();
+            stream.read_to_end(&mut raw).await.unwrap();
+            let response = String::from_utf8(raw).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_insecure_never_sends_cleartext() {
+        // Upstream reporting the first bytes it receives, then hanging up
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            let _ = tx.send(buf[..n].to_vec());
+        });
+        let proxy = start_proxy_with(&["--upgrade-insecure"]).await;
+
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+            upstream
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        // A TLS ClientHello record, not a request line
+        assert_eq!(rx.await.unwrap()[0], 0x16);
+    }
+
+    #[tokio::test]
+    async fn hedged_requests_race_a_second_upstream() {
+        let stalled_addr = stalled_upstream().await;
+        let fast = fixed_upstream(SIZED).await;
+
+        let proxy = start_proxy_with_config(
+            &format!(
+                "[[route]]\n\
+                 path = \"/m/{{path..}}\"\n\
+                 upstreams = [\"http://{}/{{path..}}\", \"http://{}/{{path..}}\"]\n\
+                 balance = \"failover\"\n\
+                 hedge_after = 50\n",
+                stalled_addr, fast
+            ),
+            &[],
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"GET /m/x HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut client, "hello").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+    }
+
+    #[tokio::test]
+    async fn segmented_download() {
+        // Serves a 1000 byte body, honoring single byte ranges
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let content: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let ranges = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (served, ranges_seen) = (content.clone(), ranges.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (content, ranges) = (served.clone(), ranges_seen.clone());
+                tokio::spawn(async move {
+                    while let Some(request) = read_request(&mut stream).await {
+                        let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                        let range = request.lines().find_map(|line| {
+                            let (start, end) =
+                                line.strip_prefix("range: bytes=")?.split_once('-')?;
+                            Some((
+                                start.parse::<usize>().ok()?,
+                                end.trim().parse::<usize>().ok()?,
+                            ))
+                        });
+                        let head = match range {
+                            Some((start, end)) => {
+                                ranges.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                format!(
+                                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/1000\r\n\
+                                     Content-Length: {}\r\n\r\n",
+                                    start,
+                                    end,
+                                    end - start + 1
+                                )
+                                .into_bytes()
+                                .into_iter()
+                                .chain(content[start..=end].iter().copied())
+                                .collect::<Vec<u8>>()
+                            }
+                            None => b"HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nETag: \"v1\"\r\n\
+                                      Content-Length: 1000\r\n\r\n"
+                                .iter()
+                                .chain(content.iter())
+                                .copied()
+                                .collect(),
+                        };
+                        if stream.write_all(&head).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let proxy =
+            start_proxy_with(&["--segmented-download", "4", "--segment-min-size", "100"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET /http://{}/file HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+                    upstream
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        assert_eq!(&response[end + 4..], &content[..]);
+        assert_eq!(ranges.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn broken_bodies_are_resumed() {
+        // Breaks off the full response after 5 bytes, serves the rest as a range
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    while let Some(request) = read_request(&mut stream).await {
+                        let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                        if request.contains("range: bytes=5-")
+                            && request.contains("if-range: \"v1\"")
+                        {
+                            let _ = stream
+                                .write_all(
+                                    b"HTTP/1.1 206 Partial Content\r\n\
+                                      Content-Range: bytes 5-9/10\r\nContent-Length: 5\r\n\r\nworld",
+                                )
+                                .await;
+                        } else {
+                            let _ = stream
+                                .write_all(
+                                    b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\n\
+                                      Content-Length: 10\r\n\r\nhello",
+                                )
+                                .await;
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        let proxy = start_proxy_with(&["--resume-attempts", "1"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET /http://{}/file HTTP/1.1\r\nHost: proxy\r\n\r\n",
+                    upstream
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let response = read_response(&mut client, "helloworld").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("120s"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1h"), None);
+        assert_eq!(parse_duration("s"), None);
+    }
+
+    #[tokio::test]
+    async fn trusted_clients_set_the_upstream_timeout() {
+        let upstream = stalled_upstream().await;
+        let proxy = start_proxy_with(&["--trusted-clients", "127.0.0.0/8"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\nX-Proxy-Timeout: 100ms\r\n\r\n",
+                    upstream
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let response = read_response(&mut client, "timed out").await;
+        assert!(response.starts_with("http/1.1 504 gateway timeout"));
+    }
+
+    #[tokio::test]
+    async fn bytes_are_counted_per_connection_and_target() {
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with(&["--trusted-clients", "127.0.0.0/8"]).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+        client.write_all(request.as_bytes()).await.unwrap();
+        read_response(&mut client, "hello").await;
+        let connections = "GET /__m2proxy/connections HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        client.write_all(connections.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "]}").await;
+        let received = format!("\"received_bytes\":{},", request.len() + connections.len());
+        assert!(response.contains(&received), "{}", response);
+        assert!(response.contains("\"peer\":\"127.0.0.1:"), "{}", response);
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                b"GET /__m2proxy/metrics HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut raw = Vec::new();
+        client.read_to_end(&mut raw).await.unwrap();
+        let metrics = String::from_utf8(raw).unwrap();
+        assert!(
+            metrics.contains("m2proxy_target_sent_bytes_total{host=\"127.0.0.1\"} 5\n"),
+            "{}",
+            metrics
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_honor_retry_after() {
+        const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\n\
+            Retry-After: 0\r\nContent-Length: 0\r\n\r\n";
+        const LATER: &str = "HTTP/1.1 503 Service Unavailable\r\n\
+            Retry-After: 3600\r\nContent-Length: 0\r\n\r\n";
+
+        let upstream = sequence_upstream(&[UNAVAILABLE, SIZED]).await;
+        let proxy = start_proxy_with(&["--retries", "2"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "hello").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+
+        // Waiting an hour exceeds --retry-max-wait, the 503 is passed through
+        let upstream = sequence_upstream(&[LATER, SIZED]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "\r\n\r\n").await;
+        assert!(response.starts_with("http/1.1 503 service unavailable"));
+        assert!(response.contains("retry-after: 3600"));
+    }
+
+    #[tokio::test]
+    async fn target_access_is_audited() {
+        let log = std::env::temp_dir().join(format!("m2proxy-test-{}.audit", std::process::id()));
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with(&["--audit-log", log.to_str().unwrap()]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "POST /http://{}/a?token=abc HTTP/1.1\r\nHost: proxy\r\n\
+             X-Request-Id: audited\r\nContent-Length: 3\r\n\r\nabc",
+            upstream
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        read_response(&mut client, "hello").await;
+
+        let mut lines = String::new();
+        for _ in 0..50 {
+            lines = std::fs::read_to_string(&log).unwrap();
+            if !lines.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&log).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(lines.trim_end()).unwrap();
+        assert_eq!(entry["client"], "127.0.0.1");
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(
+            entry["url"],
+            format!("http://{}/a?token=%5Bredacted%5D", upstream)
+        );
+        assert_eq!(entry["request_id"], "audited");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["received_bytes"], 3);
+        assert_eq!(entry["sent_bytes"], 5);
+    }
+
+    #[tokio::test]
+    async fn exchanges_are_recorded() {
+        let har = std::env::temp_dir().join(format!("m2proxy-test-{}.har", std::process::id()));
+        let upstream = fixed_upstream(SIZED).await;
+        let proxy = start_proxy_with(&["--record", har.to_str().unwrap()]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://{}/a?token=abc&page=2 HTTP/1.1\r\nHost: proxy\r\n\
+             Authorization: Bearer abc\r\n\r\n",
+            upstream
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        read_response(&mut client, "hello").await;
+
+        let mut entries = Vec::new();
+        for _ in 0..50 {
+            let har: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&har).unwrap()).unwrap();
+            entries = har["log"]["entries"].as_array().unwrap().clone();
+            if !entries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        std::fs::remove_file(&har).unwrap();
+        let entry = &entries[0];
+        assert_eq!(
+            entry["request"]["url"],
+            format!("http://{}/a?token=%5Bredacted%5D&page=2", upstream)
+        );
+        let authorization = entry["request"]["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["name"] == "authorization")
+            .unwrap();
+        assert_eq!(authorization["value"], "[redacted]");
+        assert_eq!(entry["response"]["status"], 200);
+        assert_eq!(entry["response"]["content"]["text"], "hello");
+    }
+
+    #[tokio::test]
+    async fn recordings_are_replayed() {
+        let har = std::env::temp_dir().join(format!("m2proxy-replay-{}.har", std::process::id()));
+        // 192.0.2.0/24 is never routed, only the recording can answer
+        let recording = serde_json::json!({ "log": { "entries": [{
+            "request": { "method": "GET", "url": "http://192.0.2.1/a?key=%5Bredacted%5D" },
+            "response": {
+                "status": 200,
+                "headers": [
+                    { "name": "Content-Length", "value": "999" },
+                    { "name": "X-Recorded", "value": "1" },
+                ],
+                "content": { "text": "recorded" },
+            },
+        }] } });
+        std::fs::write(&har, recording.to_string()).unwrap();
+        let proxy = start_proxy_with(&["--replay", har.to_str().unwrap()]).await;
+        std::fs::remove_file(&har).unwrap();
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = "GET /http://192.0.2.1/a?key=abc HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "recorded").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+        assert!(response.contains("x-recorded: 1"));
+        assert!(response.contains("content-length: 8"));
+
+        let request = "GET /http://192.0.2.1/b HTTP/1.1\r\nHost: proxy\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "No recorded response").await;
+        assert!(response.starts_with("http/1.1 502 bad gateway"));
+    }
+
+    #[tokio::test]
+    async fn requests_are_shadowed() {
+        let primary = fixed_upstream(SIZED).await;
+        let (shadow, mut shadowed) =
+            recording_upstream(b"HTTP/1.1 500 Oops\r\n\r\n".to_vec()).await;
+        let tokens = fixed_upstream(
+            "HTTP/1.1 200 OK\r\nContent-Length: 26\r\n\r\n{\"access_token\":\"primary\"}",
+        )
+        .await;
+
+        let proxy = start_proxy_with_config(
+            &format!(
+                "[[route]]\n\
+                 path = \"/m/{{path..}}\"\n\
+                 upstream = \"http://{}/{{path..}}\"\n\
+                 shadow = \"http://{}/canary/{{path..}}\"\n\
+                 host_header = \"primary.example.com\"\n\
+                 oauth2 = {{ token_url = \"http://{}/token\", client_id = \"a\", client_secret = \"b\" }}\n",
+                primary, shadow, tokens
+            ),
+            &[],
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"GET /m/x?y=1 HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        // The shadow's response never reaches the client
+        let response = read_response(&mut client, "hello").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+        let request = tokio::time::timeout(Duration::from_secs(5), shadowed.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(request.starts_with(b"GET /canary/x?y=1 HTTP/1.1\r\n"));
+        // Neither the primary's credentials nor its Host
+        let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+        assert!(!request.contains("authorization:"));
+        assert!(request.contains(&format!("host: {}\r\n", shadow)));
+    }
+
+    #[tokio::test]
+    async fn warm_targets_are_connected_ahead() {
+        let (upstream, mut requests) =
+            recording_upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_vec()).await;
+        let _proxy = start_proxy_with_config(
+            &format!(
+                "[[warm]]\nurl = \"http://{}/\"\nconnections = 2\n",
+                upstream
+            ),
+            &[],
+        )
+        .await;
+
+        // Without any client request
+        for _ in 0..2 {
+            let request = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(request.starts_with(b"HEAD / HTTP/1.1\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn old_upstream_connections_are_not_reused() {
+        // Keep-alive upstream counting the connections it accepted
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        if stream.write_all(SIZED.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
             }
         });
+        let proxy = start_proxy_with(&["--conn-max-lifetime", "1"]).await;
+        let get = async || {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+            client.write_all(request.as_bytes()).await.unwrap();
+            read_response(&mut client, "hello").await
+        };
+        get().await;
+        // Once the first connection is back in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get().await;
+        assert_eq!(connections.load(std::sync::atomic::Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        get().await;
+        assert_eq!(connections.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn connections_outliving_a_response_are_not_reused() {
+        // Upstream whose first response takes longer than the lifetime
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut slow = accepted.fetch_add(1, std::sync::atomic::Ordering::Relaxed) == 0;
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        if std::mem::take(&mut slow) {
+                            tokio::time::sleep(Duration::from_millis(1200)).await;
+                        }
+                        if stream.write_all(SIZED.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let proxy = start_proxy_with(&["--conn-max-lifetime", "1"]).await;
+        let get = async || {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("GET /http://{}/ HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+            client.write_all(request.as_bytes()).await.unwrap();
+            read_response(&mut client, "hello").await
+        };
+        assert!(get().await.starts_with("http/1.1 200 ok"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(get().await.starts_with("http/1.1 200 ok"));
+        assert_eq!(connections.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_upstream_fetch() {
+        // Slow upstream counting the requests it answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let counted = counted.clone();
+                tokio::spawn(async move {
+                    while read_request(&mut stream).await.is_some() {
+                        counted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        if stream.write_all(SIZED.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        let proxy = start_proxy_with(&["--coalesce"]).await;
+        let get = async || {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("GET /http://{}/a HTTP/1.1\r\nHost: proxy\r\n\r\n", upstream);
+            client.write_all(request.as_bytes()).await.unwrap();
+            read_response(&mut client, "hello").await
+        };
+        let (first, second) = tokio::join!(get(), get());
+        assert!(first.starts_with("http/1.1 200 ok"));
+        assert!(second.starts_with("http/1.1 200 ok"));
+        assert_eq!(requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // Once it is done, the next request is fetched again
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        get().await;
+        assert_eq!(requests.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn tls_version_bounds() {
+        let connector = |options: &[&str]| {
+            tls_connector(&Args::parse_from(["m2proxy"].iter().chain(options))).map(|_| ())
+        };
+        assert!(connector(&["--tls-min-version", "1.2", "--tls-max-version", "1.3"]).is_ok());
+        assert!(connector(&["--tls-max-version", "1.2"]).is_ok());
+        assert!(connector(&["--tls-min-version", "1.2", "--tls-max-version", "1.1"]).is_err());
+        assert!(connector(&["--tls-min-version", "1.3"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn config_file_pins_are_resolved() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        let proxy = start_proxy_with_config(
+            &format!(
+                "resolve = [\"origin.invalid:{}:127.0.0.1\"]\n",
+                upstream.port()
+            ),
+            &[],
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "GET /http://origin.invalid:{}/x HTTP/1.1\r\nHost: proxy\r\n\r\n",
+            upstream.port()
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "hello").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+        assert!(requests.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn routes_connect_to_a_pinned_address() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        // Nothing listens on port 80 for requests the route does not pin
+        let proxy = start_proxy_with_config(
+            &format!(
+                "[[route]]\n\
+                 path = \"/s/{{path..}}\"\n\
+                 upstream = \"http://origin.invalid/{{path..}}\"\n\
+                 connect_to = \"{}\"\n\
+                 sni = \"staged.example.com\"\n",
+                upstream
+            ),
+            &["--resolve", "origin.invalid:80:127.0.0.1"],
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"GET /s/x HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut client, "hello").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+        let request = String::from_utf8(requests.recv().await.unwrap()).unwrap();
+        assert!(request.starts_with("GET /x HTTP/1.1\r\n"));
+        // SNI does not change the Host of the upstream
+        assert!(
+            request
+                .to_ascii_lowercase()
+                .contains("\r\nhost: origin.invalid\r\n")
+        );
+
+        // Requests outside the route are not redirected
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(
+                b"GET /http://origin.invalid/x HTTP/1.1\r\nHost: proxy\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut raw = Vec::new();
+        client.read_to_end(&mut raw).await.unwrap();
+        let response = String::from_utf8(raw).unwrap();
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn pinned_routes_are_health_checked_where_they_connect() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        // Nothing listens on port 80 for probes that ignore the pin
+        let _proxy = start_proxy_with_config(
+            &format!(
+                "[[route]]\n\
+                 path = \"/s/{{path..}}\"\n\
+                 upstream = \"http://origin.invalid/{{path..}}\"\n\
+                 connect_to = \"{}\"\n\
+                 host_header = \"staged.example.com\"\n\
+                 health_check = \"/healthz\"\n",
+                upstream
+            ),
+            &["--resolve", "origin.invalid:80:127.0.0.1"],
+        )
+        .await;
+
+        let request = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let request = String::from_utf8(request).unwrap().to_ascii_lowercase();
+        assert!(request.starts_with("get /healthz http/1.1\r\n"));
+        assert!(request.contains("\r\nhost: staged.example.com\r\n"));
+    }
+
+    #[tokio::test]
+    async fn host_header_is_preserved_or_overridden() {
+        let (upstream, mut requests) = recording_upstream(SIZED.as_bytes().to_vec()).await;
+        let proxy = start_proxy_with_config(
+            &format!(
+                "[[route]]\n\
+                 path = \"/b/{{path..}}\"\n\
+                 upstream = \"http://{}/{{path..}}\"\n\
+                 host_header = \"bucket.example.com\"\n",
+                upstream
+            ),
+            &[],
+        )
+        .await;
+        let preserving = start_proxy_with(&["--preserve-host"]).await;
+
+        let mut host_of = async |proxy, path: &str| {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: proxy.example\r\n\r\n", path);
+            client.write_all(request.as_bytes()).await.unwrap();
+            read_response(&mut client, "hello").await;
+            let request = String::from_utf8(requests.recv().await.unwrap()).unwrap();
+            let request = request.to_ascii_lowercase();
+            let start = request.find("\r\nhost: ").unwrap() + 8;
+            request[start..start + request[start..].find("\r\n").unwrap()].to_string()
+        };
+        assert_eq!(host_of(proxy, "/b/x").await, "bucket.example.com");
+        assert_eq!(
+            host_of(proxy, &format!("/http://{}/x", upstream)).await,
+            upstream.to_string()
+        );
+        assert_eq!(
+            host_of(preserving, &format!("/http://{}/x", upstream)).await,
+            "proxy.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_runs_preview_the_outbound_request() {
+        let proxy = start_proxy_with(&["--trusted-clients", "127.0.0.1/32"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        // Never contacted
+        let request = "GET /http://192.0.2.1:8080/a?token=abc HTTP/1.1\r\nHost: proxy\r\n\
+            Authorization: Bearer abc\r\nX-Proxy-Dry-Run: 1\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut client, "\"\n}").await;
+        assert!(response.starts_with("http/1.1 200 ok"));
+        let json = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let preview: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(preview["method"], "get");
+        assert_eq!(
+            preview["url"],
+            "http://192.0.2.1:8080/a?token=%5bredacted%5d"
+        );
+        let headers = preview["headers"].as_array().unwrap();
+        assert!(
+            headers.contains(&serde_json::json!({ "name": "host", "value": "192.0.2.1:8080" }))
+        );
+        assert!(
+            headers
+                .contains(&serde_json::json!({ "name": "authorization", "value": "[redacted]" }))
+        );
+        assert!(!headers.iter().any(|h| h["name"] == "x-proxy-dry-run"));
     }
 }
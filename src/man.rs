@@ -0,0 +1,146 @@
+use std::io::Result;
+
+use clap::Command;
+use clap_mangen::Man;
+
+/// Keys of the `--config` file, kept in step with [`crate::config`]
+const CONFIGURATION: &str = r#".TP
+\fBresolve\fR
+List of \fIHOST:PORT:ADDR\fR pins added to \fB\-\-resolve\fR, which wins for the same \fIHOST:PORT\fR.
+.SS [[route]]
+Short path mapped to upstreams, tried in order before path-based targets.
+.TP
+\fBpath\fR
+Path with \fI{name}\fR segments and an optional trailing \fI{name..}\fR.
+.TP
+\fBupstream\fR, \fBupstreams\fR
+Upstream URL template, or a list of equivalent mirrors.
+.TP
+\fBbalance\fR
+\fBround-robin\fR (default), \fBleast-connections\fR, \fBfastest\fR or \fBfailover\fR.
+.TP
+\fBhealth_check\fR, \fBhealth_interval\fR
+Path requested on every mirror and the seconds between checks (default 10).
+.TP
+\fBhedge_after\fR
+Milliseconds before a GET or HEAD request is also sent to another mirror.
+.TP
+\fBsticky\fR
+\fBip\fR or \fBcookie:NAME\fR, keeps each client on one mirror.
+.TP
+\fBshadow\fR, \fBshadow_percent\fR
+URL template also sent a share of the requests (default 100), its responses are discarded.
+.TP
+\fBhost_header\fR
+Host header sent to the upstreams instead of their own.
+.TP
+\fBconnect_to\fR, \fBsni\fR
+\fIIP\fR or \fIIP:PORT\fR dialed for the upstreams, and the name sent as TLS SNI instead of theirs, for the requests of the route only.
+.TP
+\fBsigv4\fR
+Table with \fBregion\fR, \fBservice\fR, \fBaccess_key\fR, \fBsecret_key\fR and \fBsession_token\fR, signs requests with AWS Signature Version 4.
+.TP
+\fBoauth2\fR
+Table with \fBtoken_url\fR, \fBclient_id\fR, \fBclient_secret\fR and \fBscope\fR, fetches bearer tokens with the client credentials grant.
+.SS [[rewrite]]
+Regex substitution on the path of targets, all matching rules apply in order.
+.TP
+\fBhost\fR
+Optional target host the rule is limited to.
+.TP
+\fBpattern\fR, \fBreplace\fR
+Regular expression and its replacement with \fI$1\fR or \fI$name\fR references.
+.SS [[redirect]]
+Redirect of moved targets, answered without contacting them; the first matching entry applies.
+.TP
+\fBhost\fR
+Optional target host the rule is limited to.
+.TP
+\fBpattern\fR, \fBto\fR
+Regular expression matched against the target path and the new target URL with \fI$1\fR or \fI$name\fR references.
+.TP
+\fBstatus\fR
+301, 302, 303, 307 or 308 (default).
+.SS [[credential]]
+Headers added to outbound requests for a host, the first matching entry applies.
+.TP
+\fBhost\fR
+Target host, or \fI*.domain\fR for its subdomains.
+.TP
+\fBheaders\fR
+Table of header names and values.
+.SS [[pin]]
+Public keys an upstream host must present, the first matching entry applies.
+.TP
+\fBhost\fR
+Upstream host, or \fI*.domain\fR for its subdomains.
+.TP
+\fBsha256\fR
+Base64 SHA\-256 hashes of accepted SubjectPublicKeyInfos, optionally prefixed with \fIsha256//\fR.
+.SS [[warm]]
+Upstream origin the connection pool keeps connections to.
+.TP
+\fBurl\fR
+URL sent a HEAD request on each connection.
+.TP
+\fBconnections\fR, \fBinterval\fR
+Connections kept open (default 1) and the seconds between requests (default 30).
+.SS [[user_agent]]
+Rule for requests by User\-Agent, the first matching entry applies.
+.TP
+\fBpattern\fR
+Regular expression matched against the User\-Agent, empty if there is none.
+.TP
+\fBaction\fR, \fBrate\fR
+\fBallow\fR, \fBblock\fR (403) or \fBthrottle\fR to \fBrate\fR requests per minute and client (429).
+.PP
+In credential headers, route upstreams and sigv4 and oauth2 secrets,
+\fI${env:VAR}\fR is replaced with an environment variable and
+\fI${file:PATH}\fR with the contents of a file.
+"#;
+
+/// Manual page in roff format for `command`, with the config file keys
+/// after its options and commands
+pub fn render(command: Command) -> Result<String> {
+    let mut page = Vec::new();
+    Man::new(command).render(&mut page)?;
+    let mut page = String::from_utf8_lossy(&page).into_owned();
+    let sections = format!(
+        ".SH CONFIGURATION\nKeys of the TOML file given to \\fB\\-\\-config\\fR.\n{}\
+         .SH ENVIRONMENT\n.TP\n\\fBRUST_LOG\\fR\nLog filter directives (default: info).\n",
+        CONFIGURATION
+    );
+    let at = page.find(".SH VERSION").unwrap_or(page.len());
+    page.insert_str(at, &sections);
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    #[test]
+    fn manual() {
+        let command = Command::new("m2proxy")
+            .about("HTTP proxy")
+            .version("1.0")
+            .arg(
+                Arg::new("port")
+                    .short('p')
+                    .long("port")
+                    .value_name("PORT")
+                    .default_value("1234")
+                    .help("Port to bind to"),
+            )
+            .subcommand(Command::new("man").about("Print the manual"));
+        let page = render(command).unwrap();
+        assert!(page.contains("\n.TH m2proxy 1"), "{}", page);
+        assert!(page.contains("\\-\\-port"), "{}", page);
+        assert!(page.contains("Port to bind to"), "{}", page);
+        assert!(page.contains("Print the manual"), "{}", page);
+        let configuration = page.find(".SH CONFIGURATION").unwrap();
+        assert!(page[configuration..].contains(".SS [[credential]]"));
+        assert!(page.find(".SH VERSION").unwrap() > configuration);
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::body::{Body, BoxError};
+use crate::pool;
+use crate::statsd::Statsd;
+
+/// Default `--duration-buckets`, in seconds
+pub const DURATION_BUCKETS: &str = "0.005,0.01,0.025,0.05,0.1,0.25,0.5,1,2.5,5,10,30,60,300";
+/// Default `--size-buckets`, 1 KiB to 10 GiB
+pub const SIZE_BUCKETS: &str =
+    "1024,16384,131072,1048576,16777216,134217728,1073741824,10737418240";
+
+/// Target hosts counted separately, later ones are counted as `other`
+const MAX_TARGET_HOSTS: usize = 1000;
+
+/// Seconds between pushes of the gauges to StatsD
+const GAUGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Process-wide counters, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    requests_total: AtomicU64,
+    requests_in_flight: AtomicU64,
+    /// Responses by status class, index 0 is 1xx
+    responses: [AtomicU64; 5],
+    retries_total: AtomicU64,
+    retries_denied_total: AtomicU64,
+    /// Open client connections by id
+    open: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    connection_ids: AtomicU64,
+    /// Bytes of closed client connections
+    closed_received: AtomicU64,
+    closed_sent: AtomicU64,
+    /// Body bytes by target host
+    targets: Mutex<HashMap<String, Arc<Transferred>>>,
+    /// Requests by client country, with `--geoip-db`
+    countries: Mutex<BTreeMap<String, u64>>,
+    /// Time until the response body is sent, in microseconds
+    durations: Histogram,
+    /// Response body bytes sent
+    sizes: Histogram,
+    /// Also pushes counters and timers as they change
+    statsd: Option<Statsd>,
+}
+
+impl Metrics {
+    /// Metrics with histograms bucketed by `durations` in seconds and
+    /// `sizes` in bytes
+    pub fn new(durations: &[f64], sizes: &[u64], statsd: Option<Statsd>) -> Self {
+        let micros = durations
+            .iter()
+            .map(|seconds| (seconds * 1e6).round() as u64);
+        Metrics {
+            durations: Histogram::new(micros.collect()),
+            sizes: Histogram::new(sizes.to_vec()),
+            statsd,
+            ..Default::default()
+        }
+    }
+
+    /// Push the gauges to StatsD periodically, counters are pushed as they
+    /// change
+    pub async fn push_gauges(&self) {
+        let Some(statsd) = &self.statsd else {
+            return;
+        };
+        let mut interval = tokio::time::interval(GAUGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let active = self.connections_active.load(Ordering::Relaxed);
+            statsd.gauge("connections_active", active);
+            let in_flight = self.requests_in_flight.load(Ordering::Relaxed);
+            statsd.gauge("requests_in_flight", in_flight);
+        }
+    }
+
+    fn count(&self, name: &str) {
+        if let Some(statsd) = &self.statsd {
+            statsd.count(name, 1);
+        }
+    }
+
+    /// Count a client connection from `peer` until the guard is dropped
+    pub fn connection(&self, peer: SocketAddr) -> Open<'_> {
+        self.count("connections");
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            id: self.connection_ids.fetch_add(1, Ordering::Relaxed),
+            peer,
+            opened: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        });
+        self.open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(connection.id, connection.clone());
+        Open {
+            metrics: self,
+            connection,
+        }
+    }
+
+    /// Open client connections with their bytes so far, as JSON
+    pub fn connections(&self) -> serde_json::Value {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let connections: Vec<serde_json::Value> = open
+            .values()
+            .map(|connection| {
+                serde_json::json!({
+                    "id": connection.id,
+                    "peer": connection.peer.to_string(),
+                    "age_seconds": connection.opened.elapsed().as_secs(),
+                    "received_bytes": connection.received(),
+                    "sent_bytes": connection.sent(),
+                })
+            })
+            .collect();
+        serde_json::json!({ "connections": connections })
+    }
+
+    /// Body bytes counters of a target host
+    pub fn target(&self, host: &str) -> Arc<Transferred> {
+        let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(transferred) = targets.get(host) {
+            return transferred.clone();
+        }
+        let host = if targets.len() < MAX_TARGET_HOSTS {
+            host
+        } else {
+            "other"
+        };
+        targets.entry(host.to_string()).or_default().clone()
+    }
+
+    /// Count a request until the guard is dropped
+    pub fn request(&self) -> Tracked<'_> {
+        self.count("requests");
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        self.requests_in_flight.fetch_add(1, Ordering::Relaxed);
+        Tracked(&self.requests_in_flight)
+    }
+
+    /// Count a request from a client in `country`
+    pub fn country(&self, country: &str) {
+        let mut countries = self.countries.lock().unwrap_or_else(|e| e.into_inner());
+        *countries.entry(country.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn response(&self, status: StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize - 1;
+        self.responses[class].fetch_add(1, Ordering::Relaxed);
+        self.count(&format!("responses.{}xx", class + 1));
+    }
+
+    /// Observe the duration of a request started at `started` and the size
+    /// of its response once `body` is sent or dropped
+    pub fn measure(self: &Arc<Self>, body: Body, started: Instant) -> Body {
+        crate::body::boxed(Measured {
+            inner: body,
+            metrics: self.clone(),
+            started,
+            sent: 0,
+        })
+    }
+
+    fn observe(&self, duration: Duration, sent: u64) {
+        self.durations.observe(duration.as_micros() as u64);
+        self.sizes.observe(sent);
+        if let Some(statsd) = &self.statsd {
+            statsd.timing("request_duration", duration);
+            statsd.count("response_bytes", sent);
+        }
+    }
+
+    /// Count a retry, `denied` if the retry budget did not allow it
+    pub fn retry(&self, denied: bool) {
+        let counter = if denied {
+            &self.retries_denied_total
+        } else {
+            &self.retries_total
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.count(if denied {
+            "upstream_retries_denied"
+        } else {
+            "upstream_retries"
+        });
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        metric(
+            "m2proxy_connections_total",
+            "counter",
+            "Accepted client connections",
+            &self.connections_total,
+        );
+        metric(
+            "m2proxy_connections_active",
+            "gauge",
+            "Open client connections",
+            &self.connections_active,
+        );
+        metric(
+            "m2proxy_requests_total",
+            "counter",
+            "Received requests",
+            &self.requests_total,
+        );
+        metric(
+            "m2proxy_requests_in_flight",
+            "gauge",
+            "Requests being handled",
+            &self.requests_in_flight,
+        );
+        metric(
+            "m2proxy_upstream_retries_total",
+            "counter",
+            "Retried upstream requests",
+            &self.retries_total,
+        );
+        metric(
+            "m2proxy_upstream_retries_denied_total",
+            "counter",
+            "Retries skipped because the retry budget was used up",
+            &self.retries_denied_total,
+        );
+
+        let (mut received, mut sent) = (
+            self.closed_received.load(Ordering::Relaxed),
+            self.closed_sent.load(Ordering::Relaxed),
+        );
+        for connection in self.open.lock().unwrap_or_else(|e| e.into_inner()).values() {
+            received += connection.received();
+            sent += connection.sent();
+        }
+        let _ = writeln!(
+            out,
+            "# HELP m2proxy_client_received_bytes_total Bytes read from client connections"
+        );
+        let _ = writeln!(out, "# TYPE m2proxy_client_received_bytes_total counter");
+        let _ = writeln!(out, "m2proxy_client_received_bytes_total {}", received);
+        let _ = writeln!(
+            out,
+            "# HELP m2proxy_client_sent_bytes_total Bytes written to client connections"
+        );
+        let _ = writeln!(out, "# TYPE m2proxy_client_sent_bytes_total counter");
+        let _ = writeln!(out, "m2proxy_client_sent_bytes_total {}", sent);
+
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut hosts: Vec<_> = targets.iter().collect();
+        hosts.sort_unstable_by_key(|(host, _)| host.as_str());
+        for (name, help, sent) in [
+            (
+                "m2proxy_target_received_bytes_total",
+                "Request body bytes received from clients by target host",
+                false,
+            ),
+            (
+                "m2proxy_target_sent_bytes_total",
+                "Response body bytes sent to clients by target host",
+                true,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (host, transferred) in &hosts {
+                let counter = if sent {
+                    &transferred.sent
+                } else {
+                    &transferred.received
+                };
+                let _ = writeln!(
+                    out,
+                    "{}{{host=\"{}\"}} {}",
+                    name,
+                    host,
+                    counter.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let countries = self.countries.lock().unwrap_or_else(|e| e.into_inner());
+        if !countries.is_empty() {
+            let _ = writeln!(
+                out,
+                "# HELP m2proxy_requests_by_country_total Received requests by client country"
+            );
+            let _ = writeln!(out, "# TYPE m2proxy_requests_by_country_total counter");
+            for (country, count) in countries.iter() {
+                let _ = writeln!(
+                    out,
+                    "m2proxy_requests_by_country_total{{country=\"{}\"}} {}",
+                    country, count
+                );
+            }
+        }
+
+        let (reused, allocated, idle) = pool::POOL.stats();
+        for (name, kind, help, value) in [
+            (
+                "m2proxy_buffer_pool_reused_total",
+                "counter",
+                "Buffers of re-encoded, filtered and segmented bodies taken from the pool",
+                reused,
+            ),
+            (
+                "m2proxy_buffer_pool_allocated_total",
+                "counter",
+                "Buffers of re-encoded, filtered and segmented bodies allocated because none was free",
+                allocated,
+            ),
+            (
+                "m2proxy_buffer_pool_idle",
+                "gauge",
+                "Buffers returned to the pool, including ones whose chunks are still in use",
+                idle,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP m2proxy_responses_total Responses sent by status class"
+        );
+        let _ = writeln!(out, "# TYPE m2proxy_responses_total counter");
+        for (i, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "m2proxy_responses_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        self.durations.render(
+            &mut out,
+            "m2proxy_request_duration_seconds",
+            "Time until the response body was sent",
+            1e6,
+        );
+        self.sizes.render(
+            &mut out,
+            "m2proxy_response_size_bytes",
+            "Response body bytes sent",
+            1.0,
+        );
+        out
+    }
+}
+
+/// A client connection and the bytes read from and written to it
+pub struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    opened: Instant,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Connection {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Lists a connection while it is open
+pub struct Open<'a> {
+    metrics: &'a Metrics,
+    pub connection: Arc<Connection>,
+}
+
+impl Drop for Open<'_> {
+    fn drop(&mut self) {
+        let metrics = self.metrics;
+        metrics
+            .open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.connection.id);
+        let connection = &self.connection;
+        metrics
+            .closed_received
+            .fetch_add(connection.received(), Ordering::Relaxed);
+        metrics
+            .closed_sent
+            .fetch_add(connection.sent(), Ordering::Relaxed);
+        metrics.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Client connection stream counting the bytes of its [`Connection`]
+pub struct Metered<S> {
+    inner: S,
+    connection: Arc<Connection>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, connection: Arc<Connection>) -> Self {
+        Metered { inner, connection }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.connection.received.fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &poll {
+            self.connection
+                .sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = &poll {
+            self.connection
+                .sent
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Body bytes of one target host
+#[derive(Default)]
+pub struct Transferred {
+    /// Request bodies from clients
+    pub received: Arc<AtomicU64>,
+    /// Response bodies to clients
+    pub sent: Arc<AtomicU64>,
+}
+
+/// Counts of observations at most each bound, plus their sum
+#[derive(Default)]
+struct Histogram {
+    bounds: Vec<u64>,
+    /// One count per bound and the last for larger values, not cumulative
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(mut bounds: Vec<u64>) -> Self {
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram {
+            bounds,
+            counts,
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        if let Some(count) = self.counts.get(bucket) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Prometheus histogram with values divided by `scale`
+    fn render(&self, out: &mut String, name: &str, help: &str, scale: f64) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut total = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(&bound) => (bound as f64 / scale).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, total);
+        }
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / scale;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, total);
+    }
+}
+
+/// Response body that reports its size and the request duration on drop
+struct Measured {
+    inner: Body,
+    metrics: Arc<Metrics>,
+    started: Instant,
+    sent: u64,
+}
+
+impl HttpBody for Measured {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.sent += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Measured {
+    fn drop(&mut self) {
+        self.metrics.observe(self.started.elapsed(), self.sent);
+    }
+}
+
+/// Decrements a gauge on drop
+pub struct Tracked<'a>(&'a AtomicU64);
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms() {
+        let metrics = Metrics::new(&[0.1, 1.0], &[1024], None);
+        metrics.observe(Duration::from_millis(50), 100);
+        metrics.observe(Duration::from_millis(100), 4096);
+        metrics.observe(Duration::from_secs(3), 1024);
+        let text = metrics.render();
+        for line in [
+            "m2proxy_request_duration_seconds_bucket{le=\"0.1\"} 2",
+            "m2proxy_request_duration_seconds_bucket{le=\"1\"} 2",
+            "m2proxy_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "m2proxy_request_duration_seconds_sum 3.15",
+            "m2proxy_request_duration_seconds_count 3",
+            "m2proxy_response_size_bytes_bucket{le=\"1024\"} 2",
+            "m2proxy_response_size_bytes_bucket{le=\"+Inf\"} 3",
+            "m2proxy_response_size_bytes_sum 5220",
+        ] {
+            assert!(text.contains(&format!("{}\n", line)), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use http_body_util::BodyExt;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::config::{self, OAuth2Config};
+use crate::{HttpClient, body};
+
+/// Tokens are refreshed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// Lifetime of tokens issued without `expires_in`
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(300);
+
+/// OAuth 2.0 client credentials grant for the upstreams of a route (`oauth2`)
+pub struct TokenSource {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    /// Authorization value and when to refresh it
+    cached: Mutex<Option<(HeaderValue, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: Option<String>,
+    expires_in: Option<u64>,
+}
+
+impl TokenSource {
+    pub fn parse(config: &OAuth2Config) -> Result<Self> {
+        Ok(TokenSource {
+            token_url: config.token_url.clone(),
+            client_id: config::interpolate(&config.client_id)?,
+            client_secret: config::interpolate(&config.client_secret)?,
+            scope: config.scope.clone(),
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Authorization header value with a current token, fetching a new one
+    /// when the cached one is about to expire
+    pub async fn authorization(&self, client: &HttpClient) -> Result<HeaderValue> {
+        let mut cached = self.cached.lock().await;
+        if let Some((value, refresh)) = &*cached
+            && Instant::now() < *refresh
+        {
+            return Ok(value.clone());
+        }
+        let (value, lifetime) = self.fetch(client).await?;
+        debug!(
+            "Fetched token from {} valid for {:?}",
+            self.token_url, lifetime
+        );
+        let refresh = Instant::now() + lifetime.saturating_sub(REFRESH_MARGIN);
+        *cached = Some((value.clone(), refresh));
+        Ok(value)
+    }
+
+    async fn fetch(&self, client: &HttpClient) -> Result<(HeaderValue, Duration)> {
+        let form = {
+            let mut form = url::form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "client_credentials")
+                .append_pair("client_id", &self.client_id)
+                .append_pair("client_secret", &self.client_secret);
+            if let Some(scope) = &self.scope {
+                form.append_pair("scope", scope);
+            }
+            form.finish()
+        };
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.token_url)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(header::ACCEPT, "application/json")
+            .body(body::full(form))?;
+        let response = tokio::time::timeout(Duration::from_secs(10), client.request(request))
+            .await
+            .map_err(|_| anyhow!("Token request to {} timed out", self.token_url))??;
+        let status = response.status();
+        let bytes = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "Token endpoint {} returned {}",
+                self.token_url,
+                status
+            ));
+        }
+        parse_token(&bytes)
+    }
+}
+
+/// Authorization value and lifetime of a token endpoint response
+fn parse_token(bytes: &[u8]) -> Result<(HeaderValue, Duration)> {
+    let token: TokenResponse =
+        serde_json::from_slice(bytes).map_err(|e| anyhow!("Invalid token response: {}", e))?;
+    // The type is case-insensitive, the header wants `Bearer`
+    let kind = match token.token_type.as_deref() {
+        None => "Bearer".to_string(),
+        Some(kind) if kind.eq_ignore_ascii_case("bearer") => "Bearer".to_string(),
+        Some(kind) => kind.to_string(),
+    };
+    let mut value = HeaderValue::from_str(&format!("{} {}", kind, token.access_token))
+        .map_err(|_| anyhow!("Invalid access token"))?;
+    value.set_sensitive(true);
+    let lifetime = token
+        .expires_in
+        .map_or(DEFAULT_LIFETIME, Duration::from_secs);
+    Ok((value, lifetime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_responses() {
+        let (value, lifetime) =
+            parse_token(br#"{"access_token":"abc","token_type":"bearer","expires_in":3600}"#)
+                .unwrap();
+        assert_eq!(value, "Bearer abc");
+        assert!(value.is_sensitive());
+        assert_eq!(lifetime, Duration::from_secs(3600));
+
+        let (value, lifetime) = parse_token(br#"{"access_token":"abc"}"#).unwrap();
+        assert_eq!(value, "Bearer abc");
+        assert_eq!(lifetime, DEFAULT_LIFETIME);
+
+        assert!(parse_token(br#"{"error":"invalid_client"}"#).is_err());
+    }
+}
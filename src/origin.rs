@@ -0,0 +1,109 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result, anyhow};
+use hyper::HeaderMap;
+use hyper::header;
+use url::Url;
+
+use crate::host;
+
+/// Site of `--allow-origins`: an origin such as `https://app.example.com`,
+/// or a host matching any scheme and port, `*.domain` for its subdomains
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Site {
+    scheme: Option<String>,
+    /// Lowercase host, or `*.domain`
+    host: String,
+    port: Option<u16>,
+}
+
+impl Site {
+    fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        host::matches(&self.host, host)
+            && self
+                .scheme
+                .as_ref()
+                .is_none_or(|scheme| scheme == url.scheme())
+            && self
+                .port
+                .is_none_or(|port| Some(port) == url.port_or_known_default())
+    }
+}
+
+impl FromStr for Site {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        if !value.contains("://") {
+            if value.is_empty() || value.contains(['/', ':']) {
+                return Err(anyhow!("invalid site: {}", value));
+            }
+            return Ok(Site {
+                scheme: None,
+                host: value,
+                port: None,
+            });
+        }
+        let url = Url::parse(&value).map_err(|_| anyhow!("invalid origin: {}", value))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("invalid origin: {}", value))?;
+        Ok(Site {
+            scheme: Some(url.scheme().to_string()),
+            host: host.to_string(),
+            port: url.port_or_known_default(),
+        })
+    }
+}
+
+/// Whether the `Origin` of a request, or its `Referer` without one, is one
+/// of `sites`
+pub fn allowed(sites: &[Site], headers: &HeaderMap) -> bool {
+    let source = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER));
+    // An opaque `null` origin parses to nothing and is refused
+    let Some(url) = source
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Url::parse(value).ok())
+    else {
+        return false;
+    };
+    sites.iter().any(|site| site.matches(&url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn sites() {
+        let sites: Vec<Site> = ["https://app.example.com", "*.example.org", "localhost"]
+            .iter()
+            .map(|site| site.parse().unwrap())
+            .collect();
+        let check = |name, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            allowed(&sites, &headers)
+        };
+        assert!(check(header::ORIGIN, "https://app.example.com"));
+        assert!(check(header::ORIGIN, "https://app.example.com:443"));
+        assert!(!check(header::ORIGIN, "http://app.example.com"));
+        assert!(!check(header::ORIGIN, "https://app.example.com:8443"));
+        assert!(check(header::REFERER, "https://app.example.com/page?x=1"));
+        assert!(check(header::ORIGIN, "http://a.b.example.org:8080"));
+        assert!(!check(header::ORIGIN, "https://example.org"));
+        assert!(!check(header::ORIGIN, "https://badexample.org"));
+        assert!(check(header::ORIGIN, "http://localhost:3000"));
+        assert!(!check(header::ORIGIN, "null"));
+        assert!(!allowed(&sites, &HeaderMap::new()));
+        assert!("https://".parse::<Site>().is_err());
+        assert!("example.com/path".parse::<Site>().is_err());
+    }
+}
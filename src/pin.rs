@@ -0,0 +1,210 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{Result, anyhow};
+use hyper::Uri;
+use hyper_tls::MaybeHttpsStream;
+use ring::digest;
+use tower::Service;
+
+use crate::config::PinConfig;
+use crate::connect::{Routed, Stream};
+use crate::har::{base64, base64_decode};
+use crate::host;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// SHA-256 hashes of the public keys upstream hosts must present
+/// (`[[pin]]`)
+#[derive(Default)]
+pub struct Pins {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    /// Lowercase host, or `*.domain` for its subdomains
+    host: String,
+    hashes: Vec<Vec<u8>>,
+}
+
+impl Pins {
+    pub fn parse(configs: &[PinConfig]) -> Result<Self> {
+        let mut rules = Vec::new();
+        for config in configs {
+            if config.sha256.is_empty() {
+                return Err(anyhow!("Pin {}: no sha256 hashes", config.host));
+            }
+            let hashes = config
+                .sha256
+                .iter()
+                .map(|hash| {
+                    base64_decode(hash.trim().trim_start_matches("sha256//"))
+                        .filter(|hash| hash.len() == 32)
+                        .ok_or_else(|| anyhow!("Pin {}: invalid sha256 {}", config.host, hash))
+                })
+                .collect::<Result<_>>()?;
+            rules.push(Rule {
+                host: config.host.to_ascii_lowercase(),
+                hashes,
+            });
+        }
+        Ok(Pins { rules })
+    }
+
+    /// Check the DER certificate `host` presented against the first rule
+    /// matching it, unpinned hosts always pass
+    fn check(&self, host: &str, certificate: Option<&[u8]>) -> Result<(), BoxError> {
+        let host = host.to_ascii_lowercase();
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| host::matches(&rule.host, &host))
+        else {
+            return Ok(());
+        };
+        let spki = certificate
+            .and_then(public_key_info)
+            .ok_or_else(|| format!("no certificate from pinned host {}", host))?;
+        let hash = digest::digest(&digest::SHA256, spki);
+        if rule.hashes.iter().any(|pin| pin == hash.as_ref()) {
+            return Ok(());
+        }
+        Err(format!(
+            "certificate of {} does not match its pins (sha256//{})",
+            host,
+            base64(hash.as_ref())
+        )
+        .into())
+    }
+}
+
+/// Upstream connector refusing pinned hosts whose certificate does not
+/// carry one of their keys
+#[derive(Clone)]
+pub struct Pinned {
+    https: Routed,
+    pins: Arc<Pins>,
+}
+
+impl Pinned {
+    pub fn new(https: Routed, pins: Pins) -> Self {
+        Pinned {
+            https,
+            pins: Arc::new(pins),
+        }
+    }
+}
+
+impl Service<Uri> for Pinned {
+    type Response = MaybeHttpsStream<Stream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.https.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.https.call(uri.clone());
+        let pins = self.pins.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            let host = uri.host().unwrap_or("");
+            match &stream {
+                MaybeHttpsStream::Https(tls) => {
+                    let certificate = tls.inner().get_ref().peer_certificate()?;
+                    let der = certificate
+                        .map(|certificate| certificate.to_der())
+                        .transpose()?;
+                    pins.check(host, der.as_deref())?;
+                }
+                MaybeHttpsStream::Http(_) => pins.check(host, None)?,
+            }
+            Ok(stream)
+        })
+    }
+}
+
+/// Type, header length and total length of the DER element at the start of
+/// `data`
+fn element(data: &[u8]) -> Option<(u8, usize, usize)> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (header, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let bytes = first & 0x7f;
+        if bytes == 0 || bytes > 4 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + bytes)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        (2 + bytes, len)
+    };
+    (header + len <= data.len()).then_some((tag, header, header + len))
+}
+
+/// SubjectPublicKeyInfo of an X.509 certificate, what pins are hashes of
+fn public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, header, end) = element(certificate)?;
+    let tbs = &certificate[header..end];
+    let (_, header, end) = element(tbs)?;
+    let mut fields = &tbs[header..end];
+    // Serial, signature algorithm, issuer, validity and subject come first,
+    // after the optional [0] version
+    let mut index = 0;
+    loop {
+        let (tag, _, len) = element(fields)?;
+        if tag != 0xa0 {
+            if index == 5 {
+                return Some(&fields[..len]);
+            }
+            index += 1;
+        }
+        fields = &fields[len..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_key_pins() {
+        let spki = b"\x30\x03\x03\x01\x00";
+        let mut tbs = b"\xa0\x03\x02\x01\x02\x02\x01\x01\x30\x00\x30\x00\x30\x00\x30\x00".to_vec();
+        tbs.extend_from_slice(spki);
+        let mut certificate = vec![0x30, tbs.len() as u8 + 6, 0x30, tbs.len() as u8];
+        certificate.extend_from_slice(&tbs);
+        certificate.extend_from_slice(b"\x30\x00\x03\x00");
+        assert_eq!(public_key_info(&certificate), Some(&spki[..]));
+        assert_eq!(public_key_info(b"\x30\x05\x30\x03\x02\x01\x01"), None);
+
+        let hash = base64(digest::digest(&digest::SHA256, spki).as_ref());
+        let pins = Pins::parse(&[PinConfig {
+            host: "*.example.com".to_string(),
+            sha256: vec![format!("sha256//{}", hash)],
+        }])
+        .unwrap();
+        assert!(pins.check("mirror.example.com", Some(&certificate)).is_ok());
+        assert!(pins.check("example.org", None).is_ok());
+        assert!(pins.check("mirror.example.com", None).is_err());
+        // The last byte of the key
+        certificate[3 + tbs.len()] = 1;
+        assert!(
+            pins.check("mirror.example.com", Some(&certificate))
+                .is_err()
+        );
+        assert!(
+            Pins::parse(&[PinConfig {
+                host: "example.com".to_string(),
+                sha256: vec!["abc".to_string()],
+            }])
+            .is_err()
+        );
+    }
+}
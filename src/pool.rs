@@ -0,0 +1,185 @@
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use hyper::body::{Body as HttpBody, Bytes, Frame};
+use tokio::io::AsyncRead;
+
+use crate::body::{Body, BoxError};
+
+/// Capacity of pooled buffers
+pub const BUFFER_SIZE: usize = 64 * 1024;
+/// Returned buffers kept for reuse, more are freed
+const MAX_IDLE: usize = 64;
+
+/// Read buffers shared by the re-encoded, filtered and segmented bodies of
+/// all requests; other bodies are not copied through buffers of the proxy
+pub static POOL: BufferPool = BufferPool::new();
+
+/// Recycles read buffers once the chunks split off them are dropped
+pub struct BufferPool {
+    /// Returned buffers, some possibly still referred to by chunks
+    idle: Mutex<Vec<BytesMut>>,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+}
+
+impl BufferPool {
+    const fn new() -> Self {
+        BufferPool {
+            idle: Mutex::new(Vec::new()),
+            reused: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    /// A returned buffer whose chunks are all dropped, or a new one
+    pub fn get(&self) -> BytesMut {
+        let buf = {
+            let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+            idle.iter_mut()
+                .position(|buf| buf.try_reclaim(BUFFER_SIZE))
+                .map(|i| idle.swap_remove(i))
+        };
+        match buf {
+            Some(buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        }
+    }
+
+    /// Keep `buf` for reuse once the chunks split off it are dropped
+    pub fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < MAX_IDLE {
+            idle.push(buf);
+        }
+    }
+
+    /// Make room for the next read, swapping a used up `buf` for a pooled one
+    pub fn reserve(&self, buf: &mut BytesMut) {
+        if buf.capacity() == buf.len() {
+            let full = std::mem::replace(buf, self.get());
+            buf.extend_from_slice(&full);
+            self.put(full);
+        }
+    }
+
+    /// Buffers taken from the pool, newly allocated ones and returned ones
+    pub fn stats(&self) -> (u64, u64, u64) {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).len();
+        (
+            self.reused.load(Ordering::Relaxed),
+            self.allocated.load(Ordering::Relaxed),
+            idle as u64,
+        )
+    }
+}
+
+/// Body streaming `reader` through a pooled buffer
+pub fn stream<R: AsyncRead + Send + Sync + 'static>(reader: R) -> Body {
+    crate::body::boxed(Pooled::new(&POOL, reader))
+}
+
+struct Pooled {
+    pool: &'static BufferPool,
+    reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    /// Taken once the reader ends
+    buf: Option<BytesMut>,
+}
+
+impl Pooled {
+    fn new<R: AsyncRead + Send + Sync + 'static>(pool: &'static BufferPool, reader: R) -> Self {
+        Pooled {
+            pool,
+            reader: Box::pin(reader),
+            buf: Some(pool.get()),
+        }
+    }
+}
+
+impl HttpBody for Pooled {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let Some(buf) = &mut this.buf else {
+            return Poll::Ready(None);
+        };
+        this.pool.reserve(buf);
+        let poll = tokio_util::io::poll_read_buf(this.reader.as_mut(), cx, buf);
+        match poll {
+            Poll::Ready(Ok(0)) => {
+                if let Some(buf) = this.buf.take() {
+                    this.pool.put(buf);
+                }
+                Poll::Ready(None)
+            }
+            Poll::Ready(Ok(_)) => Poll::Ready(Some(Ok(Frame::data(buf.split().freeze())))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(Box::new(e)))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_none()
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn buffers_are_reused() {
+        let pool = BufferPool::new();
+        let mut buf = pool.get();
+        buf.extend_from_slice(b"chunk");
+        let chunk = buf.split().freeze();
+        pool.put(buf);
+        assert_eq!(pool.stats(), (0, 1, 1));
+        // Its memory is still used by the chunk
+        drop(pool.get());
+        assert_eq!(pool.stats(), (0, 2, 1));
+        drop(chunk);
+        assert!(pool.get().capacity() >= BUFFER_SIZE);
+        assert_eq!(pool.stats(), (1, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn streams_reuse_buffers() {
+        let pool: &'static BufferPool = Box::leak(Box::new(BufferPool::new()));
+        let data = vec![7u8; BUFFER_SIZE * 2 + 3];
+        let body = Pooled::new(pool, std::io::Cursor::new(data.clone()));
+        let bytes = body.collect().await.unwrap().to_bytes();
+        assert_eq!(bytes, data);
+        let (reused, allocated, idle) = pool.stats();
+        assert_eq!(idle, allocated);
+
+        // The chunks of the first body are gone
+        let body = Pooled::new(pool, std::io::Cursor::new(data.clone()));
+        assert_eq!(body.collect().await.unwrap().to_bytes(), data);
+        assert_eq!(pool.stats(), (reused + allocated, allocated, allocated));
+    }
+}
@@ -0,0 +1,170 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use tokio::time::Sleep;
+use tracing::info;
+
+use crate::body::{Body, BoxError};
+
+/// Periodic log lines for large transfers (`--progress-threshold`)
+pub struct Progress {
+    pub threshold: u64,
+    pub interval: Duration,
+}
+
+impl Progress {
+    /// Log the progress of sending `body` if it is, or turns out to be, at
+    /// least the threshold in size
+    pub fn apply(&self, body: Body, label: String, length: Option<u64>) -> Body {
+        if length.is_some_and(|length| length < self.threshold) {
+            return body;
+        }
+        crate::body::boxed(ProgressBody {
+            inner: body,
+            label,
+            length,
+            threshold: self.threshold,
+            interval: self.interval,
+            tick: Box::pin(tokio::time::sleep(self.interval)),
+            started: Instant::now(),
+            sent: 0,
+            reported: false,
+        })
+    }
+}
+
+struct ProgressBody {
+    inner: Body,
+    label: String,
+    length: Option<u64>,
+    threshold: u64,
+    interval: Duration,
+    tick: Pin<Box<Sleep>>,
+    started: Instant,
+    sent: u64,
+    /// Whether a progress line was logged, so the end gets one too
+    reported: bool,
+}
+
+impl ProgressBody {
+    fn report(&mut self) {
+        if self.sent < self.threshold && self.length.is_none() {
+            return;
+        }
+        self.reported = true;
+        info!(
+            "{}: {}",
+            self.label,
+            describe(self.sent, self.length, self.started.elapsed())
+        );
+    }
+}
+
+impl HttpBody for ProgressBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.sent += data.len() as u64;
+        }
+        // Also fires while the transfer is stalled
+        if self.tick.as_mut().poll(cx).is_ready() {
+            self.report();
+            let next = tokio::time::Instant::now() + self.interval;
+            self.tick.as_mut().reset(next);
+            let _ = self.tick.as_mut().poll(cx);
+        }
+        match &poll {
+            Poll::Ready(None) if self.reported => {
+                info!(
+                    "{}: finished {} in {:.1}s",
+                    self.label,
+                    format_bytes(self.sent),
+                    self.started.elapsed().as_secs_f64()
+                );
+            }
+            Poll::Ready(Some(Err(e))) if self.reported => {
+                info!(
+                    "{}: failed after {}: {}",
+                    self.label,
+                    format_bytes(self.sent),
+                    e
+                );
+            }
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// `sent` of `length` bytes with rate and ETA, e.g.
+/// `1.5 MiB of 3.0 MiB (50%), 512.0 KiB/s, ETA 3s`
+fn describe(sent: u64, length: Option<u64>, elapsed: Duration) -> String {
+    let rate = sent as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut line = format_bytes(sent);
+    if let Some(length) = length.filter(|&length| length > 0) {
+        line += &format!(
+            " of {} ({}%)",
+            format_bytes(length),
+            sent.saturating_mul(100) / length
+        );
+    }
+    line += &format!(", {}/s", format_bytes(rate as u64));
+    if let Some(length) = length
+        && rate >= 1.0
+    {
+        let eta = length.saturating_sub(sent) as f64 / rate;
+        line += &format!(", ETA {}s", eta.ceil() as u64);
+    }
+    line
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_lines() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(
+            describe(1536 * 1024, Some(3 * 1024 * 1024), Duration::from_secs(3)),
+            "1.5 MiB of 3.0 MiB (50%), 512.0 KiB/s, ETA 3s"
+        );
+        assert_eq!(
+            describe(2048, None, Duration::from_secs(2)),
+            "2.0 KiB, 1.0 KiB/s"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Result, anyhow};
+use hyper::StatusCode;
+use regex::Regex;
+use url::Url;
+
+use crate::config::RedirectConfig;
+
+/// Redirect of moved targets, from `[[redirect]]` config entries
+#[derive(Debug)]
+pub struct Redirect {
+    host: Option<String>,
+    pattern: Regex,
+    to: String,
+    status: StatusCode,
+}
+
+impl Redirect {
+    pub fn parse(config: &RedirectConfig) -> Result<Self> {
+        let pattern = Regex::new(&config.pattern)
+            .map_err(|e| anyhow!("Invalid redirect pattern {}: {}", config.pattern, e))?;
+        let status = match config.status.unwrap_or(308) {
+            code @ (301 | 302 | 303 | 307 | 308) => StatusCode::from_u16(code)?,
+            code => return Err(anyhow!("Invalid redirect status {}", code)),
+        };
+        Ok(Redirect {
+            host: config.host.as_ref().map(|h| h.to_ascii_lowercase()),
+            pattern,
+            to: config.to.clone(),
+            status,
+        })
+    }
+
+    /// Status and new location of `url`, `None` if the rule does not apply;
+    /// the query is kept unless the new location has one
+    pub fn apply(&self, url: &Url) -> Option<(StatusCode, Url)> {
+        if let Some(host) = &self.host
+            && url.host_str() != Some(host.as_str())
+        {
+            return None;
+        }
+        let captures = self.pattern.captures(url.path())?;
+        let mut to = String::new();
+        captures.expand(&self.to, &mut to);
+        let mut location = Url::parse(&to).ok()?;
+        if location.query().is_none() {
+            location.set_query(url.query());
+        }
+        Some((self.status, location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_targets() {
+        let redirect = Redirect::parse(&RedirectConfig {
+            host: Some("Old.example.com".to_string()),
+            pattern: "^/repo/(?<rest>.*)$".to_string(),
+            to: "https://new.example.com/renamed/$rest".to_string(),
+            status: None,
+        })
+        .unwrap();
+
+        let url = Url::parse("https://old.example.com/repo/a/b.tar.gz?x=1").unwrap();
+        let (status, location) = redirect.apply(&url).unwrap();
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            location.as_str(),
+            "https://new.example.com/renamed/a/b.tar.gz?x=1"
+        );
+        let other = Url::parse("https://other.example.com/repo/a").unwrap();
+        assert!(redirect.apply(&other).is_none());
+
+        let invalid = RedirectConfig {
+            host: None,
+            pattern: "^/".to_string(),
+            to: "https://example.com/".to_string(),
+            status: Some(200),
+        };
+        assert!(Redirect::parse(&invalid).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use futures_util::stream;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{self, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Method, StatusCode};
+use tokio::sync::mpsc;
+use tracing::warn;
+use url::Url;
+
+use crate::body::{self, Body, BoxError};
+use crate::segment::range_validator;
+use crate::{HttpClient, upstream_request};
+
+/// Resumption of upstream bodies that break off (`--resume-attempts`)
+pub struct Resume {
+    pub attempts: u32,
+}
+
+impl Resume {
+    /// Continue `body` with Range requests from the received offset if the
+    /// upstream connection fails mid-body
+    pub fn apply(
+        &self,
+        client: &HttpClient,
+        url: &Url,
+        headers: &HeaderMap,
+        parts: &Parts,
+        body: Body,
+    ) -> Body {
+        if parts.status != StatusCode::OK || headers.contains_key(header::RANGE) {
+            return body;
+        }
+        let Some(validator) = range_validator(&parts.headers) else {
+            return body;
+        };
+        let upstream = Upstream {
+            client: client.clone(),
+            url: url.clone(),
+            headers: headers.clone(),
+            validator,
+        };
+        let (tx, mut rx) = mpsc::channel(4);
+        tokio::spawn(relay(upstream, body, self.attempts, tx));
+        body::boxed(StreamBody::new(stream::poll_fn(move |cx| rx.poll_recv(cx))))
+    }
+}
+
+/// Where to request the rest of a body
+struct Upstream {
+    client: HttpClient,
+    url: Url,
+    headers: HeaderMap,
+    validator: HeaderValue,
+}
+
+impl Upstream {
+    /// Remaining body from `offset`, if the upstream still has the same
+    /// representation
+    async fn resume(&self, offset: u64) -> Result<Body, BoxError> {
+        let mut headers = self.headers.clone();
+        headers.insert(
+            header::RANGE,
+            HeaderValue::from_str(&format!("bytes={}-", offset))?,
+        );
+        headers.insert(header::IF_RANGE, self.validator.clone());
+        let request = upstream_request(&Method::GET, &self.url, &headers, body::empty())?;
+        let response = self.client.request(request).await?;
+        let expected = format!("bytes {}-", offset);
+        let resumed = response.status() == StatusCode::PARTIAL_CONTENT
+            && response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|range| range.starts_with(&expected));
+        if !resumed {
+            return Err(format!("upstream answered {} to resume", response.status()).into());
+        }
+        Ok(body::boxed(response.into_body()))
+    }
+}
+
+/// Forward the frames of `body`, resuming up to `attempts` times
+async fn relay(
+    upstream: Upstream,
+    mut body: Body,
+    mut attempts: u32,
+    tx: mpsc::Sender<Result<Frame<Bytes>, BoxError>>,
+) {
+    let mut offset = 0;
+    loop {
+        let frame = match body.frame().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) if attempts > 0 => {
+                attempts -= 1;
+                warn!(
+                    "Upstream body of {} failed at byte {}, resuming: {}",
+                    upstream.url, offset, e
+                );
+                match upstream.resume(offset).await {
+                    Ok(rest) => {
+                        body = rest;
+                        continue;
+                    }
+                    Err(resume) => {
+                        warn!("Resuming {} failed: {}", upstream.url, resume);
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+            None => return,
+        };
+        if let Some(data) = frame.data_ref() {
+            offset += data.len() as u64;
+        }
+        if tx.send(Ok(frame)).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use hyper::header::{self, HeaderValue};
+use hyper::{HeaderMap, Response, StatusCode};
+
+use crate::body;
+use crate::connect::ConnLimitExceeded;
+
+/// Result of one upstream request
+pub type Attempt = Result<Response<body::Body>, hyper_util::client::legacy::Error>;
+
+/// First delay between retries without Retry-After, doubled each attempt
+const BACKOFF: Duration = Duration::from_millis(100);
+
+/// Retries of failed bodiless requests (`--retries`)
+pub struct Retry {
+    pub attempts: u32,
+    /// Longest wait before a retry; a longer Retry-After is passed through
+    pub max_wait: Duration,
+    pub budget: Option<Budget>,
+}
+
+/// One retry in budget units
+const RETRY: u64 = 1000;
+
+/// Retries saved up while traffic is healthy, also the starting balance
+const MAX_SAVED: u64 = 10 * RETRY;
+
+/// Keeps retries to a share of requests (`--retry-budget`), so a failing
+/// upstream does not get several times the normal traffic
+pub struct Budget {
+    /// Earned per request
+    earn: u64,
+    balance: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(percent: u64) -> Self {
+        Budget {
+            earn: percent.saturating_mul(RETRY / 100),
+            balance: AtomicU64::new(MAX_SAVED),
+        }
+    }
+
+    /// Account for a request that may be retried
+    pub fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                Some((balance + self.earn).min(MAX_SAVED))
+            });
+    }
+
+    /// Take one retry from the budget, `false` if it is used up
+    pub fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |balance| {
+                balance.checked_sub(RETRY)
+            })
+            .is_ok()
+    }
+}
+
+impl Retry {
+    /// Delay before retrying after `attempt` failed attempts, `None` to
+    /// use the result as it is
+    pub fn delay(&self, attempt: u32, result: &Attempt) -> Option<Duration> {
+        if attempt >= self.attempts {
+            return None;
+        }
+        let backoff = BACKOFF
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_wait);
+        match result {
+            Ok(response) => match response.status() {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                    match retry_after(response.headers(), SystemTime::now()) {
+                        Some(wait) if wait > self.max_wait => None,
+                        Some(wait) => Some(wait),
+                        None => Some(backoff),
+                    }
+                }
+                StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => Some(backoff),
+                _ => None,
+            },
+            // Waiting for a connection slot already took long enough
+            Err(e) if body::caused_by::<ConnLimitExceeded>(e) => None,
+            Err(_) => Some(backoff),
+        }
+    }
+}
+
+/// Wait requested by a Retry-After header, in seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Rewrite a Retry-After date as seconds, so clients with a skewed clock
+/// wait as long as the upstream asked (`--translate-retry-after`)
+pub fn translate_retry_after(headers: &mut HeaderMap) {
+    let is_date = headers
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().parse::<u64>().is_err());
+    if is_date && let Some(wait) = retry_after(headers, SystemTime::now()) {
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_values() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(120)));
+        let later = httpdate::fmt_http_date(now + Duration::from_secs(30));
+        headers.insert(header::RETRY_AFTER, later.parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(Duration::from_secs(30)));
+        let earlier = httpdate::fmt_http_date(now - Duration::from_secs(30));
+        headers.insert(header::RETRY_AFTER, earlier.parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn budget() {
+        let budget = Budget::new(20);
+        for _ in 0..10 {
+            assert!(budget.withdraw());
+        }
+        assert!(!budget.withdraw());
+        // Five requests earn one retry
+        for _ in 0..4 {
+            budget.deposit();
+        }
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn translated_dates() {
+        let mut headers = HeaderMap::new();
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        headers.insert(header::RETRY_AFTER, later.parse().unwrap());
+        translate_retry_after(&mut headers);
+        let seconds: u64 = headers[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((58..=60).contains(&seconds));
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use url::Url;
+
+use crate::config::RewriteConfig;
+
+/// Regex rewrite of target paths, from `[[rewrite]]` config entries
+#[derive(Debug)]
+pub struct Rewrite {
+    host: Option<String>,
+    pattern: Regex,
+    replace: String,
+}
+
+impl Rewrite {
+    pub fn parse(config: &RewriteConfig) -> Result<Self> {
+        let pattern = Regex::new(&config.pattern)
+            .map_err(|e| anyhow!("Invalid rewrite pattern {}: {}", config.pattern, e))?;
+        Ok(Rewrite {
+            host: config.host.as_ref().map(|h| h.to_ascii_lowercase()),
+            pattern,
+            replace: config.replace.clone(),
+        })
+    }
+
+    /// Rewrite the path of `url` if the rule applies to it
+    pub fn apply(&self, url: &mut Url) {
+        if let Some(host) = &self.host
+            && url.host_str() != Some(host.as_str())
+        {
+            return;
+        }
+        if !self.pattern.is_match(url.path()) {
+            return;
+        }
+        let path = self
+            .pattern
+            .replace(url.path(), self.replace.as_str())
+            .into_owned();
+        url.set_path(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_groups() {
+        let rewrite = Rewrite::parse(&RewriteConfig {
+            host: Some("Example.com".to_string()),
+            pattern: "^/releases/v([0-9.]+)/(.*)$".to_string(),
+            replace: "/download/$1/$2".to_string(),
+        })
+        .unwrap();
+
+        let mut url = Url::parse("https://example.com/releases/v1.2/app.tar.gz?x=1").unwrap();
+        rewrite.apply(&mut url);
+        assert_eq!(
+            url.as_str(),
+            "https://example.com/download/1.2/app.tar.gz?x=1"
+        );
+
+        let mut other = Url::parse("https://other.com/releases/v1.2/app.tar.gz").unwrap();
+        rewrite.apply(&mut other);
+        assert_eq!(other.path(), "/releases/v1.2/app.tar.gz");
+    }
+}
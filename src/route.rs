@@ -0,0 +1,537 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::config::{self, RouteConfig};
+use crate::connect::Via;
+use crate::oauth::TokenSource;
+use crate::sigv4::SigV4;
+use crate::upstream::{HealthCheck, Pick, Pool};
+use anyhow::{Result, anyhow};
+use hyper::HeaderMap;
+use hyper::header::{self, HeaderValue};
+
+/// Path pattern segment
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, exactly one path segment
+    Param(String),
+    /// `{name..}`, the rest of the path, possibly empty
+    Rest(String),
+}
+
+/// What identifies a client for sticky upstream selection
+#[derive(Debug, PartialEq, Eq)]
+enum Sticky {
+    Ip,
+    Cookie(String),
+}
+
+impl Sticky {
+    fn parse(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            None if value == "ip" => Ok(Sticky::Ip),
+            Some(("cookie", name)) if !name.is_empty() => Ok(Sticky::Cookie(name.to_string())),
+            _ => Err(anyhow!(
+                "Invalid sticky {}, expected ip or cookie:NAME",
+                value
+            )),
+        }
+    }
+
+    /// Key of a request, `None` if it carries no cookie of that name
+    fn key(&self, headers: &HeaderMap, client_ip: IpAddr) -> Option<String> {
+        match self {
+            Sticky::Ip => Some(client_ip.to_string()),
+            Sticky::Cookie(name) => headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    (key == name).then(|| value.to_string())
+                }),
+        }
+    }
+}
+
+/// Share of requests copied to a shadow upstream
+struct Shadow {
+    template: String,
+    percent: u64,
+    requests: AtomicU64,
+}
+
+impl Shadow {
+    /// Whether the next request is copied, spread evenly over requests
+    fn sample(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        (n + 1) * self.percent / 100 > n * self.percent / 100
+    }
+}
+
+/// Shadow URL of a request, copied only if the route's share picks it
+pub struct Shadowed {
+    pub url: String,
+    shadow: Arc<Shadow>,
+}
+
+impl Shadowed {
+    /// Whether this request is copied, counted only for requests that can be
+    pub fn sample(&self) -> bool {
+        self.shadow.sample()
+    }
+}
+
+/// Configured route from a path pattern to upstream URL templates
+pub struct Route {
+    path: String,
+    segments: Vec<Segment>,
+    upstreams: Arc<Pool>,
+    hedge_after: Option<Duration>,
+    sticky: Option<Sticky>,
+    signer: Option<Arc<SigV4>>,
+    tokens: Option<Arc<TokenSource>>,
+    shadow: Option<Arc<Shadow>>,
+    host_header: Option<HeaderValue>,
+    via: Option<Arc<Via>>,
+}
+
+/// Upstream URL of a matched route
+pub struct Resolved {
+    pub url: String,
+    pub upstream: Pick,
+    pub hedge: Option<Hedge>,
+    pub signer: Option<Arc<SigV4>>,
+    pub tokens: Option<Arc<TokenSource>>,
+    /// Set for routes with `shadow`
+    pub shadow: Option<Shadowed>,
+    pub host_header: Option<HeaderValue>,
+    pub via: Option<Arc<Via>>,
+}
+
+/// Duplicate of a request for another upstream, sent if the first one is slow
+pub struct Hedge {
+    pub delay: Duration,
+    upstreams: Arc<Pool>,
+    values: HashMap<String, String>,
+}
+
+impl Hedge {
+    /// URL on a healthy upstream other than `primary`, if there is one
+    pub fn resolve(&self, primary: &Pick) -> Option<(String, Pick)> {
+        let pick = self.upstreams.pick_other(primary)?;
+        Some((fill(pick.template(), &self.values), pick))
+    }
+}
+
+impl Route {
+    pub fn parse(config: &RouteConfig) -> Result<Self> {
+        let mut segments = Vec::new();
+        let parts: Vec<&str> = config.path.trim_matches('/').split('/').collect();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) => match name.strip_suffix("..") {
+                    Some(name) if i + 1 == parts.len() => Segment::Rest(name.to_string()),
+                    Some(_) => {
+                        return Err(anyhow!(
+                            "Route {}: {{name..}} must be the last segment",
+                            config.path
+                        ));
+                    }
+                    None => Segment::Param(name.to_string()),
+                },
+                None if part.is_empty() && parts.len() == 1 => continue,
+                None => Segment::Literal(part.to_string()),
+            };
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(anyhow!("Route {}: path must not be the root", config.path));
+        }
+
+        let templates = match (&config.upstream, config.upstreams.is_empty()) {
+            (Some(upstream), true) => vec![config::interpolate(upstream)?],
+            (None, false) => config
+                .upstreams
+                .iter()
+                .map(|upstream| config::interpolate(upstream))
+                .collect::<Result<_>>()?,
+            _ => {
+                return Err(anyhow!(
+                    "Route {}: exactly one of upstream and upstreams is required",
+                    config.path
+                ));
+            }
+        };
+
+        // Every placeholder in the templates needs a value
+        let names: HashMap<String, String> = segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Param(name) | Segment::Rest(name) => Some((name.clone(), String::new())),
+                Segment::Literal(_) => None,
+            })
+            .collect();
+        let shadow = config
+            .shadow
+            .as_deref()
+            .map(config::interpolate)
+            .transpose()?;
+        if let Some(template) = templates
+            .iter()
+            .chain(&shadow)
+            .find(|t| fill(t, &names).contains('{'))
+        {
+            return Err(anyhow!(
+                "Route {}: upstream {} uses an undefined placeholder",
+                config.path,
+                template
+            ));
+        }
+        let connect_to = config
+            .connect_to
+            .as_deref()
+            .map(parse_connect_to)
+            .transpose()
+            .map_err(|e| anyhow!("Route {}: {}", config.path, e))?;
+        let via = (connect_to.is_some() || config.sni.is_some()).then(|| {
+            Arc::new(Via {
+                connect_to,
+                sni: config.sni.clone(),
+            })
+        });
+        let health = config.health_check.as_ref().map(|path| HealthCheck {
+            path: path.clone(),
+            interval: Duration::from_secs(config.health_interval.unwrap_or(10).max(1)),
+        });
+        Ok(Route {
+            path: config.path.clone(),
+            segments,
+            upstreams: Pool::new(templates, config.balance, health)?,
+            hedge_after: config.hedge_after.map(Duration::from_millis),
+            sticky: config.sticky.as_deref().map(Sticky::parse).transpose()?,
+            signer: config
+                .sigv4
+                .as_ref()
+                .map(|sigv4| SigV4::parse(sigv4).map(Arc::new))
+                .transpose()
+                .map_err(|e| anyhow!("Route {}: {}", config.path, e))?,
+            shadow: shadow.map(|template| {
+                Arc::new(Shadow {
+                    template,
+                    percent: config.shadow_percent.unwrap_or(100).min(100),
+                    requests: AtomicU64::new(0),
+                })
+            }),
+            host_header: config
+                .host_header
+                .as_deref()
+                .map(HeaderValue::from_str)
+                .transpose()
+                .map_err(|_| anyhow!("Route {}: invalid host_header", config.path))?,
+            via,
+            tokens: config
+                .oauth2
+                .as_ref()
+                .map(|oauth2| TokenSource::parse(oauth2).map(Arc::new))
+                .transpose()
+                .map_err(|e| anyhow!("Route {}: {}", config.path, e))?,
+        })
+    }
+
+    pub fn upstreams(&self) -> &Arc<Pool> {
+        &self.upstreams
+    }
+
+    /// `connect_to` and `sni` of the route's upstreams
+    pub fn via(&self) -> Option<&Arc<Via>> {
+        self.via.as_ref()
+    }
+
+    /// `host_header` of the route's upstreams
+    pub fn host_header(&self) -> Option<&HeaderValue> {
+        self.host_header.as_ref()
+    }
+
+    /// Route and upstream state, for the stats endpoint
+    pub fn stats(&self) -> serde_json::Value {
+        let mut stats = self.upstreams.stats();
+        stats["path"] = self.path.clone().into();
+        stats
+    }
+
+    /// Upstream URL for `path`, if the route matches it
+    pub fn resolve(&self, path: &str, headers: &HeaderMap, client_ip: IpAddr) -> Option<Resolved> {
+        let mut rest = path.strip_prefix('/').unwrap_or(path);
+        let mut values = HashMap::new();
+        for segment in &self.segments {
+            if let Segment::Rest(name) = segment {
+                values.insert(name.clone(), rest.to_string());
+                rest = "";
+                break;
+            }
+            if rest.is_empty() {
+                return None;
+            }
+            let (part, tail) = rest.split_once('/').unwrap_or((rest, ""));
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    values.insert(name.clone(), part.to_string());
+                }
+                _ => return None,
+            }
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        let key = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky.key(headers, client_ip));
+        let upstream = match key {
+            Some(key) => self.upstreams.pick_sticky(&key),
+            None => self.upstreams.pick(),
+        };
+        let host_header = self.host_header.clone();
+        let shadow = self.shadow.as_ref().map(|shadow| Shadowed {
+            url: fill(&shadow.template, &values),
+            shadow: shadow.clone(),
+        });
+        Some(Resolved {
+            url: fill(upstream.template(), &values),
+            shadow,
+            host_header,
+            upstream,
+            hedge: self.hedge_after.map(|delay| Hedge {
+                delay,
+                upstreams: self.upstreams.clone(),
+                values,
+            }),
+            signer: self.signer.clone(),
+            tokens: self.tokens.clone(),
+            via: self.via.clone(),
+        })
+    }
+}
+
+/// `IP` or `IP:PORT` of `connect_to`, without a port the upstream's is kept
+fn parse_connect_to(connect_to: &str) -> Result<(IpAddr, Option<u16>)> {
+    if let Ok(addr) = connect_to.parse::<SocketAddr>() {
+        return Ok((addr.ip(), Some(addr.port())));
+    }
+    let ip = connect_to
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| anyhow!("invalid connect_to {}", connect_to))?;
+    Ok((ip, None))
+}
+
+/// Substitute `values` into an upstream URL template
+fn fill(template: &str, values: &HashMap<String, String>) -> String {
+    let mut url = template.to_string();
+    for (name, value) in values {
+        url = url
+            .replace(&format!("{{{}..}}", name), value)
+            .replace(&format!("{{{}}}", name), value);
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, upstream: &str) -> Result<Route> {
+        Route::parse(&RouteConfig {
+            path: path.to_string(),
+            upstream: Some(upstream.to_string()),
+            ..Default::default()
+        })
+    }
+
+    fn url(route: &Route, path: &str) -> Option<String> {
+        let client_ip = IpAddr::from([192, 0, 2, 1]);
+        route
+            .resolve(path, &HeaderMap::new(), client_ip)
+            .map(|resolved| resolved.url)
+    }
+
+    #[test]
+    fn resolve() {
+        let gh = route(
+            "/gh/{owner}/{repo}/{path..}",
+            "https://github.com/{owner}/{repo}/{path..}",
+        )
+        .unwrap();
+        assert_eq!(
+            url(&gh, "/gh/rust-lang/rust/blob/master/README.md").as_deref(),
+            Some("https://github.com/rust-lang/rust/blob/master/README.md")
+        );
+        assert_eq!(
+            url(&gh, "/gh/rust-lang/rust").as_deref(),
+            Some("https://github.com/rust-lang/rust/")
+        );
+        assert_eq!(url(&gh, "/gh/rust-lang"), None);
+        assert_eq!(url(&gh, "/gl/rust-lang/rust/x"), None);
+
+        let pypi = route("/pypi/{path..}", "https://pypi.org/{path..}").unwrap();
+        assert_eq!(
+            url(&pypi, "/pypi/simple/requests/").as_deref(),
+            Some("https://pypi.org/simple/requests/")
+        );
+
+        let fixed = route("/status", "https://status.example.com/").unwrap();
+        assert_eq!(
+            url(&fixed, "/status").as_deref(),
+            Some("https://status.example.com/")
+        );
+        assert_eq!(url(&fixed, "/status/x"), None);
+    }
+
+    #[test]
+    fn invalid_routes() {
+        assert!(route("/", "https://example.com/").is_err());
+        assert!(route("/a/{rest..}/b", "https://example.com/{rest..}").is_err());
+        assert!(route("/a/{x}", "https://example.com/{y}").is_err());
+        assert!(
+            Route::parse(&RouteConfig {
+                path: "/a".to_string(),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn sticky_keys() {
+        assert!(Sticky::parse("cookie:").is_err());
+        assert!(Sticky::parse("header:x").is_err());
+        let sticky = Sticky::parse("cookie:session").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "a=1; session=abc".parse().unwrap());
+        let client_ip = IpAddr::from([192, 0, 2, 1]);
+        assert_eq!(sticky.key(&headers, client_ip).as_deref(), Some("abc"));
+        assert_eq!(sticky.key(&HeaderMap::new(), client_ip), None);
+        assert_eq!(
+            Sticky::Ip.key(&headers, client_ip).as_deref(),
+            Some("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn shadow_share() {
+        let route = Route::parse(&RouteConfig {
+            path: "/pypi/{path..}".to_string(),
+            upstream: Some("https://pypi.org/{path..}".to_string()),
+            shadow: Some("https://canary.example.com/{path..}".to_string()),
+            shadow_percent: Some(25),
+            ..Default::default()
+        })
+        .unwrap();
+        let client_ip = IpAddr::from([192, 0, 2, 1]);
+        let shadows: Vec<Option<String>> = (0..8)
+            .map(|_| {
+                route
+                    .resolve("/pypi/simple/", &HeaderMap::new(), client_ip)
+                    .unwrap()
+                    .shadow
+                    .filter(Shadowed::sample)
+                    .map(|shadow| shadow.url)
+            })
+            .collect();
+        assert_eq!(shadows.iter().filter(|shadow| shadow.is_some()).count(), 2);
+        assert!(shadows.contains(&Some("https://canary.example.com/simple/".to_string())));
+        // Resolving alone does not use up the share
+        for _ in 0..3 {
+            route.resolve("/pypi/simple/", &HeaderMap::new(), client_ip);
+        }
+        let shadow = route
+            .resolve("/pypi/simple/", &HeaderMap::new(), client_ip)
+            .unwrap()
+            .shadow
+            .unwrap();
+        let sampled: Vec<bool> = (0..4).map(|_| shadow.sample()).collect();
+        assert_eq!(sampled, [false, false, false, true]);
+
+        assert!(
+            Route::parse(&RouteConfig {
+                path: "/a".to_string(),
+                upstream: Some("https://example.com/".to_string()),
+                shadow: Some("https://canary.example.com/{x}".to_string()),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn pinned_upstreams() {
+        let pinned = |connect_to: Option<&str>, sni: Option<&str>| {
+            Route::parse(&RouteConfig {
+                path: "/a/{path..}".to_string(),
+                upstream: Some("https://{path..}".to_string()),
+                connect_to: connect_to.map(str::to_string),
+                sni: sni.map(str::to_string),
+                ..Default::default()
+            })
+        };
+        let staged = pinned(Some("192.0.2.1"), Some("staging.example.com")).unwrap();
+        // The upstream host is kept, it is only dialed and named differently
+        let resolved = staged
+            .resolve("/a/example.com/x", &HeaderMap::new(), IpAddr::from([0; 4]))
+            .unwrap();
+        assert_eq!(resolved.url, "https://example.com/x");
+        assert_eq!(
+            resolved.via.as_deref(),
+            Some(&Via {
+                connect_to: Some(("192.0.2.1".parse().unwrap(), None)),
+                sni: Some("staging.example.com".to_string()),
+            })
+        );
+        let direct = pinned(Some("[2001:db8::1]:80"), None).unwrap();
+        assert_eq!(
+            direct.via.as_deref(),
+            Some(&Via {
+                connect_to: Some(("2001:db8::1".parse().unwrap(), Some(80))),
+                sni: None,
+            })
+        );
+        assert!(
+            pinned(None, Some("staging.example.com"))
+                .unwrap()
+                .via
+                .is_some()
+        );
+        assert!(pinned(None, None).unwrap().via.is_none());
+        assert!(pinned(Some("example.net"), None).is_err());
+    }
+
+    #[test]
+    fn mirrors() {
+        let pypi = Route::parse(&RouteConfig {
+            path: "/pypi/{path..}".to_string(),
+            upstreams: vec![
+                "https://pypi.org/{path..}".to_string(),
+                "https://mirror.example.com/pypi/{path..}".to_string(),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            url(&pypi, "/pypi/simple/").as_deref(),
+            Some("https://pypi.org/simple/")
+        );
+        assert_eq!(
+            url(&pypi, "/pypi/simple/").as_deref(),
+            Some("https://mirror.example.com/pypi/simple/")
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::Body as HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request, StatusCode};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::warn;
+use url::Url;
+
+use crate::body::{self, Body};
+use crate::{HttpClient, har};
+
+/// Header telling `--scan-url` where the upload is going
+const SCAN_TARGET: &str = "x-scan-target";
+
+/// What checks uploads (`--scan-command`, `--scan-url`)
+pub enum Scanner {
+    /// Shell command given the body on stdin, exit status 0 allows it
+    Command(String),
+    /// Endpoint the body is POSTed to, a 2xx status allows it and a 4xx
+    /// rejects it
+    Url(Url),
+}
+
+/// Check of request bodies before they are forwarded, e.g. for DLP
+pub struct Scan {
+    pub scanner: Scanner,
+    /// Smaller bodies are forwarded unscanned
+    pub min_size: u64,
+    /// Larger bodies are refused, they are held in memory while scanned
+    pub max_size: u64,
+    pub timeout: Duration,
+}
+
+const TOO_LARGE: Verdict = Verdict::Deny(StatusCode::PAYLOAD_TOO_LARGE, "Upload too large to scan");
+
+/// Outcome of a scan
+pub enum Verdict {
+    /// Forward this body, with the content of the original
+    Allow(Body),
+    /// Refuse the upload with this status and message, also when the
+    /// scanner failed
+    Deny(StatusCode, &'static str),
+}
+
+impl Scan {
+    /// Read `body` to the end if it reaches `min_size` and let the scanner
+    /// decide on it
+    pub async fn check(
+        &self,
+        client: &HttpClient,
+        target: &Url,
+        content_type: Option<&HeaderValue>,
+        mut body: Body,
+    ) -> Result<Verdict> {
+        let hint = body.size_hint();
+        if body.is_end_stream() || hint.upper().is_some_and(|upper| upper < self.min_size) {
+            return Ok(Verdict::Allow(body));
+        }
+        if hint.lower() > self.max_size {
+            return Ok(TOO_LARGE);
+        }
+        let mut content = BytesMut::new();
+        while let Some(frame) = body.frame().await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                // `--body-read-timeout` passed while the upload was read
+                Err(e) if body::caused_by::<body::BodyTimeout>(&*e) => {
+                    return Ok(Verdict::Deny(
+                        StatusCode::REQUEST_TIMEOUT,
+                        "Request body timed out",
+                    ));
+                }
+                Err(e) => return Err(anyhow!("Failed to read upload: {}", e)),
+            };
+            if let Ok(data) = frame.into_data() {
+                content.extend_from_slice(&data);
+                if content.len() as u64 > self.max_size {
+                    return Ok(TOO_LARGE);
+                }
+            }
+        }
+        let content = content.freeze();
+        if (content.len() as u64) < self.min_size {
+            return Ok(Verdict::Allow(body::full(content)));
+        }
+        let scanned = tokio::time::timeout(
+            self.timeout,
+            self.run(client, target, content_type, content.clone()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out after {:?}", self.timeout)));
+        Ok(match scanned {
+            Ok(true) => Verdict::Allow(body::full(content)),
+            Ok(false) => {
+                warn!(
+                    "Upload to {} rejected by the scanner",
+                    har::redact_url(target)
+                );
+                Verdict::Deny(StatusCode::FORBIDDEN, "Upload rejected")
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to scan upload to {}: {}",
+                    har::redact_url(target),
+                    e
+                );
+                Verdict::Deny(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Upload scanner unavailable",
+                )
+            }
+        })
+    }
+
+    /// Whether the scanner allows `content`
+    async fn run(
+        &self,
+        client: &HttpClient,
+        target: &Url,
+        content_type: Option<&HeaderValue>,
+        content: Bytes,
+    ) -> Result<bool> {
+        match &self.scanner {
+            Scanner::Command(command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("M2PROXY_TARGET", target.as_str())
+                    .env(
+                        "M2PROXY_CONTENT_TYPE",
+                        content_type
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or(""),
+                    )
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|e| anyhow!("Failed to run {:?}: {}", command, e))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // The command may decide without reading everything
+                    let _ = stdin.write_all(&content).await;
+                }
+                Ok(child.wait().await?.success())
+            }
+            Scanner::Url(url) => {
+                let content_type = content_type
+                    .cloned()
+                    .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url.as_str())
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(SCAN_TARGET, target.as_str())
+                    .body(body::full(content))?;
+                let status = client.request(request).await?.status();
+                if status.is_success() {
+                    Ok(true)
+                } else if status.is_client_error() {
+                    Ok(false)
+                } else {
+                    Err(anyhow!("{} returned {}", url, status))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures_util::TryStreamExt;
+use futures_util::stream;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use hyper::header::{self, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Method, StatusCode};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::body::{self, BoxError};
+use crate::{HttpClient, pool, upstream_request};
+
+/// Parallel Range downloads of large responses (`--segmented-download`)
+pub struct Segmented {
+    pub segments: u64,
+    pub min_size: u64,
+}
+
+impl Segmented {
+    /// Replace `body` with a download in parallel segments if the response
+    /// allows it
+    ///
+    /// The first segment keeps streaming from `body` while the others are
+    /// fetched with Range requests into temporary files, then sent in order.
+    pub fn apply(
+        &self,
+        client: &HttpClient,
+        url: &Url,
+        headers: &HeaderMap,
+        parts: &Parts,
+        body: body::Body,
+    ) -> body::Body {
+        let Some((length, validator)) = self.eligible(headers, parts) else {
+            return body;
+        };
+        let segment = length.div_ceil(self.segments);
+        debug!(
+            "Fetching {} ({} bytes) in {} segments",
+            url, length, self.segments
+        );
+
+        let mut fetches = Vec::new();
+        let mut start = segment;
+        while start < length {
+            let end = (start + segment).min(length) - 1;
+            let mut headers = headers.clone();
+            headers.insert(
+                header::RANGE,
+                HeaderValue::from_str(&format!("bytes={}-{}", start, end)).unwrap(),
+            );
+            headers.insert(header::IF_RANGE, validator.clone());
+            fetches.push(tokio::spawn(fetch(
+                client.clone(),
+                url.clone(),
+                headers,
+                start,
+                end,
+            )));
+            start += segment;
+        }
+
+        let (tx, mut rx) = mpsc::channel(4);
+        tokio::spawn(assemble(body, segment, Fetches(fetches), tx));
+        body::boxed(StreamBody::new(
+            stream::poll_fn(move |cx| rx.poll_recv(cx)).map_ok(Frame::data),
+        ))
+    }
+
+    /// Length and validator of a response worth splitting
+    fn eligible(&self, request: &HeaderMap, parts: &Parts) -> Option<(u64, HeaderValue)> {
+        if self.segments < 2
+            || parts.status != StatusCode::OK
+            || request.contains_key(header::RANGE)
+        {
+            return None;
+        }
+        let headers = &parts.headers;
+        let accepts_ranges = headers
+            .get(header::ACCEPT_RANGES)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"));
+        let length: u64 = headers
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()?;
+        if !accepts_ranges || length < self.min_size.max(self.segments) {
+            return None;
+        }
+        Some((length, range_validator(headers)?))
+    }
+}
+
+/// If-Range value for a response whose ranges can be combined
+///
+/// The validator must be strong, so every range comes from the same
+/// representation, and ranges of on-the-fly encodings are not reliably the
+/// same bytes.
+pub fn range_validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    let identity = headers
+        .get(header::CONTENT_ENCODING)
+        .is_none_or(|v| v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    if !identity {
+        return None;
+    }
+    headers
+        .get(header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(header::LAST_MODIFIED))
+        .cloned()
+}
+
+/// Aborts the segment downloads when the response is dropped
+struct Fetches(Vec<JoinHandle<Result<Spool, BoxError>>>);
+
+impl Drop for Fetches {
+    fn drop(&mut self) {
+        for fetch in &self.0 {
+            fetch.abort();
+        }
+    }
+}
+
+/// Temporary file holding one segment, removed on drop
+struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    async fn create() -> std::io::Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "m2proxy-segment-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        Ok(Spool { path, file })
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Download `start..=end` into a temporary file
+async fn fetch(
+    client: HttpClient,
+    url: Url,
+    headers: HeaderMap,
+    start: u64,
+    end: u64,
+) -> Result<Spool, BoxError> {
+    let request = upstream_request(&Method::GET, &url, &headers, body::empty())?;
+    let response = client.request(request).await?;
+    let expected = format!("bytes {}-{}/", start, end);
+    let content_range = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok());
+    if response.status() != StatusCode::PARTIAL_CONTENT
+        || !content_range.is_some_and(|range| range.starts_with(&expected))
+    {
+        return Err(format!("unexpected segment response {}", response.status()).into());
+    }
+
+    let mut spool = Spool::create().await?;
+    let mut body = response.into_body();
+    let mut written = 0;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            written += data.len() as u64;
+            spool.file.write_all(&data).await?;
+        }
+    }
+    if written != end - start + 1 {
+        return Err("segment ended early".into());
+    }
+    spool.file.flush().await?;
+    spool.file.rewind().await?;
+    Ok(spool)
+}
+
+/// Send the first `first_len` bytes of `first`, then every fetched segment
+async fn assemble(
+    mut first: body::Body,
+    first_len: u64,
+    mut fetches: Fetches,
+    tx: mpsc::Sender<Result<Bytes, std::io::Error>>,
+) {
+    let fail = |e: BoxError| std::io::Error::other(e);
+    let mut remaining = first_len;
+    while remaining > 0 {
+        let data = match first.frame().await {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(data) => data,
+                Err(_) => continue,
+            },
+            Some(Err(e)) => {
+                let _ = tx.send(Err(fail(e))).await;
+                return;
+            }
+            None => {
+                let _ = tx.send(Err(fail("response ended early".into()))).await;
+                return;
+            }
+        };
+        let data = data.slice(..data.len().min(remaining as usize));
+        remaining -= data.len() as u64;
+        if tx.send(Ok(data)).await.is_err() {
+            return;
+        }
+    }
+    // The rest of the full response is not needed
+    drop(first);
+
+    for fetch in fetches.0.iter_mut() {
+        let mut spool = match fetch.await {
+            Ok(Ok(spool)) => spool,
+            Ok(Err(e)) => {
+                warn!("Segment download failed: {}", e);
+                let _ = tx.send(Err(fail(e))).await;
+                return;
+            }
+            Err(e) => {
+                let _ = tx.send(Err(fail(e.into()))).await;
+                return;
+            }
+        };
+        let mut buf = pool::POOL.get();
+        loop {
+            pool::POOL.reserve(&mut buf);
+            match spool.file.read_buf(&mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    if tx.send(Ok(buf.split().freeze())).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+        pool::POOL.put(buf);
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Result, anyhow};
+use bytes::BytesMut;
+use http_body_util::BodyExt;
+use hyper::HeaderMap;
+use hyper::header;
+
+use crate::body::{self, Body};
+use crate::filter::{self, ContentTypes};
+
+/// Bytes of a response body looked at to recognize its type
+const SNIFF_LEN: usize = 512;
+
+/// Leading bytes of the types recognized in bodies
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"MZ", "application/x-dosexec"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"\xcf\xfa\xed\xfe", "application/x-mach-binary"),
+    (b"\xce\xfa\xed\xfe", "application/x-mach-binary"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+/// Declared types that say nothing about the content
+const GENERIC: &[&str] = &[
+    "",
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/unknown",
+];
+
+/// Media type recognized from the start of a body
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        return Some(mime);
+    }
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    let text = data.trim_ascii_start();
+    let html = [b"<!doctype html".as_slice(), b"<html"]
+        .iter()
+        .any(|tag| text.len() >= tag.len() && text[..tag.len()].eq_ignore_ascii_case(tag));
+    html.then_some("text/html")
+}
+
+/// Whether content recognized as `sniffed` may be served as `declared`
+fn compatible(declared: &str, sniffed: &str) -> bool {
+    declared == sniffed
+        || GENERIC.contains(&declared)
+        // Jars, wheels, tarballs and many more are archives inside
+        || (matches!(sniffed, "application/zip" | "application/gzip")
+            && declared.starts_with("application/"))
+        || (sniffed == "text/html" && declared.starts_with("text/"))
+}
+
+/// Outcome of a type check
+pub enum Verdict {
+    /// Send this body, with the content of the original
+    Allow(Body),
+    /// Refuse the response, for this reason
+    Deny(String),
+}
+
+/// Responses refused by their type (`--deny-content-types`,
+/// `--deny-type-mismatch`)
+pub struct TypePolicy {
+    pub deny: ContentTypes,
+    /// Also refuse content recognized as another type than declared
+    pub mismatch: bool,
+}
+
+impl TypePolicy {
+    /// Check the declared type and what the start of `body` looks like
+    pub async fn check(&self, headers: &HeaderMap, mut body: Body) -> Result<Verdict> {
+        let declared = filter::mime(
+            headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or(""),
+        );
+        if self.deny.matches(&declared) {
+            return Ok(Verdict::Deny(format!("declared as {}", declared)));
+        }
+        // Encoded bytes cannot be recognized
+        if headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|coding| coding != "identity")
+        {
+            return Ok(Verdict::Allow(body));
+        }
+        let mut start = BytesMut::new();
+        while start.len() < SNIFF_LEN
+            && let Some(frame) = body.frame().await
+        {
+            let frame = frame.map_err(|e| anyhow!("Failed to read response: {}", e))?;
+            if let Ok(data) = frame.into_data() {
+                start.extend_from_slice(&data);
+            }
+        }
+        let start = start.freeze();
+        if let Some(sniffed) = sniff(&start) {
+            if self.deny.matches(sniffed) {
+                return Ok(Verdict::Deny(format!("recognized as {}", sniffed)));
+            }
+            if self.mismatch && !compatible(&declared, sniffed) {
+                return Ok(Verdict::Deny(format!(
+                    "declared as {} but recognized as {}",
+                    declared, sniffed
+                )));
+            }
+        }
+        Ok(Verdict::Allow(body::prepend(start, body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        assert_eq!(sniff(b"MZ\x90\x00"), Some("application/x-dosexec"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\n  <!DOCTYPE HTML><html>"), Some("text/html"));
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(b""), None);
+
+        assert!(compatible(
+            "application/octet-stream",
+            "application/x-dosexec"
+        ));
+        assert!(compatible("application/java-archive", "application/zip"));
+        assert!(compatible("text/plain", "text/html"));
+        assert!(!compatible("image/png", "application/x-dosexec"));
+        assert!(!compatible("image/png", "text/html"));
+    }
+}
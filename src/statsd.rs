@@ -0,0 +1,76 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+/// Pushes metrics as StatsD lines over UDP (`--statsd-addr`)
+///
+/// Names are plain dotted paths without tags, which both StatsD and the
+/// Datadog agent accept.
+pub struct Statsd {
+    socket: UdpSocket,
+}
+
+impl Statsd {
+    pub fn connect(addr: &str) -> Result<Self> {
+        let target = addr
+            .to_socket_addrs()
+            .map_err(|e| anyhow!("Invalid StatsD address {}: {}", addr, e))?
+            .next()
+            .ok_or_else(|| anyhow!("StatsD address {} did not resolve", addr))?;
+        let bind = if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        // A full buffer drops the line instead of stalling a request
+        socket.set_nonblocking(true)?;
+        Ok(Statsd { socket })
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(&line(name, value, "c"));
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(&line(name, value, "g"));
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        self.send(&line(name, duration.as_millis() as u64, "ms"));
+    }
+
+    fn send(&self, line: &str) {
+        // Metrics are best effort, a missing agent must not break requests
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+/// `m2proxy.NAME:VALUE|KIND`
+fn line(name: &str, value: u64, kind: &str) -> String {
+    format!("m2proxy.{}:{}|{}", name, value, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        assert_eq!(line("requests", 1, "c"), "m2proxy.requests:1|c");
+
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let statsd = Statsd::connect(&agent.local_addr().unwrap().to_string()).unwrap();
+        statsd.timing("request_duration", Duration::from_millis(42));
+        let mut buf = [0; 64];
+        let n = agent.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"m2proxy.request_duration:42|ms");
+
+        assert!(Statsd::connect("not an address").is_err());
+    }
+}
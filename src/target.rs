@@ -0,0 +1,316 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Uri};
+use url::Url;
+
+use crate::connect::Via;
+use crate::oauth::TokenSource;
+use crate::route::{Hedge, Route, Shadowed};
+use crate::sigv4::SigV4;
+use crate::upstream::Pick;
+
+/// Request header naming the target base URL, the request path is appended
+pub const TARGET_HEADER: &str = "x-proxy-target";
+
+/// Where the target of a request was taken from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// `/https://example.com/path`
+    Path,
+    /// `Host: example-com.DOMAIN` (`--vhost-domain`)
+    VirtualHost,
+    /// `X-Proxy-Target: https://example.com`
+    Header,
+    /// A configured `[[route]]`
+    Route,
+}
+
+/// Target of a proxied request
+pub struct Target {
+    pub url: Url,
+    pub source: Source,
+    /// Upstream of a route, busy while the pick is alive
+    pub upstream: Option<Pick>,
+    /// Set for routes with `hedge_after`
+    pub hedge: Option<Hedge>,
+    /// Set for routes with `sigv4`
+    pub signer: Option<Arc<SigV4>>,
+    /// Set for routes with `oauth2`
+    pub tokens: Option<Arc<TokenSource>>,
+    /// Set for requests a route copies to its `shadow`
+    pub shadow: Option<Shadowed>,
+    /// Set for routes with `host_header`
+    pub host_header: Option<HeaderValue>,
+    /// Set for routes with `connect_to` or `sni`
+    pub via: Option<Arc<Via>>,
+}
+
+/// Derives targets from requests
+pub struct Targets {
+    /// Scheme for targets without one, `None` to reject them
+    default_scheme: Option<&'static str>,
+    /// `--vhost-domain`, lowercased and without leading dot
+    vhost_domain: Option<String>,
+    routes: Vec<Route>,
+}
+
+impl Targets {
+    pub fn new(
+        default_scheme: Option<&'static str>,
+        vhost_domain: Option<&str>,
+        routes: Vec<Route>,
+    ) -> Self {
+        Targets {
+            default_scheme,
+            vhost_domain: vhost_domain.map(|d| d.trim_start_matches('.').to_ascii_lowercase()),
+            routes,
+        }
+    }
+
+    /// Target host of a virtual-host request, e.g. `example.com` for
+    /// `Host: example-com.proxy.example.dev`
+    pub fn virtual_host(&self, headers: &HeaderMap) -> Option<String> {
+        let domain = self.vhost_domain.as_deref()?;
+        let host = headers.get("host")?.to_str().ok()?;
+        let (host, _) = split_port(host);
+        let label = host
+            .to_ascii_lowercase()
+            .strip_suffix(domain)?
+            .strip_suffix('.')?
+            .to_string();
+        (!label.is_empty()).then(|| decode_host(&label))
+    }
+
+    /// Whether the target of a request is taken from its path
+    pub fn path_based(&self, headers: &HeaderMap) -> bool {
+        !headers.contains_key(TARGET_HEADER) && self.virtual_host(headers).is_none()
+    }
+
+    /// State of the configured routes, for the stats endpoint
+    pub fn stats(&self) -> serde_json::Value {
+        let routes: Vec<serde_json::Value> = self.routes.iter().map(Route::stats).collect();
+        serde_json::json!({ "routes": routes })
+    }
+
+    /// Target of a request, or the reason it has none
+    ///
+    /// `client_ip` is used for sticky upstream selection of routes.
+    pub fn extract(
+        &self,
+        headers: &HeaderMap,
+        uri: &Uri,
+        client_ip: IpAddr,
+    ) -> Result<Target, &'static str> {
+        if let Some(host) = self.virtual_host(headers) {
+            let scheme = self.default_scheme.unwrap_or("https");
+            let path = uri.path_and_query().map_or("/", |p| p.as_str());
+            let url = Url::parse(&format!("{}://{}{}", scheme, host, path))
+                .map_err(|_| "Invalid target URL")?;
+            return Ok(Target {
+                url,
+                source: Source::VirtualHost,
+                upstream: None,
+                hedge: None,
+                signer: None,
+                tokens: None,
+                shadow: None,
+                host_header: None,
+                via: None,
+            });
+        }
+
+        let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+        if let Some(base) = headers.get(TARGET_HEADER) {
+            let base = base.to_str().map_err(|_| "Invalid target URL")?;
+            let base = self.with_scheme(base.trim())?;
+            let url = Url::parse(&format!("{}{}", base.trim_end_matches('/'), path_and_query))
+                .map_err(|_| "Invalid target URL")?;
+            return Ok(Target {
+                url,
+                source: Source::Header,
+                upstream: None,
+                hedge: None,
+                signer: None,
+                tokens: None,
+                shadow: None,
+                host_header: None,
+                via: None,
+            });
+        }
+
+        let path = uri.path();
+        if let Some(resolved) = self
+            .routes
+            .iter()
+            .find_map(|route| route.resolve(path, headers, client_ip))
+        {
+            let mut url = Url::parse(&resolved.url).map_err(|_| "Invalid target URL")?;
+            if let Some(query) = uri.query() {
+                url.set_query(Some(query));
+            }
+            return Ok(Target {
+                url,
+                source: Source::Route,
+                upstream: Some(resolved.upstream),
+                hedge: resolved.hedge,
+                signer: resolved.signer,
+                tokens: resolved.tokens,
+                shadow: resolved.shadow,
+                host_header: resolved.host_header,
+                via: resolved.via,
+            });
+        }
+
+        // Extract target URL (remove leading '/')
+        let target = path.strip_prefix('/').unwrap_or(path);
+
+        let mut url = Url::parse(&self.with_scheme(target)?).map_err(|_| "Invalid target URL")?;
+        if let Some(query) = uri.query() {
+            url.set_query(Some(query));
+        }
+        Ok(Target {
+            url,
+            source: Source::Path,
+            upstream: None,
+            hedge: None,
+            signer: None,
+            tokens: None,
+            shadow: None,
+            host_header: None,
+            via: None,
+        })
+    }
+
+    /// If no protocol prefix, use the default scheme
+    fn with_scheme(&self, target: &str) -> Result<String, &'static str> {
+        if target.starts_with("http://") || target.starts_with("https://") {
+            return Ok(target.to_string());
+        }
+        let scheme = self
+            .default_scheme
+            .ok_or("Target URL must include the scheme")?;
+        Ok(format!("{}://{}", scheme, target))
+    }
+
+    /// Point an absolute Location at the virtual host of its target
+    ///
+    /// `client_host` is the Host the client used, its port is kept.
+    pub fn vhost_location(
+        &self,
+        location: &Url,
+        client_scheme: &str,
+        client_host: &str,
+    ) -> Option<String> {
+        let domain = self.vhost_domain.as_deref()?;
+        // Ports cannot be expressed in a label
+        if location.port().is_some() {
+            return None;
+        }
+        let host = location.host_str()?;
+        let port = split_port(client_host)
+            .1
+            .map_or(String::new(), |p| format!(":{}", p));
+        let path = &location[url::Position::BeforePath..];
+        Some(format!(
+            "{}://{}.{}{}{}",
+            client_scheme,
+            encode_host(host),
+            domain,
+            port,
+            path
+        ))
+    }
+}
+
+/// `example.com` -> `example-com`, with literal dashes doubled
+pub fn encode_host(host: &str) -> String {
+    host.replace('-', "--").replace('.', "-")
+}
+
+/// Inverse of [`encode_host`]
+pub fn decode_host(label: &str) -> String {
+    let mut host = String::with_capacity(label.len());
+    let mut chars = label.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                host.push('-');
+            }
+            '-' => host.push('.'),
+            c => host.push(c),
+        }
+    }
+    host
+}
+
+/// Split `host:port`, keeping bracketed IPv6 addresses intact
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            (name, Some(port))
+        }
+        _ => (host, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    fn host(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn host_labels() {
+        assert_eq!(encode_host("my-site.example.com"), "my--site-example-com");
+        assert_eq!(decode_host("my--site-example-com"), "my-site.example.com");
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let targets = Targets::new(Some("https"), Some("proxy.test"), Vec::new());
+        assert_eq!(
+            targets.virtual_host(&host("example-com.proxy.test:8080")),
+            Some("example.com".to_string())
+        );
+        assert_eq!(targets.virtual_host(&host("proxy.test")), None);
+        assert_eq!(targets.virtual_host(&host("example-com.other.test")), None);
+
+        let uri: Uri = "/a/b?c=d".parse().unwrap();
+        let target = targets
+            .extract(&host("example-com.proxy.test"), &uri, CLIENT)
+            .unwrap();
+        assert_eq!(target.source, Source::VirtualHost);
+        assert_eq!(target.url.as_str(), "https://example.com/a/b?c=d");
+
+        let location = Url::parse("https://www.example.com/x?y").unwrap();
+        assert_eq!(
+            targets.vhost_location(&location, "http", "example-com.proxy.test:8080"),
+            Some("http://www-example-com.proxy.test:8080/x?y".to_string())
+        );
+    }
+
+    #[test]
+    fn target_header() {
+        let targets = Targets::new(Some("https"), None, Vec::new());
+        let uri: Uri = "/v1/items?page=2".parse().unwrap();
+        for base in ["https://example.com/api", "example.com/api/"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(TARGET_HEADER, base.parse().unwrap());
+            let target = targets.extract(&headers, &uri, CLIENT).unwrap();
+            assert_eq!(target.source, Source::Header);
+            assert_eq!(
+                target.url.as_str(),
+                "https://example.com/api/v1/items?page=2"
+            );
+        }
+    }
+}
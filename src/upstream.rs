@@ -0,0 +1,388 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use hyper::Request;
+use hyper::header::{self, HeaderValue};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::HttpClient;
+use crate::body;
+
+/// How a route picks one of its upstreams
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Each upstream in turn
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in progress
+    LeastConnections,
+    /// The first healthy upstream in the configured order
+    Failover,
+    /// The upstream with the lowest average response time
+    Fastest,
+}
+
+/// Active health check of every upstream in a pool
+#[derive(Debug)]
+pub struct HealthCheck {
+    /// Requested on the origin of each upstream
+    pub path: String,
+    pub interval: Duration,
+}
+
+/// Equivalent upstream URL templates of a route
+#[derive(Debug)]
+pub struct Pool {
+    upstreams: Vec<Upstream>,
+    balance: Balance,
+    health: Option<HealthCheck>,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    template: String,
+    /// Requests whose response has not been fully sent yet
+    active: AtomicUsize,
+    /// Result of the last health check, upstreams start out healthy
+    healthy: AtomicBool,
+    /// Moving average of the time to response headers in microseconds,
+    /// 0 until measured
+    latency: AtomicU64,
+    health_url: Option<Url>,
+}
+
+impl Pool {
+    pub fn new(
+        templates: Vec<String>,
+        balance: Balance,
+        health: Option<HealthCheck>,
+    ) -> Result<Arc<Self>> {
+        let mut upstreams = Vec::with_capacity(templates.len());
+        for template in templates {
+            let health_url = match &health {
+                Some(health) => Some(health_url(&template, &health.path)?),
+                None => None,
+            };
+            upstreams.push(Upstream {
+                template,
+                active: AtomicUsize::new(0),
+                healthy: AtomicBool::new(true),
+                latency: AtomicU64::new(0),
+                health_url,
+            });
+        }
+        Ok(Arc::new(Pool {
+            upstreams,
+            balance,
+            health,
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Choose an upstream, counted as active until the pick is dropped
+    ///
+    /// Unhealthy upstreams are skipped unless no upstream is healthy.
+    pub fn pick(self: &Arc<Self>) -> Pick {
+        let index = self.choose(None).unwrap_or(0);
+        self.activate(index)
+    }
+
+    /// Choose the same upstream for every request with `key` while it is
+    /// healthy, regardless of the balance mode
+    ///
+    /// Rendezvous hashing: when an upstream goes down, only its keys move.
+    pub fn pick_sticky(self: &Arc<Self>, key: &str) -> Pick {
+        let all: Vec<usize> = (0..self.upstreams.len()).collect();
+        let healthy: Vec<usize> = all
+            .iter()
+            .copied()
+            .filter(|&i| self.upstreams[i].healthy.load(Ordering::Relaxed))
+            .collect();
+        let candidates = if healthy.is_empty() { all } else { healthy };
+        let index = candidates
+            .into_iter()
+            .max_by_key(|&i| {
+                let mut hasher = DefaultHasher::new();
+                (key, &self.upstreams[i].template).hash(&mut hasher);
+                hasher.finish()
+            })
+            .unwrap_or(0);
+        self.activate(index)
+    }
+
+    /// Choose a healthy upstream other than `other`, e.g. for a hedged request
+    pub fn pick_other(self: &Arc<Self>, other: &Pick) -> Option<Pick> {
+        let index = self.choose(Some(other.index))?;
+        Some(self.activate(index))
+    }
+
+    fn choose(&self, exclude: Option<usize>) -> Option<usize> {
+        let len = self.upstreams.len();
+        let order: Vec<usize> = match self.balance {
+            Balance::Failover => (0..len).collect(),
+            _ => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len).map(|i| (start + i) % len).collect()
+            }
+        };
+        let order: Vec<usize> = order.into_iter().filter(|&i| Some(i) != exclude).collect();
+        let healthy: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| self.upstreams[i].healthy.load(Ordering::Relaxed))
+            .collect();
+        let candidates = if healthy.is_empty() && exclude.is_none() {
+            order
+        } else {
+            healthy
+        };
+        match self.balance {
+            Balance::RoundRobin | Balance::Failover => candidates.first().copied(),
+            // Ties go round-robin, so idle mirrors all get traffic
+            Balance::LeastConnections => candidates
+                .into_iter()
+                .min_by_key(|&i| self.upstreams[i].active.load(Ordering::Relaxed)),
+            // Unmeasured upstreams come first, so all of them get measured
+            Balance::Fastest => candidates
+                .into_iter()
+                .min_by_key(|&i| self.upstreams[i].latency.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// State of every upstream, for the stats endpoint
+    pub fn stats(&self) -> serde_json::Value {
+        let upstreams: Vec<serde_json::Value> = self
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let latency = upstream.latency.load(Ordering::Relaxed);
+                json!({
+                    "upstream": upstream.template,
+                    "healthy": upstream.healthy.load(Ordering::Relaxed),
+                    "active": upstream.active.load(Ordering::Relaxed),
+                    "latency_ms": (latency > 0).then(|| latency as f64 / 1000.0),
+                })
+            })
+            .collect();
+        json!({
+            "balance": self.balance,
+            "upstreams": upstreams,
+        })
+    }
+
+    fn activate(self: &Arc<Self>, index: usize) -> Pick {
+        self.upstreams[index].active.fetch_add(1, Ordering::Relaxed);
+        Pick {
+            pool: self.clone(),
+            index,
+        }
+    }
+
+    /// Run the health checks of the pool forever, if it has any
+    pub async fn check_health(self: Arc<Self>, client: HttpClient, host: Option<HeaderValue>) {
+        let Some(health) = &self.health else {
+            return;
+        };
+        let mut interval = tokio::time::interval(health.interval);
+        loop {
+            interval.tick().await;
+            let mut checks = JoinSet::new();
+            for (index, upstream) in self.upstreams.iter().enumerate() {
+                let Some(url) = upstream.health_url.clone() else {
+                    continue;
+                };
+                let (client, host) = (client.clone(), host.clone());
+                let timeout = health.interval;
+                checks.spawn(async move { (index, probe(&client, &url, host, timeout).await) });
+            }
+            while let Some(Ok((index, result))) = checks.join_next().await {
+                let upstream = &self.upstreams[index];
+                let healthy = result.is_ok();
+                if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                    match result {
+                        Ok(()) => info!("Upstream {} is healthy again", upstream.template),
+                        Err(e) => warn!("Upstream {} is unhealthy: {}", upstream.template, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `path` on the origin of an upstream URL template
+fn health_url(template: &str, path: &str) -> Result<Url> {
+    let base = template.split('{').next().unwrap_or(template);
+    Url::parse(base)
+        .and_then(|url| url.join(path))
+        .map_err(|e| anyhow!("Invalid health check URL for {}: {}", template, e))
+}
+
+/// Healthy means any response below 500 within `timeout`
+async fn probe(
+    client: &HttpClient,
+    url: &Url,
+    host: Option<HeaderValue>,
+    timeout: Duration,
+) -> Result<()> {
+    let mut request = Request::get(url.as_str()).header("user-agent", "m2proxy health check");
+    if let Some(host) = host {
+        request = request.header(header::HOST, host);
+    }
+    let request = request.body(body::empty())?;
+    let response = tokio::time::timeout(timeout, client.request(request))
+        .await
+        .map_err(|_| anyhow!("timed out"))??;
+    if response.status().is_server_error() {
+        return Err(anyhow!("status {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Upstream chosen for one request
+#[derive(Debug)]
+pub struct Pick {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Pick {
+    pub fn template(&self) -> &str {
+        &self.pool.upstreams[self.index].template
+    }
+
+    /// Update the latency average with the time it took to get response
+    /// headers
+    pub fn record_latency(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        let latency = &self.pool.upstreams[self.index].latency;
+        let _ = latency.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(match average {
+                0 => sample,
+                average => (average * 7 + sample * 3) / 10,
+            })
+        });
+    }
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        self.pool.upstreams[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balance: Balance) -> Arc<Pool> {
+        let templates = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        Pool::new(templates, balance, None).unwrap()
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(Balance::RoundRobin);
+        let picks: Vec<String> = (0..4).map(|_| pool.pick().template().to_string()).collect();
+        assert_eq!(picks, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn least_connections() {
+        let pool = pool(Balance::LeastConnections);
+        let a = pool.pick();
+        let b = pool.pick();
+        assert_eq!((a.template(), b.template()), ("a", "b"));
+        drop(a);
+        // "a" and "c" are idle, the counter moved on to "c"
+        let c = pool.pick();
+        assert_eq!(c.template(), "c");
+        // Only "a" is idle
+        assert_eq!(pool.pick().template(), "a");
+    }
+
+    #[test]
+    fn failover() {
+        let pool = pool(Balance::Failover);
+        assert_eq!(pool.pick().template(), "a");
+        pool.upstreams[0].healthy.store(false, Ordering::Relaxed);
+        assert_eq!(pool.pick().template(), "b");
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        assert_eq!(pool.pick().template(), "c");
+        // Recovery switches back
+        pool.upstreams[0].healthy.store(true, Ordering::Relaxed);
+        assert_eq!(pool.pick().template(), "a");
+        // With nothing healthy, the first upstream is still tried
+        for upstream in &pool.upstreams {
+            upstream.healthy.store(false, Ordering::Relaxed);
+        }
+        assert_eq!(pool.pick().template(), "a");
+    }
+
+    #[test]
+    fn fastest() {
+        let pool = pool(Balance::Fastest);
+        let picks: Vec<Pick> = (0..3).map(|_| pool.pick()).collect();
+        for (pick, ms) in picks.iter().zip([30, 10, 20]) {
+            pick.record_latency(Duration::from_millis(ms));
+        }
+        assert_eq!(pool.pick().template(), "b");
+        // One slow response moves the average, but not all the way
+        picks[1].record_latency(Duration::from_millis(60));
+        assert_eq!(pool.upstreams[1].latency.load(Ordering::Relaxed), 25_000);
+        assert_eq!(pool.pick().template(), "c");
+    }
+
+    #[test]
+    fn sticky() {
+        let pool = pool(Balance::RoundRobin);
+        let keys = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"];
+        let first: Vec<String> = keys
+            .iter()
+            .map(|key| pool.pick_sticky(key).template().to_string())
+            .collect();
+        for (key, template) in keys.iter().zip(&first) {
+            assert_eq!(pool.pick_sticky(key).template(), template);
+        }
+        // Keys of a failed upstream move, the others stay
+        pool.upstreams[0].healthy.store(false, Ordering::Relaxed);
+        for (key, template) in keys.iter().zip(&first) {
+            let moved = pool.pick_sticky(key);
+            assert_ne!(moved.template(), "a");
+            if template != "a" {
+                assert_eq!(moved.template(), template);
+            }
+        }
+    }
+
+    #[test]
+    fn pick_other() {
+        let pool = pool(Balance::Failover);
+        let a = pool.pick();
+        assert_eq!(pool.pick_other(&a).unwrap().template(), "b");
+        pool.upstreams[1].healthy.store(false, Ordering::Relaxed);
+        pool.upstreams[2].healthy.store(false, Ordering::Relaxed);
+        assert!(pool.pick_other(&a).is_none());
+    }
+
+    #[test]
+    fn health_urls() {
+        assert_eq!(
+            health_url("https://pypi.org:8443/{path..}", "/healthz")
+                .unwrap()
+                .as_str(),
+            "https://pypi.org:8443/healthz"
+        );
+        assert!(health_url("{host}/x", "/").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use regex::Regex;
+
+use crate::config::{UserAgentAction, UserAgentConfig};
+
+/// Period `rate` of a throttle rule counts requests in
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Throttled clients remembered before expired windows are dropped
+const MAX_CLIENTS: usize = 1024;
+
+/// Outcome of the User-Agent rules for a request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Block,
+    /// Over the rate, retry after this long
+    Throttle(Duration),
+}
+
+/// Rule from a `[[user_agent]]` config entry
+struct Rule {
+    pattern: Regex,
+    action: UserAgentAction,
+    rate: u32,
+    /// Start of each client's window and its requests in it
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+/// `[[user_agent]]` rules, the first matching one applies
+pub struct UserAgents {
+    rules: Vec<Rule>,
+}
+
+impl UserAgents {
+    pub fn parse(configs: &[UserAgentConfig]) -> Result<Self> {
+        let rules = configs
+            .iter()
+            .map(|config| {
+                let pattern = Regex::new(&config.pattern)
+                    .map_err(|e| anyhow!("Invalid user_agent pattern {}: {}", config.pattern, e))?;
+                let rate = match (config.action, config.rate) {
+                    (UserAgentAction::Throttle, Some(rate)) if rate > 0 => rate,
+                    (UserAgentAction::Throttle, _) => {
+                        return Err(anyhow!(
+                            "user_agent {} needs a rate above 0 to throttle",
+                            config.pattern
+                        ));
+                    }
+                    _ => 0,
+                };
+                Ok(Rule {
+                    pattern,
+                    action: config.action,
+                    rate,
+                    clients: Mutex::default(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(UserAgents { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Verdict on a request from `ip` with `user_agent`, empty if it sent
+    /// none
+    pub fn check(&self, user_agent: &str, ip: IpAddr) -> Verdict {
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(user_agent))
+        else {
+            return Verdict::Allow;
+        };
+        match rule.action {
+            UserAgentAction::Allow => Verdict::Allow,
+            UserAgentAction::Block => Verdict::Block,
+            UserAgentAction::Throttle => {
+                let now = Instant::now();
+                let mut clients = rule.clients.lock().unwrap_or_else(|e| e.into_inner());
+                if clients.len() >= MAX_CLIENTS {
+                    clients.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+                }
+                let (start, count) = clients.entry(ip.to_canonical()).or_insert((now, 0));
+                if now.duration_since(*start) >= WINDOW {
+                    (*start, *count) = (now, 0);
+                }
+                if *count >= rule.rate {
+                    return Verdict::Throttle(WINDOW - now.duration_since(*start));
+                }
+                *count += 1;
+                Verdict::Allow
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let configs: Vec<UserAgentConfig> = toml::from_str::<crate::config::Config>(
+            r#"
+            [[user_agent]]
+            pattern = "^(pip|cargo)/"
+            action = "allow"
+
+            [[user_agent]]
+            pattern = "(?i)bot"
+            action = "block"
+
+            [[user_agent]]
+            pattern = "^curl/|^$"
+            action = "throttle"
+            rate = 2
+            "#,
+        )
+        .unwrap()
+        .user_agents;
+        let rules = UserAgents::parse(&configs).unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(rules.check("pip/24.0 robot", ip), Verdict::Allow);
+        assert_eq!(rules.check("GoogleBot/2.1", ip), Verdict::Block);
+        assert_eq!(rules.check("Mozilla/5.0", ip), Verdict::Allow);
+        assert_eq!(rules.check("curl/8.5", ip), Verdict::Allow);
+        assert_eq!(rules.check("", ip), Verdict::Allow);
+        assert!(matches!(rules.check("curl/8.5", ip), Verdict::Throttle(wait) if wait <= WINDOW));
+        assert_eq!(
+            rules.check("curl/8.5", "192.0.2.2".parse().unwrap()),
+            Verdict::Allow
+        );
+
+        let unlimited = UserAgentConfig {
+            pattern: "curl".to_string(),
+            action: UserAgentAction::Throttle,
+            rate: None,
+        };
+        assert!(UserAgents::parse(&[unlimited]).is_err());
+    }
+}
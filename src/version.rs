@@ -0,0 +1,67 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::date::Utc;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short git commit the binary was built from, or `unknown`
+pub const GIT_COMMIT: &str = env!("M2PROXY_GIT_COMMIT");
+const BUILD_EPOCH: &str = env!("M2PROXY_BUILD_EPOCH");
+/// Target triple the binary was compiled for
+pub const TARGET: &str = env!("M2PROXY_TARGET");
+/// Enabled cargo features, comma separated
+const FEATURES: &str = env!("M2PROXY_FEATURES");
+
+/// Build time in ISO 8601
+pub fn build_date() -> String {
+    let epoch = BUILD_EPOCH.parse().unwrap_or(0);
+    Utc::from(UNIX_EPOCH + Duration::from_secs(epoch)).iso8601()
+}
+
+pub fn features() -> Vec<&'static str> {
+    FEATURES.split(',').filter(|f| !f.is_empty()).collect()
+}
+
+/// `--version --verbose` output
+pub fn verbose() -> String {
+    let features = features();
+    format!(
+        "m2proxy {}\ncommit: {}\nbuild date: {}\ntarget: {}\nfeatures: {}\n",
+        VERSION,
+        GIT_COMMIT,
+        build_date(),
+        TARGET,
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    )
+}
+
+/// Build information as JSON (`/__m2proxy/version`)
+pub fn info() -> serde_json::Value {
+    serde_json::json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_date": build_date(),
+        "target": TARGET,
+        "features": features(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info() {
+        let info = info();
+        assert_eq!(info["version"], VERSION);
+        assert!(verbose().contains(&format!("\ntarget: {}\n", TARGET)));
+        assert!(!GIT_COMMIT.is_empty());
+        let date = info["build_date"].as_str().unwrap();
+        // Built in the past, not at the epoch fallback
+        assert!(date.ends_with('Z') && !date.starts_with("1970"), "{}", date);
+        assert!(Utc::from(std::time::SystemTime::now()).iso8601().as_str() >= date);
+    }
+}
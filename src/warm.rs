@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use hyper::Request;
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::config::WarmConfig;
+use crate::{HttpClient, body};
+
+/// Upstream origin the pool keeps connections to (`[[warm]]`)
+pub struct Warm {
+    url: Url,
+    connections: usize,
+    interval: Duration,
+}
+
+impl Warm {
+    pub fn parse(config: &WarmConfig) -> Result<Self> {
+        let url = Url::parse(&config.url)
+            .ok()
+            .filter(|url| url.has_host() && matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| anyhow!("Warm {}: invalid URL", config.url))?;
+        Ok(Warm {
+            url,
+            connections: config.connections.unwrap_or(1).max(1),
+            interval: Duration::from_secs(config.interval.unwrap_or(30).max(1)),
+        })
+    }
+
+    /// Open the connections right away and send a HEAD request on each every
+    /// `interval`, so they are never idle long enough to expire
+    pub async fn run(self, client: HttpClient) {
+        let timeout = self.interval;
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            // Concurrent requests each need their own connection
+            let mut pings = JoinSet::new();
+            for _ in 0..self.connections {
+                let (client, url) = (client.clone(), self.url.clone());
+                pings.spawn(async move { ping(&client, &url, timeout).await });
+            }
+            while let Some(Ok(result)) = pings.join_next().await {
+                if let Err(e) = result {
+                    tracing::debug!("Failed to warm {}: {}", self.url, e);
+                }
+            }
+        }
+    }
+}
+
+async fn ping(client: &HttpClient, url: &Url, timeout: Duration) -> Result<()> {
+    let request = Request::head(url.as_str())
+        .header("user-agent", "m2proxy warm-up")
+        .body(body::empty())?;
+    tokio::time::timeout(timeout, client.request(request))
+        .await
+        .map_err(|_| anyhow!("timed out"))??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warm_targets() {
+        let config = |url: &str| WarmConfig {
+            url: url.to_string(),
+            connections: Some(0),
+            interval: None,
+        };
+        let warm = Warm::parse(&config("https://pypi.org")).unwrap();
+        assert_eq!(warm.url.as_str(), "https://pypi.org/");
+        assert_eq!(warm.connections, 1);
+        assert_eq!(warm.interval, Duration::from_secs(30));
+        assert!(Warm::parse(&config("pypi.org")).is_err());
+        assert!(Warm::parse(&config("ftp://pypi.org/")).is_err());
+    }
+}